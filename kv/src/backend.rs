@@ -0,0 +1,1523 @@
+//! The storage layer underneath [crate::Operations].
+//!
+//! A backend only ever sees [Record]s of raw bytes and the hex SHA-256 digest of their keys.
+//! Serialization and hashing stay in [crate::Store], so a new backend only has to decide where
+//! the bytes go.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use walkdir::WalkDir;
+
+use crate::durability::Syncer;
+use crate::index::{is_dirty_marker, KeyIndex, INDEX_FILE};
+use crate::journal::{Intent, Journal, JOURNAL_FILE};
+use crate::manifest::{Manifest, MANIFEST_FILE};
+use crate::record::Record;
+use crate::{Codec, Options, ShardLayout};
+
+/// A trait that defines where and how serialized key-value mappings are kept.
+pub trait Backend {
+    /// A function that opens the backend rooted at `path`, creating it if needed.
+    fn open(path: &str, options: &Options) -> std::io::Result<Self>
+    where
+        Self: Sized;
+
+    /// A function that stores `record` under its key, replacing any record already stored there.
+    fn put(&mut self, hash: &str, record: &Record) -> std::io::Result<()>;
+
+    /// A function that returns the record stored under `key`, or `None` if there is none.
+    ///
+    /// A record that fails validation is reported as an [ErrorKind::InvalidData] error.
+    fn get(&self, hash: &str, key: &[u8]) -> std::io::Result<Option<Record>>;
+
+    /// A function that deletes the mapping stored under `key`.
+    ///
+    /// Returns whether there was a mapping to delete.
+    fn delete(&mut self, hash: &str, key: &[u8]) -> std::io::Result<bool>;
+
+    /// A function that returns whether a mapping is stored under `key`.
+    fn exists(&self, hash: &str, key: &[u8]) -> std::io::Result<bool>;
+
+    /// A function that stores one chunk of a large value under `id`, replacing any chunk already
+    /// stored there.
+    ///
+    /// Chunks live apart from records: they are neither counted nor returned by [Backend::scan].
+    fn put_chunk(&mut self, id: &str, chunk: &[u8]) -> std::io::Result<()>;
+
+    /// A function that returns the chunk stored under `id`, or `None` if there is none.
+    fn get_chunk(&self, id: &str) -> std::io::Result<Option<Vec<u8>>>;
+
+    /// A function that deletes the chunk stored under `id`.
+    ///
+    /// Returns whether there was a chunk to delete.
+    fn delete_chunk(&mut self, id: &str) -> std::io::Result<bool>;
+
+    /// A function that makes every change in `ops`, in order, or none of them.
+    ///
+    /// The default makes the changes one at a time, which is only all-or-nothing for a backend
+    /// whose changes cannot fail halfway, such as one that lives in memory.
+    fn write_batch(&mut self, ops: &[BatchOp]) -> std::io::Result<()> {
+        for op in ops {
+            match op {
+                BatchOp::Put { hash, record } => self.put(hash, record)?,
+                BatchOp::Delete { hash, key } => {
+                    self.delete(hash, key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// A function that keeps every other handle on the same storage from changing it until the
+    /// matching [Backend::unlock], waiting for a lock held by another handle to be released.
+    /// Locks nest, and changes made through the handle holding the lock still go through.
+    ///
+    /// The default does nothing, which suits backends that cannot be shared between handles.
+    fn lock(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// A function that releases a lock taken by [Backend::lock].
+    fn unlock(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// A function that keeps the storage readable as it is right now until the matching
+    /// [Backend::unpin], and returns the sequence number to read it with. Every chunk deleted
+    /// from now on, by this handle or any other one on the same storage, stays readable through
+    /// this handle's [Backend::get_chunk], and every record replaced or deleted stays readable
+    /// through [Backend::get_pinned]. Pins nest.
+    ///
+    /// The default does nothing, which suits backends that cannot be shared between handles.
+    fn pin(&self) -> std::io::Result<u64> {
+        Ok(0)
+    }
+
+    /// A function that releases a pin taken by [Backend::pin].
+    fn unpin(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// A function that returns the record stored under `key` at the time [Backend::pin] returned
+    /// `sequence`, or `None` if there was none. Records stored later are never returned.
+    ///
+    /// The default returns the record stored now, which suits backends that cannot be shared
+    /// between handles.
+    fn get_pinned(&self, hash: &str, key: &[u8], sequence: u64) -> std::io::Result<Option<Record>> {
+        let _ = sequence;
+        self.get(hash, key)
+    }
+
+    /// A function that returns the highest version any removed mapping had, or 0 if none was
+    /// removed. Mappings that are inserted start above it, so no key ever gets a version again
+    /// that it had before.
+    ///
+    /// The default keeps it in a chunk of its own.
+    fn removed_version(&self) -> std::io::Result<u64> {
+        match self.get_chunk(REMOVED_VERSION_CHUNK)? {
+            None => Ok(0),
+            Some(version) => match version.as_slice().try_into() {
+                Ok(version) => Ok(u64::from_le_bytes(version)),
+                Err(_) => Err(crate::Error::corrupt("Highest removed version is corrupt!").into()),
+            },
+        }
+    }
+
+    /// A function that raises what [Backend::removed_version] returns to `version`, unless it is
+    /// higher already. It is called with the lock held, before the mapping is removed.
+    fn retire_version(&mut self, version: u64) -> std::io::Result<()> {
+        if version > self.removed_version()? {
+            self.put_chunk(REMOVED_VERSION_CHUNK, &version.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// A function that returns the serialized keys of every stored mapping, including the ones
+    /// other handles on the same storage changed.
+    fn scan(&self) -> std::io::Result<Vec<Vec<u8>>>;
+
+    /// A function that returns a number that changes whenever another handle on the same storage
+    /// has changed it since the last call. Changes made through this handle leave it alone.
+    ///
+    /// The default always returns 0, which suits backends that cannot be shared between handles.
+    fn generation(&self) -> std::io::Result<u64> {
+        Ok(0)
+    }
+
+    /// A function that returns the number of stored mappings.
+    fn count(&self) -> std::io::Result<usize> {
+        Ok(self.scan()?.len())
+    }
+
+    /// A function that forces every change made so far to stable storage.
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// A function that returns the codec the store was created with, if the backend records one.
+    ///
+    /// A recorded codec wins over [Options::codec].
+    fn codec(&self) -> Option<Codec> {
+        None
+    }
+}
+
+/// The chunk the default [Backend::removed_version] is kept in.
+const REMOVED_VERSION_CHUNK: &str = "removed.version";
+
+/// One change of a batch handed to [Backend::write_batch].
+#[derive(Debug, Clone, PartialEq)]
+pub enum BatchOp {
+    /// Store `record` under its key, replacing any record already stored there.
+    Put { hash: String, record: Record },
+    /// Delete the mapping stored under `key`, if there is one.
+    Delete { hash: String, key: Vec<u8> },
+}
+
+/// The default backend: every mapping is a single `<hash>.rec` [Record] file inside the shard
+/// directory its [ShardLayout] picks for the hash.
+///
+/// The layout is chosen through [Options::shard_layout] when the store is created and recorded in
+/// its manifest; [DirBackend::reshard] moves an existing store to a different one.
+///
+/// Keys whose hashes collide are chained in the same directory as `<hash>.1.rec`, `<hash>.2.rec`
+/// and so on. Lookups compare the key stored in each record of the chain against the requested
+/// key, so a collision can never return or overwrite the wrong mapping.
+///
+/// Every change goes through a write-ahead [Journal] and every file is replaced atomically rather
+/// than written in place, so a crash never leaves a half-written mapping behind. The keys and
+/// their count are kept in a persisted [KeyIndex], so opening a cleanly closed store does not
+/// have to walk the directory tree.
+#[derive(Debug)]
+pub struct DirBackend {
+    /// The location of the file system where key-value mappings are stored.
+    root: PathBuf,
+    /// Where record files are placed, as this handle read it from the manifest last.
+    layout: Mutex<CurrentLayout>,
+    codec: Codec,
+    journal: Journal,
+    index: KeyIndex,
+    syncer: Syncer,
+    /// The `LOCK` file every handle on the store locks while it changes records, and counts the
+    /// changes in.
+    lock_file: File,
+    /// How many times the lock is held by this handle.
+    locks: Mutex<usize>,
+    /// What this handle knows about the changes counted in the lock file.
+    changes: Mutex<ChangeCount>,
+    /// The [Backend::generation] up to which the keys of the index are complete.
+    index_generation: u64,
+    /// The `SNAPSHOTS` file every handle with a pin holds a shared lock on.
+    pin_file: File,
+    /// How many times this handle is pinned.
+    pins: Mutex<usize>,
+}
+
+impl DirBackend {
+    /// Returns the layout record files are currently placed in.
+    pub fn layout(&self) -> ShardLayout {
+        lock_count(&self.layout).layout
+    }
+
+    /// Moves every record file into the place `layout` picks for it and records the new layout
+    /// in the manifest.
+    ///
+    /// The store lock is held throughout, so no other handle changes the store in the meantime,
+    /// and handles that only read find every record file in the old or the new place. The store
+    /// stays fully usable afterwards. If the process dies half-way, the re-shard is finished the
+    /// next time the store is opened.
+    pub fn reshard(&mut self, layout: ShardLayout) -> std::io::Result<()> {
+        layout.validate()?;
+        self.locked(|backend| backend.move_records(layout))
+    }
+
+    /// Does the work of [DirBackend::reshard]. The store lock must be held.
+    fn move_records(&mut self, layout: ShardLayout) -> std::io::Result<()> {
+        let mut manifest = match Manifest::load(&self.root)? {
+            Some(manifest) => manifest,
+            None => Manifest::new(self.layout(), self.codec, None),
+        };
+        manifest.resharding_to = Some(layout);
+        self.save_layout(&mut manifest)?;
+
+        let record_files: Vec<PathBuf> = files_with_extension(&self.root, RECORD_EXTENSION)
+            .map(|e| e.into_path())
+            .collect();
+        for record_file in record_files {
+            let name = match record_file.file_name().and_then(|n| n.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            let hash = name.split('.').next().unwrap_or_default();
+            let sub_dir = layout.shard_dir(&self.root, hash)?;
+            let target = sub_dir.join(&name);
+            if target == record_file {
+                continue;
+            }
+            self.create_shard_dir(&sub_dir)?;
+            fs::rename(&record_file, &target)?;
+            self.syncer.dir_changed(&sub_dir)?;
+            if let Some(old_dir) = record_file.parent() {
+                self.syncer.dir_changed(old_dir)?;
+            }
+        }
+        self.remove_empty_shard_dirs()?;
+
+        manifest.layout = layout;
+        manifest.resharding_to = None;
+        self.save_layout(&mut manifest)
+    }
+
+    /// Saves `manifest` with a new layout generation and counts the change, so that other
+    /// handles read the layout again before they look for another record file. The store lock
+    /// must be held.
+    fn save_layout(&mut self, manifest: &mut Manifest) -> std::io::Result<()> {
+        manifest.layout_generation = manifest.layout_generation.wrapping_add(1);
+        manifest.save(&self.root, &mut self.syncer)?;
+        self.count_change()?;
+        let mut current = lock_count(&self.layout);
+        current.generation = manifest.layout_generation;
+        current.layout = manifest.layout;
+        current.resharding_to = manifest.resharding_to;
+        Ok(())
+    }
+
+    /// Returns where record files are placed, reading the manifest again first if another handle
+    /// changed the store since it was read last.
+    fn current_layout(&self) -> std::io::Result<CurrentLayout> {
+        let generation = self.generation()?;
+        let mut current = lock_count(&self.layout);
+        if current.checked != generation {
+            if let Some(manifest) = Manifest::load(&self.root)? {
+                current.generation = manifest.layout_generation;
+                current.layout = manifest.layout;
+                current.resharding_to = manifest.resharding_to;
+            }
+            current.checked = generation;
+        }
+        Ok(*current)
+    }
+
+    /// Reads the key of every record file, for when the index cannot be trusted. Records that
+    /// fail validation are left out.
+    fn rescan(&self) -> std::io::Result<BTreeSet<Vec<u8>>> {
+        let mut keys = BTreeSet::new();
+        for entry in files_with_extension(&self.root, RECORD_EXTENSION) {
+            if let Ok(record) = Record::decode(&fs::read(entry.path())?) {
+                keys.insert(record.key);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Gets the index ready to record a change that is about to be made.
+    fn prepare_index(&mut self) -> std::io::Result<()> {
+        if !self.index.load_keys()? {
+            self.rebuild_index()?;
+        }
+        self.index.mark_dirty(&mut self.syncer)
+    }
+
+    /// Rebuilds the index from the record files and saves it. The store lock must be held.
+    fn rebuild_index(&mut self) -> std::io::Result<()> {
+        self.index_generation = self.generation()?;
+        let keys = self.rescan()?;
+        self.index.save(&mut self.syncer, keys)
+    }
+
+    /// Saves the index, if this handle changed the store since it was last saved. The store lock
+    /// must be held.
+    ///
+    /// Other handles may have saved the index in the meantime, so only the keys this handle
+    /// changed are taken over, each as its record file says it is now, into the index as it was
+    /// saved last.
+    fn save_index(&mut self) -> std::io::Result<()> {
+        if !self.index.is_dirty() {
+            return Ok(());
+        }
+        let mut keys = match KeyIndex::read_saved(&self.root)? {
+            Some(keys) => keys,
+            None => return self.rebuild_index(),
+        };
+        for (key, hash) in self.index.changed() {
+            if self.chain(hash)?.iter().any(|r| &r.key == key) {
+                keys.insert(key.clone());
+            } else {
+                keys.remove(key);
+            }
+        }
+        self.index.save(&mut self.syncer, keys)
+    }
+
+    /// Returns the directory that holds the record files of `hash`. The store lock must be held,
+    /// which keeps the layout from changing.
+    fn shard_dir(&self, hash: &str) -> std::io::Result<PathBuf> {
+        self.layout().shard_dir(&self.root, hash)
+    }
+
+    /// Creates `dir` and any missing parent shard directories.
+    fn create_shard_dir(&mut self, dir: &Path) -> std::io::Result<()> {
+        let mut missing = Vec::new();
+        let mut next = dir;
+        while !next.is_dir() {
+            missing.push(next.to_path_buf());
+            next = match next.parent() {
+                Some(parent) => parent,
+                None => break,
+            };
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+        if let Err(e) = fs::create_dir_all(dir) {
+            return Err(crate::Error::io(e, dir).into());
+        }
+        for created in missing.iter().rev() {
+            if let Some(parent) = created.parent() {
+                self.syncer.dir_changed(parent)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes `dir` and its parent shard directories for as long as they are empty.
+    fn prune_shard_dir(&mut self, dir: PathBuf) -> std::io::Result<()> {
+        let mut dir = dir;
+        while dir != self.root && is_empty_dir(&dir)? {
+            if let Err(e) = fs::remove_dir(&dir) {
+                return Err(crate::Error::io(e, &dir).into());
+            }
+            dir = match dir.parent() {
+                Some(parent) => parent.to_path_buf(),
+                None => break,
+            };
+        }
+        if dir.is_dir() {
+            self.syncer.dir_changed(&dir)?;
+        }
+        Ok(())
+    }
+
+    /// Removes every empty directory below the root, deepest first.
+    fn remove_empty_shard_dirs(&mut self) -> std::io::Result<()> {
+        let dirs: Vec<PathBuf> = WalkDir::new(&self.root)
+            .min_depth(1)
+            .contents_first(true)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_dir())
+            .map(|e| e.into_path())
+            .collect();
+        for dir in dirs {
+            if is_empty_dir(&dir)? {
+                fs::remove_dir(&dir)?;
+            }
+        }
+        self.syncer.dir_changed(&self.root)
+    }
+
+    /// Returns the file holding the chunk stored under `id`.
+    fn chunk_file(&self, id: &str) -> PathBuf {
+        let prefix = id.get(0..2).unwrap_or(id);
+        self.root
+            .join(CHUNK_DIR)
+            .join(prefix)
+            .join(format!("{}.{}", id, CHUNK_EXTENSION))
+    }
+
+    /// Returns the file holding the record in position `slot` of the chain for `hash`. The store
+    /// lock must be held.
+    fn record_file(&self, hash: &str, slot: u32) -> std::io::Result<PathBuf> {
+        Ok(self.shard_dir(hash)?.join(record_name(hash, slot)))
+    }
+
+    /// Reads every record chained under `hash`, in slot order.
+    ///
+    /// The store lock need not be held: if another handle changes the layout while the chain is
+    /// read, it is read again in the new one.
+    fn chain(&self, hash: &str) -> std::io::Result<Vec<Record>> {
+        loop {
+            let layout = self.current_layout()?;
+            let records = self.chain_in(&layout, hash)?;
+            if self.current_layout()?.generation == layout.generation {
+                return Ok(records);
+            }
+        }
+    }
+
+    /// Reads every record chained under `hash` in `layout`. Record files that a re-shard under way
+    /// has already moved are read from their new place.
+    fn chain_in(&self, layout: &CurrentLayout, hash: &str) -> std::io::Result<Vec<Record>> {
+        let mut records = Vec::new();
+        loop {
+            let name = record_name(hash, records.len() as u32);
+            let mut record_file = layout.layout.shard_dir(&self.root, hash)?.join(&name);
+            if let Some(resharding_to) = layout.resharding_to {
+                // Files only ever move to the new place, so looking there second never misses
+                // one that is moved in between.
+                if !record_file.is_file() {
+                    record_file = resharding_to.shard_dir(&self.root, hash)?.join(&name);
+                }
+            }
+            if !record_file.is_file() {
+                return Ok(records);
+            }
+            let bytes = match fs::read(&record_file) {
+                Err(e) => return Err(crate::Error::io(e, &record_file).for_key(hash).into()),
+                Ok(bytes) => bytes,
+            };
+            match Record::decode(&bytes) {
+                Err(e) => {
+                    return Err(crate::Error::corrupt(e.to_string())
+                        .for_key(hash)
+                        .at_path(&record_file)
+                        .into())
+                }
+                Ok(record) => records.push(record),
+            }
+        }
+    }
+
+    /// Journals `intents`, applies them and then clears the journal again.
+    fn commit(&mut self, intents: &[Intent]) -> std::io::Result<()> {
+        self.count_change()?;
+        self.journal.begin(&mut self.syncer, intents)?;
+        for intent in intents {
+            self.apply(intent)?;
+        }
+        self.journal.commit(&mut self.syncer)
+    }
+
+    /// Makes a single journaled change. Applying the same intent twice is harmless, which is what
+    /// lets an interrupted change be replayed.
+    fn apply(&mut self, intent: &Intent) -> std::io::Result<()> {
+        match intent {
+            Intent::Put { hash, slot, record } => {
+                self.create_shard_dir(&self.shard_dir(hash)?)?;
+                let record_file = self.record_file(hash, *slot)?;
+                if let Err(e) = self.syncer.write_atomic(&record_file, record) {
+                    return Err(crate::Error::io(e, &record_file).for_key(hash).into());
+                }
+            }
+            Intent::Delete { hash, slot } => {
+                let record_file = self.record_file(hash, *slot)?;
+                if record_file.is_file() {
+                    if let Err(e) = fs::remove_file(&record_file) {
+                        return Err(crate::Error::io(e, &record_file).for_key(hash).into());
+                    }
+                }
+                self.prune_shard_dir(self.shard_dir(hash)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores `record` in the chain for `hash`, in the slot of the record of the same key if
+    /// there is one.
+    fn put_record(&mut self, hash: &str, record: &Record) -> std::io::Result<()> {
+        let chain = self.chain(hash)?;
+        let slot = match chain.iter().position(|r| r.key == record.key) {
+            Some(slot) => {
+                self.retire_records(hash, &[&chain[slot]])?;
+                slot
+            }
+            None => chain.len(),
+        };
+        self.prepare_index()?;
+        self.commit(&[Intent::Put {
+            hash: hash.to_string(),
+            slot: slot as u32,
+            record: record.encode(),
+        }])?;
+        self.index.insert(&record.key, hash);
+        Ok(())
+    }
+
+    /// Deletes the record of `key` and, to keep the chain without gaps, moves the last record of
+    /// the chain into the freed slot.
+    fn delete_record(&mut self, hash: &str, key: &[u8]) -> std::io::Result<bool> {
+        let chain = self.chain(hash)?;
+        let slot = match chain.iter().position(|r| r.key == key) {
+            Some(slot) => slot,
+            None => return Ok(false),
+        };
+        let last = chain.len() - 1;
+        let mut intents = Vec::new();
+        if slot != last {
+            intents.push(Intent::Put {
+                hash: hash.to_string(),
+                slot: slot as u32,
+                record: chain[last].encode(),
+            });
+        }
+        intents.push(Intent::Delete {
+            hash: hash.to_string(),
+            slot: last as u32,
+        });
+        self.retire_records(hash, &[&chain[slot]])?;
+        self.prepare_index()?;
+        self.commit(&intents)?;
+        self.index.remove(key, hash);
+        Ok(true)
+    }
+
+    /// Works out the final chain of every hash the batch touches and journals all record files
+    /// that change as a single commit.
+    fn write_records(&mut self, ops: &[BatchOp]) -> std::io::Result<()> {
+        // The chain of every touched hash as it is on disk and as the batch leaves it.
+        let mut chains: BTreeMap<&str, (Vec<Record>, Vec<Record>)> = BTreeMap::new();
+        for op in ops {
+            let hash = match op {
+                BatchOp::Put { hash, .. } | BatchOp::Delete { hash, .. } => hash.as_str(),
+            };
+            if !chains.contains_key(hash) {
+                let chain = self.chain(hash)?;
+                chains.insert(hash, (chain.clone(), chain));
+            }
+            let (_, chain) = chains.get_mut(hash).unwrap();
+            match op {
+                BatchOp::Put { record, .. } => {
+                    match chain.iter().position(|r| r.key == record.key) {
+                        Some(slot) => chain[slot] = record.clone(),
+                        None => chain.push(record.clone()),
+                    }
+                }
+                BatchOp::Delete { key, .. } => {
+                    // Like a single delete, the last record moves into the freed slot.
+                    if let Some(slot) = chain.iter().position(|r| &r.key == key) {
+                        chain.swap_remove(slot);
+                    }
+                }
+            }
+        }
+
+        let mut intents = Vec::new();
+        for (hash, (before, after)) in &chains {
+            for (slot, record) in after.iter().enumerate() {
+                if before.get(slot) != Some(record) {
+                    intents.push(Intent::Put {
+                        hash: hash.to_string(),
+                        slot: slot as u32,
+                        record: record.encode(),
+                    });
+                }
+            }
+            for slot in after.len()..before.len() {
+                intents.push(Intent::Delete {
+                    hash: hash.to_string(),
+                    slot: slot as u32,
+                });
+            }
+        }
+        if intents.is_empty() {
+            return Ok(());
+        }
+        for (hash, (before, after)) in &chains {
+            let retired: Vec<&Record> = before
+                .iter()
+                .filter(|record| !after.contains(record))
+                .collect();
+            self.retire_records(hash, &retired)?;
+        }
+        self.prepare_index()?;
+        self.commit(&intents)?;
+        for (hash, (before, after)) in &chains {
+            for record in before {
+                self.index.remove(&record.key, hash);
+            }
+            for record in after {
+                self.index.insert(&record.key, hash);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the file a chunk deleted while a handle was pinned is kept in.
+    fn retired_chunk_file(&self, id: &str) -> PathBuf {
+        self.root
+            .join(CHUNK_DIR)
+            .join(RETIRED_DIR)
+            .join(format!("{}.{}", id, CHUNK_EXTENSION))
+    }
+
+    /// Reads how many changes every handle on the store made so far, which they count in the
+    /// lock file.
+    fn read_change_count(&self) -> std::io::Result<u64> {
+        self.read_lock_word(CHANGE_COUNT_AT)
+    }
+
+    /// Counts a change made through this handle. The store lock must be held.
+    fn count_change(&mut self) -> std::io::Result<()> {
+        self.generation()?;
+        let seen = {
+            let mut changes = lock_count(&self.changes);
+            changes.seen = changes.seen.wrapping_add(1);
+            changes.seen
+        };
+        self.write_lock_word(CHANGE_COUNT_AT, seen)
+    }
+
+    /// Reads the little-endian u64 at `at` in the lock file.
+    fn read_lock_word(&self, at: u64) -> std::io::Result<u64> {
+        let mut word = [0; 8];
+        let mut file = &self.lock_file;
+        let read = file
+            .seek(SeekFrom::Start(at))
+            .and_then(|_| file.read(&mut word));
+        match read {
+            // The lock file starts out empty, before anything was written to it.
+            Ok(_) => Ok(u64::from_le_bytes(word)),
+            Err(e) => Err(crate::Error::io(e, &self.root.join(LOCK_FILE)).into()),
+        }
+    }
+
+    /// Writes `word` at `at` in the lock file. The store lock must be held.
+    fn write_lock_word(&mut self, at: u64, word: u64) -> std::io::Result<()> {
+        let mut file = &self.lock_file;
+        let written = file
+            .seek(SeekFrom::Start(at))
+            .and_then(|_| file.write_all(&word.to_le_bytes()));
+        if let Err(e) = written {
+            return Err(crate::Error::io(e, &self.root.join(LOCK_FILE)).into());
+        }
+        Ok(())
+    }
+
+    /// Keeps copies of `records`, which the change about to be committed replaces or deletes
+    /// under `hash`, while any handle is pinned. The store lock must be held.
+    ///
+    /// Every copy is named after the change count the change gets, so that a pin taken before it
+    /// can tell the copy apart from the ones of later changes.
+    fn retire_records(&mut self, hash: &str, records: &[&Record]) -> std::io::Result<()> {
+        if records.is_empty() || !self.pinned()? {
+            return Ok(());
+        }
+        let sequence = self.read_change_count()?.wrapping_add(1);
+        let retired_dir = self.root.join(RETIRED_DIR).join(hash);
+        self.create_shard_dir(&retired_dir)?;
+        for (i, record) in records.iter().enumerate() {
+            let name = format!("{}.{}.{}", sequence, i, RETIRED_EXTENSION);
+            let retired_file = retired_dir.join(name);
+            if let Err(e) = self.syncer.write_atomic(&retired_file, &record.encode()) {
+                return Err(crate::Error::io(e, &retired_file).for_key(hash).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the oldest copy of the record of `key` that a change after `sequence` retired.
+    fn retired_record(
+        &self,
+        hash: &str,
+        key: &[u8],
+        sequence: u64,
+    ) -> std::io::Result<Option<Record>> {
+        let retired_dir = self.root.join(RETIRED_DIR).join(hash);
+        let entries = match fs::read_dir(&retired_dir) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(crate::Error::io(e, &retired_dir).for_key(hash).into()),
+            Ok(entries) => entries,
+        };
+        let mut oldest: Option<(u64, Record)> = None;
+        for entry in entries {
+            let retired_file = entry?.path();
+            let retired_by = retired_file
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.split('.').next())
+                .and_then(|n| n.parse::<u64>().ok());
+            let retired_by = match retired_by {
+                Some(retired_by) if retired_by > sequence => retired_by,
+                _ => continue,
+            };
+            if oldest
+                .as_ref()
+                .is_some_and(|(oldest, _)| *oldest < retired_by)
+            {
+                continue;
+            }
+            let bytes = match fs::read(&retired_file) {
+                Err(e) => return Err(crate::Error::io(e, &retired_file).for_key(hash).into()),
+                Ok(bytes) => bytes,
+            };
+            match Record::decode(&bytes) {
+                Err(e) => {
+                    return Err(crate::Error::corrupt(e.to_string())
+                        .for_key(hash)
+                        .at_path(&retired_file)
+                        .into())
+                }
+                Ok(record) if record.key == key => oldest = Some((retired_by, record)),
+                Ok(_) => {}
+            }
+        }
+        Ok(oldest.map(|(_, record)| record))
+    }
+
+    /// Returns whether any handle on the store, this one included, is pinned.
+    fn pinned(&self) -> std::io::Result<bool> {
+        if *lock_count(&self.pins) > 0 {
+            return Ok(true);
+        }
+        match self.pin_file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Ok(true),
+            Err(TryLockError::Error(e)) => {
+                return Err(crate::Error::io(e, &self.root.join(PIN_FILE)).into())
+            }
+        }
+        if let Err(e) = self.pin_file.unlock() {
+            return Err(crate::Error::io(e, &self.root.join(PIN_FILE)).into());
+        }
+        Ok(false)
+    }
+
+    /// Deletes every retired record and chunk, unless a handle is pinned. No handle can pin
+    /// the store while they are deleted, so none of them can be needed any more.
+    fn purge_retired(&self) -> std::io::Result<()> {
+        let retired_dirs = [
+            self.root.join(RETIRED_DIR),
+            self.root.join(CHUNK_DIR).join(RETIRED_DIR),
+        ];
+        if !retired_dirs.iter().any(|dir| dir.is_dir()) || *lock_count(&self.pins) > 0 {
+            return Ok(());
+        }
+        match self.pin_file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Ok(()),
+            Err(TryLockError::Error(e)) => {
+                return Err(crate::Error::io(e, &self.root.join(PIN_FILE)).into())
+            }
+        }
+        let mut purged = Ok(());
+        for retired_dir in &retired_dirs {
+            match fs::remove_dir_all(retired_dir) {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    purged = Err(crate::Error::io(e, retired_dir).into());
+                }
+                _ => {}
+            }
+        }
+        if let Err(e) = self.pin_file.unlock() {
+            return Err(crate::Error::io(e, &self.root.join(PIN_FILE)).into());
+        }
+        purged
+    }
+
+    /// Runs `change` while holding the store lock.
+    fn locked<T, F>(&mut self, change: F) -> std::io::Result<T>
+    where
+        F: FnOnce(&mut DirBackend) -> std::io::Result<T>,
+    {
+        self.lock()?;
+        let result = change(self);
+        self.unlock()?;
+        result
+    }
+
+    /// Converts mappings written by older versions as a `<hash>.key` and `<hash>.value` file
+    /// pair into record files.
+    fn upgrade_legacy_pairs(&mut self) -> std::io::Result<()> {
+        let key_files: Vec<PathBuf> = files_with_extension(&self.root, "key")
+            .map(|e| e.into_path())
+            .collect();
+        for key_file in key_files {
+            let value_file = key_file.with_extension("value");
+            if !value_file.is_file() {
+                continue;
+            }
+            let record = Record::new(
+                fs::read(&key_file)?,
+                fs::read(&value_file)?,
+                Codec::Json.id(),
+            );
+            self.syncer
+                .write_atomic(&key_file.with_extension(RECORD_EXTENSION), &record.encode())?;
+            fs::remove_file(&key_file)?;
+            fs::remove_file(&value_file)?;
+        }
+        Ok(())
+    }
+}
+
+impl Backend for DirBackend {
+    fn open(path: &str, options: &Options) -> std::io::Result<DirBackend> {
+        let root = Path::new(path);
+        if let Err(e) = fs::create_dir_all(root) {
+            return Err(crate::Error::invalid_path(e, root).into());
+        }
+        let mut syncer = Syncer::new(options.durability);
+        let manifest = match Manifest::load(root)? {
+            Some(manifest) => manifest,
+            None => {
+                if has_foreign_files(root)? {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "Directory holds files that do not belong to a key-value store!",
+                    ));
+                }
+                // Stores created before the manifest existed always used the legacy layout and
+                // were never encrypted.
+                let manifest = if has_sub_dirs(root)? {
+                    Manifest::new(ShardLayout::LEGACY, Codec::Json, None)
+                } else {
+                    options.shard_layout.validate()?;
+                    Manifest::new(
+                        options.shard_layout,
+                        options.codec,
+                        options.encryption_key.as_ref(),
+                    )
+                };
+                manifest.save(root, &mut syncer)?;
+                manifest
+            }
+        };
+        manifest.check_key(options.encryption_key.as_ref())?;
+        let lock_file = open_lock_file(&root.join(LOCK_FILE))?;
+        let pin_file = open_lock_file(&root.join(PIN_FILE))?;
+        let mut backend = DirBackend {
+            root: root.to_path_buf(),
+            layout: Mutex::new(CurrentLayout {
+                checked: 0,
+                generation: manifest.layout_generation,
+                layout: manifest.layout,
+                resharding_to: manifest.resharding_to,
+            }),
+            codec: Codec::from_name(&manifest.codec).unwrap_or_default(),
+            journal: Journal::new(root),
+            index: KeyIndex::unloaded(root),
+            syncer,
+            lock_file,
+            locks: Mutex::new(0),
+            changes: Mutex::new(ChangeCount::default()),
+            index_generation: 0,
+            pin_file,
+            pins: Mutex::new(0),
+        };
+
+        // Another handle may be in the middle of a change, so recovery waits until the change is
+        // over, which normally leaves no journal to replay.
+        backend.locked(|backend| {
+            for intent in &backend.journal.pending()? {
+                backend.apply(intent)?;
+            }
+            backend.journal.commit(&mut backend.syncer)?;
+            lock_count(&backend.changes).seen = backend.read_change_count()?;
+            backend.upgrade_legacy_pairs()?;
+            if let Some(layout) = manifest.resharding_to {
+                backend.move_records(layout)?;
+            }
+
+            match KeyIndex::load(root)? {
+                Some(index) => {
+                    backend.index = index;
+                    backend.index_generation = backend.generation()?;
+                }
+                None => backend.rebuild_index()?,
+            }
+            Ok(())
+        })?;
+        backend.purge_retired()?;
+        Ok(backend)
+    }
+
+    fn put(&mut self, hash: &str, record: &Record) -> std::io::Result<()> {
+        self.locked(|backend| backend.put_record(hash, record))
+    }
+
+    fn get(&self, hash: &str, key: &[u8]) -> std::io::Result<Option<Record>> {
+        Ok(self.chain(hash)?.into_iter().find(|r| r.key == key))
+    }
+
+    fn delete(&mut self, hash: &str, key: &[u8]) -> std::io::Result<bool> {
+        self.locked(|backend| backend.delete_record(hash, key))
+    }
+
+    fn exists(&self, hash: &str, key: &[u8]) -> std::io::Result<bool> {
+        Ok(self.get(hash, key)?.is_some())
+    }
+
+    fn write_batch(&mut self, ops: &[BatchOp]) -> std::io::Result<()> {
+        self.locked(|backend| backend.write_records(ops))
+    }
+
+    /// Writes the chunk to `chunks/<prefix>/<id>.chunk`. Chunks are written before the record
+    /// that refers to them, so they need no journaling: a crash can only leave an unreferenced
+    /// chunk behind, never a record with a missing one.
+    fn put_chunk(&mut self, id: &str, chunk: &[u8]) -> std::io::Result<()> {
+        let chunk_file = self.chunk_file(id);
+        if let Some(dir) = chunk_file.parent() {
+            self.create_shard_dir(dir)?;
+        }
+        if let Err(e) = self.syncer.write_atomic(&chunk_file, chunk) {
+            return Err(crate::Error::io(e, &chunk_file).into());
+        }
+        Ok(())
+    }
+
+    /// Reads the chunk from `chunks/<prefix>/<id>.chunk` or, while this handle is pinned, from
+    /// `chunks/retired/<id>.chunk` if it has been deleted since.
+    fn get_chunk(&self, id: &str) -> std::io::Result<Option<Vec<u8>>> {
+        let chunk = read_chunk_file(&self.chunk_file(id))?;
+        if chunk.is_none() && *lock_count(&self.pins) > 0 {
+            return read_chunk_file(&self.retired_chunk_file(id));
+        }
+        Ok(chunk)
+    }
+
+    /// Deletes the chunk, or moves it to `chunks/retired/` while any handle is pinned.
+    fn delete_chunk(&mut self, id: &str) -> std::io::Result<bool> {
+        let chunk_file = self.chunk_file(id);
+        if !chunk_file.is_file() {
+            return Ok(false);
+        }
+        let deleted = if self.pinned()? {
+            let retired_file = self.retired_chunk_file(id);
+            if let Some(dir) = retired_file.parent() {
+                self.create_shard_dir(dir)?;
+            }
+            fs::rename(&chunk_file, &retired_file)
+        } else {
+            fs::remove_file(&chunk_file)
+        };
+        if let Err(e) = deleted {
+            return Err(crate::Error::io(e, &chunk_file).into());
+        }
+        if let Some(dir) = chunk_file.parent() {
+            self.prune_shard_dir(dir.to_path_buf())?;
+        }
+        Ok(true)
+    }
+
+    /// Returns the keys of the index, unless another handle changed the store since they were
+    /// complete, in which case the record files are read instead.
+    /// Reads the highest removed version from the lock file, next to the change count.
+    fn removed_version(&self) -> std::io::Result<u64> {
+        self.read_lock_word(REMOVED_VERSION_AT)
+    }
+
+    fn retire_version(&mut self, version: u64) -> std::io::Result<()> {
+        if version > self.removed_version()? {
+            self.write_lock_word(REMOVED_VERSION_AT, version)?;
+        }
+        Ok(())
+    }
+
+    fn scan(&self) -> std::io::Result<Vec<Vec<u8>>> {
+        if self.generation()? == self.index_generation {
+            if let Some(keys) = self.index.keys()? {
+                return Ok(keys);
+            }
+        }
+        Ok(self.rescan()?.into_iter().collect())
+    }
+
+    /// Counts the changes other handles made, as the difference between the count in the lock
+    /// file and the count this handle saw last.
+    fn generation(&self) -> std::io::Result<u64> {
+        let count = self.read_change_count()?;
+        let mut changes = lock_count(&self.changes);
+        if count != changes.seen {
+            changes.seen = count;
+            changes.foreign += 1;
+        }
+        Ok(changes.foreign)
+    }
+
+    /// Counts the keys of the index, or the record files once other handles changed the store
+    /// since the index was read.
+    fn count(&self) -> std::io::Result<usize> {
+        if self.generation()? == self.index_generation {
+            return Ok(self.index.count());
+        }
+        Ok(self.scan()?.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.locked(DirBackend::save_index)?;
+        self.syncer.flush()
+    }
+
+    /// Takes the store lock and, when this handle did not hold it yet, reads the layout again if
+    /// another handle may have changed it.
+    fn lock(&self) -> std::io::Result<()> {
+        let mut locks = lock_count(&self.locks);
+        if *locks == 0 {
+            if let Err(e) = self.lock_file.lock() {
+                return Err(crate::Error::io(e, &self.root.join(LOCK_FILE)).into());
+            }
+            if let Err(e) = self.current_layout() {
+                let _ = self.lock_file.unlock();
+                return Err(e);
+            }
+        }
+        *locks += 1;
+        Ok(())
+    }
+
+    fn unlock(&self) -> std::io::Result<()> {
+        let mut locks = lock_count(&self.locks);
+        *locks = locks.saturating_sub(1);
+        if *locks == 0 {
+            if let Err(e) = self.lock_file.unlock() {
+                return Err(crate::Error::io(e, &self.root.join(LOCK_FILE)).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Pins the store and returns the change count as the sequence number. The store lock must
+    /// be held, so that no change is counted without being seen as made while pinned.
+    fn pin(&self) -> std::io::Result<u64> {
+        let mut pins = lock_count(&self.pins);
+        if *pins == 0 {
+            if let Err(e) = self.pin_file.lock_shared() {
+                return Err(crate::Error::io(e, &self.root.join(PIN_FILE)).into());
+            }
+        }
+        *pins += 1;
+        drop(pins);
+        self.read_change_count()
+    }
+
+    /// Releases the pin and, once no handle is pinned any more, deletes the records and chunks
+    /// that were retired while one was.
+    fn unpin(&self) -> std::io::Result<()> {
+        {
+            let mut pins = lock_count(&self.pins);
+            *pins = pins.saturating_sub(1);
+            if *pins > 0 {
+                return Ok(());
+            }
+            if let Err(e) = self.pin_file.unlock() {
+                return Err(crate::Error::io(e, &self.root.join(PIN_FILE)).into());
+            }
+        }
+        self.purge_retired()
+    }
+
+    /// Returns the oldest record of `key` retired by a change after `sequence`, which is the one
+    /// that was stored at the time, or else the record stored now.
+    fn get_pinned(&self, hash: &str, key: &[u8], sequence: u64) -> std::io::Result<Option<Record>> {
+        match self.retired_record(hash, key, sequence)? {
+            Some(record) => Ok(Some(record)),
+            None => self.get(hash, key),
+        }
+    }
+
+    fn codec(&self) -> Option<Codec> {
+        Some(self.codec)
+    }
+}
+
+impl Drop for DirBackend {
+    /// Saves the index, so the next open does not have to rebuild it.
+    fn drop(&mut self) {
+        let _ = self.locked(DirBackend::save_index);
+    }
+}
+
+const RECORD_EXTENSION: &str = "rec";
+const CHUNK_EXTENSION: &str = "chunk";
+/// The file handles lock to change the store one at a time, which also holds the change count
+/// and the highest removed version.
+const LOCK_FILE: &str = "LOCK";
+/// Where the change count is kept in the lock file.
+const CHANGE_COUNT_AT: u64 = 0;
+/// Where the highest removed version is kept in the lock file.
+const REMOVED_VERSION_AT: u64 = 8;
+/// The file pinned handles hold a shared lock on.
+const PIN_FILE: &str = "SNAPSHOTS";
+/// The directory below the root, and below [CHUNK_DIR], that holds the records and the chunks
+/// replaced or deleted while a handle was pinned.
+const RETIRED_DIR: &str = "retired";
+const RETIRED_EXTENSION: &str = "retired";
+/// The directory below the root that holds the chunks of large values.
+const CHUNK_DIR: &str = "chunks";
+
+/// Where the record files of a store are placed, as a handle read it from the manifest.
+#[derive(Debug, Clone, Copy)]
+struct CurrentLayout {
+    /// The [Backend::generation] of the handle when the manifest was read.
+    checked: u64,
+    /// The layout generation of the manifest.
+    generation: u64,
+    layout: ShardLayout,
+    /// The layout a re-shard under way is moving record files to.
+    resharding_to: Option<ShardLayout>,
+}
+
+/// What a handle knows about the changes every handle on a store counts in the lock file.
+#[derive(Debug, Default)]
+struct ChangeCount {
+    /// The count in the lock file as this handle saw it last.
+    seen: u64,
+    /// How many times the count turned out to have moved on without this handle, which is the
+    /// [Backend::generation] of the handle.
+    foreign: u64,
+}
+
+/// Returns the name of the file holding the record in position `slot` of the chain for `hash`.
+fn record_name(hash: &str, slot: u32) -> String {
+    match slot {
+        0 => format!("{}.{}", hash, RECORD_EXTENSION),
+        _ => format!("{}.{}.{}", hash, slot, RECORD_EXTENSION),
+    }
+}
+
+/// Opens (or creates) a file that is locked, and that holds no more than a few counts.
+fn open_lock_file(path: &Path) -> std::io::Result<File> {
+    match OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)
+    {
+        Ok(file) => Ok(file),
+        Err(e) => Err(crate::Error::io(e, path).into()),
+    }
+}
+
+/// Returns a lock, pin or change count, which stays usable if a thread panicked while holding
+/// it.
+fn lock_count<T>(count: &Mutex<T>) -> MutexGuard<'_, T> {
+    count.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Reads a chunk file, or returns `None` if there is none.
+fn read_chunk_file(chunk_file: &Path) -> std::io::Result<Option<Vec<u8>>> {
+    match fs::read(chunk_file) {
+        Ok(chunk) => Ok(Some(chunk)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(crate::Error::io(e, chunk_file).into()),
+    }
+}
+
+fn is_empty_dir(dir: &Path) -> std::io::Result<bool> {
+    Ok(dir.is_dir() && dir.read_dir()?.next().is_none())
+}
+
+/// Returns whether `root` holds regular files other than the ones a store keeps at its root.
+fn has_foreign_files(root: &Path) -> std::io::Result<bool> {
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if entry.file_type()?.is_file()
+            && !name.starts_with('.')
+            && name != JOURNAL_FILE
+            && name != MANIFEST_FILE
+            && name != INDEX_FILE
+            && !is_dirty_marker(&name)
+            && name != LOCK_FILE
+            && name != PIN_FILE
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn has_sub_dirs(root: &Path) -> std::io::Result<bool> {
+    for entry in fs::read_dir(root)? {
+        if entry?.file_type()?.is_dir() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn files_with_extension<'a>(
+    root: &Path,
+    extension: &'a str,
+) -> impl Iterator<Item = walkdir::DirEntry> + 'a {
+    WalkDir::new(root)
+        .follow_links(true)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(move |e| e.path().extension().and_then(|x| x.to_str()) == Some(extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::durability::Syncer;
+    use crate::journal::{Intent, Journal};
+    use crate::manifest::Manifest;
+    use crate::record::Record;
+    use crate::{
+        hash_key, Backend, Codec, DirBackend, Durability, KVStore, Operations, Options, ShardLayout,
+    };
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::path::Path;
+
+    fn fresh_dir(path: &str) -> &Path {
+        let _ = fs::remove_dir_all(path);
+        fs::create_dir_all(path).unwrap();
+        Path::new(path)
+    }
+
+    #[test]
+    fn interrupted_insert_is_replayed() {
+        let root = fresh_dir("./test-KV/journal1");
+        let key = serde_json::to_string("Pizza").unwrap();
+        let hash = hash_key(&key);
+        let record = Record::new(key.into_bytes(), b"21".to_vec(), Codec::Json.id());
+        Journal::new(root)
+            .begin(
+                &mut Syncer::new(Durability::PerOperation),
+                &[Intent::Put {
+                    hash: hash.clone(),
+                    slot: 0,
+                    record: record.encode(),
+                }],
+            )
+            .unwrap();
+        // The crash happened after the journal was written but before the record file.
+
+        let kv_store = KVStore::new("./test-KV/journal1").unwrap();
+        assert_eq!(kv_store.size(), 1);
+        assert_eq!(kv_store.lookup::<&str, i32>("Pizza").unwrap(), 21);
+        assert!(!root.join("journal").exists());
+    }
+
+    #[test]
+    fn interrupted_remove_is_replayed() {
+        let path = "./test-KV/journal2";
+        fresh_dir(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        kv_store.insert(String::from("Cold"), 86_i32).unwrap();
+        kv_store.insert(String::from("Water"), 90_i32).unwrap();
+
+        let hash = hash_key(&serde_json::to_string("Water").unwrap());
+        Journal::new(Path::new(path))
+            .begin(
+                &mut Syncer::new(Durability::PerOperation),
+                &[Intent::Delete {
+                    hash: hash.clone(),
+                    slot: 0,
+                }],
+            )
+            .unwrap();
+        // The crash happened after the journal was written but before the record file was removed.
+
+        let mut kv_store = KVStore::new(path).unwrap();
+        assert_eq!(kv_store.size(), 1);
+        assert!(kv_store.lookup::<&str, i32>("Water").is_err());
+        kv_store.insert(String::from("Water"), 91_i32).unwrap();
+        assert_eq!(kv_store.lookup::<&str, i32>("Water").unwrap(), 91);
+    }
+
+    #[test]
+    fn damaged_record_is_reported_as_corrupt() {
+        let path = "./test-KV/record1";
+        fresh_dir(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        kv_store.insert(String::from("Salt"), 3_i32).unwrap();
+
+        let hash = hash_key(&serde_json::to_string("Salt").unwrap());
+        let file = Path::new(path)
+            .join(&hash[0..2])
+            .join(format!("{}.rec", hash));
+        let mut bytes = fs::read(&file).unwrap();
+        *bytes.last_mut().unwrap() = b'4';
+        fs::write(&file, bytes).unwrap();
+
+        let err = kv_store.lookup::<&str, i32>("Salt").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let err = kv_store.remove::<&str, i32>("Salt").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn legacy_key_value_pairs_are_upgraded() {
+        let root = fresh_dir("./test-KV/record2");
+        let key = serde_json::to_string("Old").unwrap();
+        let hash = hash_key(&key);
+        let shard = root.join(&hash[0..10]);
+        fs::create_dir_all(&shard).unwrap();
+        fs::write(shard.join(format!("{}.key", hash)), &key).unwrap();
+        fs::write(shard.join(format!("{}.value", hash)), "[1,2]").unwrap();
+
+        let kv_store = KVStore::new("./test-KV/record2").unwrap();
+        assert_eq!(kv_store.size(), 1);
+        assert_eq!(
+            kv_store.lookup::<&str, Vec<i32>>("Old").unwrap(),
+            vec![1, 2]
+        );
+        assert_eq!(fs::read_dir(&shard).unwrap().count(), 1);
+        assert_eq!(kv_store.backend().layout(), ShardLayout::LEGACY);
+    }
+
+    #[test]
+    fn reshard_moves_every_record() {
+        let path = "./test-KV/shard1";
+        let root = fresh_dir(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        for i in 0..50_i32 {
+            kv_store.insert(i, i * 2).unwrap();
+        }
+
+        let nested = ShardLayout {
+            prefix_len: 2,
+            depth: 2,
+        };
+        kv_store.backend_mut().reshard(nested).unwrap();
+        let hash = hash_key(&serde_json::to_string(&7_i32).unwrap());
+        assert!(root
+            .join(&hash[0..2])
+            .join(&hash[2..4])
+            .join(format!("{}.rec", hash))
+            .is_file());
+        assert_eq!(kv_store.lookup::<i32, i32>(7).unwrap(), 14);
+        kv_store.insert(50_i32, 100_i32).unwrap();
+        assert_eq!(kv_store.remove::<i32, i32>(3).unwrap(), 6);
+
+        // The layout recorded in the manifest wins over the options.
+        let kv_store = KVStore::open(path, Options::default()).unwrap();
+        assert_eq!(kv_store.backend().layout(), nested);
+        assert_eq!(kv_store.size(), 50);
+        for i in (0..51_i32).filter(|&i| i != 3) {
+            assert_eq!(kv_store.lookup::<i32, i32>(i).unwrap(), i * 2);
+        }
+    }
+
+    #[test]
+    fn interrupted_reshard_is_finished_on_open() {
+        let path = "./test-KV/shard2";
+        let root = fresh_dir(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        for i in 0..20_i32 {
+            kv_store.insert(i, i).unwrap();
+        }
+        drop(kv_store);
+
+        let flat = ShardLayout {
+            prefix_len: 0,
+            depth: 0,
+        };
+        let mut manifest = Manifest::new(ShardLayout::default(), Codec::Json, None);
+        manifest.resharding_to = Some(flat);
+        manifest
+            .save(root, &mut Syncer::new(Durability::PerOperation))
+            .unwrap();
+
+        let kv_store = KVStore::new(path).unwrap();
+        assert_eq!(kv_store.backend().layout(), flat);
+        assert_eq!(kv_store.size(), 20);
+        assert_eq!(kv_store.lookup::<i32, i32>(19).unwrap(), 19);
+        assert!(fs::read_dir(root)
+            .unwrap()
+            .all(|e| !e.unwrap().file_type().unwrap().is_dir()));
+    }
+
+    #[test]
+    fn other_handles_follow_a_reshard() {
+        let path = "./test-KV/shard4";
+        let root = fresh_dir(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        for i in 0..20_i32 {
+            kv_store.insert(i, i).unwrap();
+        }
+        let mut other = KVStore::new(path).unwrap();
+
+        // A re-shard under way has moved some record files and not others yet.
+        let flat = ShardLayout {
+            prefix_len: 0,
+            depth: 0,
+        };
+        let moved = hash_key(&serde_json::to_string(&7_i32).unwrap());
+        kv_store
+            .backend_mut()
+            .locked(|backend| {
+                let mut manifest = Manifest::load(root).unwrap().unwrap();
+                manifest.resharding_to = Some(flat);
+                backend.save_layout(&mut manifest)
+            })
+            .unwrap();
+        let name = format!("{}.rec", moved);
+        fs::rename(root.join(&moved[0..2]).join(&name), root.join(&name)).unwrap();
+        assert_eq!(other.lookup::<i32, i32>(7).unwrap(), 7);
+        assert_eq!(other.lookup::<i32, i32>(8).unwrap(), 8);
+
+        kv_store.backend_mut().reshard(flat).unwrap();
+        assert_eq!(other.lookup::<i32, i32>(8).unwrap(), 8);
+        other.insert(20_i32, 20_i32).unwrap();
+        assert_eq!(other.backend().layout(), flat);
+        let added = hash_key(&serde_json::to_string(&20_i32).unwrap());
+        assert!(root.join(format!("{}.rec", added)).is_file());
+        assert_eq!(KVStore::new(path).unwrap().size(), 21);
+    }
+
+    #[test]
+    fn short_hashes_are_invalid_paths() {
+        let path = "./test-KV/shard5";
+        fresh_dir(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        let err = crate::Error::from(kv_store.backend().get("a", b"key").unwrap_err());
+        assert!(matches!(err, crate::Error::InvalidPath { .. }));
+        assert_eq!(err.hash(), Some("a"));
+        assert!(kv_store.backend_mut().delete("a", b"key").is_err());
+    }
+
+    #[test]
+    fn invalid_layouts_are_rejected() {
+        let path = "./test-KV/shard3";
+        fresh_dir(path);
+        let options = Options {
+            shard_layout: ShardLayout {
+                prefix_len: 9,
+                depth: 8,
+            },
+            ..Options::default()
+        };
+        assert!(KVStore::open(path, options).is_err());
+    }
+
+    #[test]
+    fn colliding_keys_are_chained() {
+        let path = "./test-KV/collision1";
+        let root = fresh_dir(path);
+        let mut backend = DirBackend::open(path, &Options::default()).unwrap();
+        let hash = "0123456789abcdef";
+        let record = |key: &str, value: &str| {
+            Record::new(
+                key.as_bytes().to_vec(),
+                value.as_bytes().to_vec(),
+                Codec::Json.id(),
+            )
+        };
+
+        backend.put(hash, &record("\"a\"", "1")).unwrap();
+        backend.put(hash, &record("\"b\"", "2")).unwrap();
+        backend.put(hash, &record("\"c\"", "3")).unwrap();
+        backend.put(hash, &record("\"b\"", "4")).unwrap();
+        assert_eq!(backend.count().unwrap(), 3);
+        assert_eq!(backend.get(hash, b"\"b\"").unwrap().unwrap().value, b"4");
+        assert!(backend.get(hash, b"\"d\"").unwrap().is_none());
+
+        assert!(backend.delete(hash, b"\"a\"").unwrap());
+        assert!(!backend.delete(hash, b"\"a\"").unwrap());
+        assert!(!backend.exists(hash, b"\"a\"").unwrap());
+        assert_eq!(backend.get(hash, b"\"c\"").unwrap().unwrap().value, b"3");
+        assert_eq!(backend.get(hash, b"\"b\"").unwrap().unwrap().value, b"4");
+        assert_eq!(fs::read_dir(root.join(&hash[0..2])).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn torn_journal_is_rolled_back() {
+        let root = fresh_dir("./test-KV/journal3");
+        fs::write(root.join("journal"), [7, 7, 7, 7, 1, 0]).unwrap();
+
+        let mut kv_store = KVStore::new("./test-KV/journal3").unwrap();
+        assert_eq!(kv_store.size(), 0);
+        assert!(!root.join("journal").exists());
+        kv_store.insert(String::from("Earth"), 77_i32).unwrap();
+        assert_eq!(kv_store.size(), 1);
+    }
+}
@@ -0,0 +1,370 @@
+//! Atomic write batches.
+//!
+//! A [WriteBatch] collects puts and deletes across any number of keys, and [Store::apply] makes
+//! all of them or none. The chunks of large values are written first, as for a single insert,
+//! and the records then go to the backend in one [Backend::write_batch] call: a
+//! [crate::DirBackend] journals every record file the batch changes as a single commit, and a
+//! [crate::Bitcask] appends the batch as a single checksummed entry. A crash before that call
+//! returns leaves the store as it was, apart from unreferenced chunks.
+
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::backend::BatchOp;
+use crate::stream::StoredValue;
+use crate::{serialize_key, Backend, Codec, Result, Store};
+
+/// A change collected by a [WriteBatch], on serialized keys and values.
+#[derive(Debug, Clone)]
+enum Change {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+/// A set of puts and deletes that [Store::apply] makes all-or-nothing.
+///
+/// Changes are applied in the order they were added, so a later change of the same key wins.
+/// Values are serialized as they are added, with the codec the batch was created with;
+/// [Store::batch] creates a batch with the codec of the store.
+///
+/// ```
+/// use kv::{KeyValue, MemoryStore, Options, Store};
+///
+/// let mut store: MemoryStore = Store::open("", Options::default()).unwrap();
+/// store.put("pending/7", &String::from("order 7")).unwrap();
+///
+/// let mut batch = store.batch();
+/// batch.delete("pending/7").unwrap();
+/// batch.put("shipped/7", "order 7").unwrap();
+/// store.apply(batch).unwrap();
+///
+/// assert!(!store.contains_key("pending/7").unwrap());
+/// assert_eq!(store.get::<_, String>("shipped/7").unwrap().unwrap(), "order 7");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    codec: Codec,
+    changes: Vec<Change>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch whose values are serialized with `codec`.
+    pub fn new(codec: Codec) -> WriteBatch {
+        WriteBatch {
+            codec,
+            changes: Vec::new(),
+        }
+    }
+
+    /// Adds storing `value` under `key`, replacing any value stored there already.
+    pub fn put<K, V>(&mut self, key: &K, value: &V) -> Result<()>
+    where
+        K: Serialize + ?Sized,
+        V: Serialize + ?Sized,
+    {
+        let key = serialize_key(key)?;
+        let value = self.codec.encode(value)?;
+        self.changes.push(Change::Put { key, value });
+        Ok(())
+    }
+
+    /// Adds removing the mapping stored under `key`, if there is one.
+    pub fn delete<K: Serialize + ?Sized>(&mut self, key: &K) -> Result<()> {
+        let key = serialize_key(key)?;
+        self.changes.push(Change::Delete { key });
+        Ok(())
+    }
+
+    /// Adds storing raw value bytes under raw key bytes, like [Store::put_bytes].
+    pub fn put_bytes(&mut self, key: &[u8], value: &[u8]) {
+        self.changes.push(Change::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        });
+    }
+
+    /// Adds removing the mapping stored under raw key bytes, like [Store::delete_bytes].
+    pub fn delete_bytes(&mut self, key: &[u8]) {
+        self.changes.push(Change::Delete { key: key.to_vec() });
+    }
+
+    /// Returns the number of changes in the batch.
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    /// Returns whether the batch holds no changes.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl<B: Backend> Store<B> {
+    /// Creates an empty [WriteBatch] whose values are serialized with the store's codec.
+    pub fn batch(&self) -> WriteBatch {
+        WriteBatch::new(self.codec)
+    }
+
+    /// Makes every change in `batch`, or none of them if any fails.
+    ///
+    /// The batch is made durable as a whole, at the point the backend commits it, and the size of
+    /// the store is updated once it has been.
+    pub fn apply(&mut self, batch: WriteBatch) -> Result<()> {
+        self.locked(|store| store.apply_changes(batch))
+    }
+
+    fn apply_changes(&mut self, batch: WriteBatch) -> Result<()> {
+        let mut staged = Staged::default();
+        let written = match self.stage_changes(&batch, &mut staged) {
+            Ok(()) => self.backend.write_batch(&staged.ops).map_err(Into::into),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            // Nothing refers to the chunks the batch has written so far.
+            for written in staged
+                .replaced
+                .iter()
+                .chain(staged.after.values().flatten())
+            {
+                let _ = self.delete_chunks(written);
+            }
+            return Err(e);
+        }
+
+        for (stored_key, old) in staged.before {
+            let present = matches!(staged.after.get(&stored_key), Some(Some(_)));
+            match old {
+                Some(old) => {
+                    self.delete_chunks(&old)?;
+                    if !present {
+                        self.track_key(&stored_key, false);
+                        *self.size_mut() -= 1;
+                    }
+                }
+                None if present => {
+                    self.track_key(&stored_key, true);
+                    *self.size_mut() += 1;
+                }
+                None => {}
+            }
+        }
+        for earlier in &staged.replaced {
+            self.delete_chunks(earlier)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the chunks of every value in `batch` and collects the records to write into
+    /// `staged`, along with what the batch does to every key it touches.
+    fn stage_changes(&mut self, batch: &WriteBatch, staged: &mut Staged) -> Result<()> {
+        for change in &batch.changes {
+            let key = match change {
+                Change::Put { key, .. } | Change::Delete { key } => key,
+            };
+            let (hash, stored_key) = self.locate(key);
+            if !staged.before.contains_key(&stored_key) {
+                let old = match self.get_record(&hash, &stored_key)? {
+                    None => {
+                        let removed = self.backend.removed_version()?;
+                        staged.versions.insert(stored_key.clone(), removed);
+                        None
+                    }
+                    Some(old) => {
+                        staged.versions.insert(stored_key.clone(), old.version);
+                        Some(
+                            self.stored_value(&hash, &old)
+                                .map_err(|e| e.for_key(&hash))?,
+                        )
+                    }
+                };
+                staged.before.insert(stored_key.clone(), old);
+            }
+            let version = staged.versions.get_mut(&stored_key).unwrap();
+            let new = match change {
+                Change::Put { value, .. } => {
+                    *version += 1;
+                    let version = *version;
+                    let record =
+                        self.prepare_value(&hash, stored_key.clone(), value, batch.codec, version)?;
+                    let stored = self.stored_value(&hash, &record)?;
+                    staged.ops.push(BatchOp::Put { hash, record });
+                    Some(stored)
+                }
+                Change::Delete { .. } => {
+                    staged.ops.push(BatchOp::Delete {
+                        hash,
+                        key: stored_key.clone(),
+                    });
+                    None
+                }
+            };
+            if let Some(Some(earlier)) = staged.after.insert(stored_key, new) {
+                staged.replaced.push(earlier);
+            }
+        }
+
+        // The last versions of keys the batch leaves removed are kept before they are removed.
+        let removed = staged
+            .versions
+            .iter()
+            .filter(|(stored_key, _)| !matches!(staged.after.get(*stored_key), Some(Some(_))))
+            .map(|(_, version)| *version)
+            .max();
+        if let Some(version) = removed {
+            self.backend.retire_version(version)?;
+        }
+        Ok(())
+    }
+}
+
+/// What [Store::apply] has prepared of a batch before it hands the records to the backend.
+#[derive(Debug, Default)]
+struct Staged {
+    /// What every touched key held before the batch.
+    before: HashMap<Vec<u8>, Option<StoredValue>>,
+    /// What every touched key holds after the batch, as far as it has been prepared.
+    after: HashMap<Vec<u8>, Option<StoredValue>>,
+    /// The last version of every touched key so far, including versions that were removed
+    /// again.
+    versions: HashMap<Vec<u8>, u64>,
+    /// Values written by the batch and replaced by a later change of the same key.
+    replaced: Vec<StoredValue>,
+    /// The records to write.
+    ops: Vec<BatchOp>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WriteBatch;
+    use crate::{
+        Backend, BitcaskStore, Codec, KVStore, KeyValue, MemoryStore, Operations, Options, Store,
+    };
+    use std::fs;
+    use std::path::Path;
+    use walkdir::WalkDir;
+
+    #[test]
+    fn batches_apply_every_change_in_order() {
+        let mut kv_store: MemoryStore = Store::open("", Options::default()).unwrap();
+        kv_store.put("a", &1).unwrap();
+        kv_store.put("b", &2).unwrap();
+
+        let mut batch = kv_store.batch();
+        batch.put("a", &10).unwrap();
+        batch.delete("b").unwrap();
+        batch.put("c", &3).unwrap();
+        batch.put("c", &30).unwrap();
+        batch.delete("missing").unwrap();
+        batch.put("d", &4).unwrap();
+        batch.delete("d").unwrap();
+        assert_eq!(batch.len(), 7);
+        kv_store.apply(batch).unwrap();
+
+        assert_eq!(kv_store.len(), 2);
+        assert_eq!(kv_store.get("a").unwrap(), Some(10));
+        assert_eq!(kv_store.get::<_, i32>("b").unwrap(), None);
+        assert_eq!(kv_store.get("c").unwrap(), Some(30));
+        assert_eq!(kv_store.get::<_, i32>("d").unwrap(), None);
+    }
+
+    #[test]
+    fn batches_survive_reopening() {
+        let path = "./test-KV/batch1";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        Operations::insert(&mut kv_store, String::from("from"), 5_i32).unwrap();
+        let mut batch = kv_store.batch();
+        batch.delete("from").unwrap();
+        batch.put("to", &5_i32).unwrap();
+        batch.put("log", "moved 5").unwrap();
+        kv_store.apply(batch).unwrap();
+        assert_eq!(kv_store.size(), 2);
+
+        let kv_store = KVStore::new(path).unwrap();
+        assert_eq!(kv_store.size(), 2);
+        assert_eq!(kv_store.get::<_, i32>("from").unwrap(), None);
+        assert_eq!(kv_store.get("to").unwrap(), Some(5_i32));
+        assert_eq!(kv_store.get("log").unwrap(), Some(String::from("moved 5")));
+    }
+
+    #[test]
+    fn torn_bitcask_batches_are_dropped_whole() {
+        let path = "./test-KV/batch2";
+        let _ = fs::remove_dir_all(path);
+        let options = Options {
+            chunk_size: 8,
+            ..Options::default()
+        };
+        let mut kv_store = BitcaskStore::open(path, options.clone()).unwrap();
+        kv_store.put("kept", &1_u8).unwrap();
+        let mut batch = WriteBatch::new(Codec::Bincode);
+        batch.put("large", &vec![7_u8; 100]).unwrap();
+        batch.put("small", &2_u8).unwrap();
+        batch.delete("kept").unwrap();
+        kv_store.apply(batch).unwrap();
+        assert_eq!(kv_store.get("large").unwrap(), Some(vec![7_u8; 100]));
+        drop(kv_store);
+
+        let kv_store = BitcaskStore::open(path, options.clone()).unwrap();
+        assert_eq!(kv_store.len(), 2);
+        assert_eq!(kv_store.get("large").unwrap(), Some(vec![7_u8; 100]));
+        assert_eq!(kv_store.get("small").unwrap(), Some(2_u8));
+        drop(kv_store);
+
+        // Cut the batch entry short, as a crash in the middle of writing it would.
+        let segment = fs::read_dir(path)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.extension().is_some_and(|x| x == "log"))
+            .unwrap();
+        let len = fs::metadata(&segment).unwrap().len();
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&segment)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        let kv_store = BitcaskStore::open(path, options).unwrap();
+        assert_eq!(kv_store.len(), 1);
+        assert_eq!(kv_store.get("kept").unwrap(), Some(1_u8));
+        assert!(kv_store.backend().scan().unwrap().len() == 1);
+    }
+
+    #[test]
+    fn failed_batches_release_their_chunks() {
+        let path = "./test-KV/batch3";
+        let _ = fs::remove_dir_all(path);
+        let options = Options {
+            chunk_size: 8,
+            deduplicate: true,
+            ..Options::default()
+        };
+        let mut kv_store = KVStore::open(path, options).unwrap();
+        let first = vec![1_u8; 40];
+        let mut later = vec![2_u8; 40];
+        let first_prefix = kv_store.content_id(&first[..8])[..2].to_string();
+        let later_prefix = loop {
+            let id = kv_store.content_id(&later[..8]);
+            if id[..2] != first_prefix {
+                break id[..2].to_string();
+            }
+            later[0] += 1;
+        };
+        // A file stands where the directory of the first chunk of the later value goes.
+        let chunks = Path::new(path).join("chunks");
+        fs::create_dir_all(&chunks).unwrap();
+        fs::write(chunks.join(&later_prefix), b"").unwrap();
+
+        let mut batch = kv_store.batch();
+        batch.put_bytes(b"first", &first);
+        batch.put_bytes(b"later", &later);
+        assert!(kv_store.apply(batch).is_err());
+        let chunk_files = WalkDir::new(&chunks)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().extension().is_some_and(|x| x == "chunk"))
+            .count();
+        assert_eq!(chunk_files, 0);
+        assert!(kv_store.is_empty());
+    }
+}
@@ -0,0 +1,577 @@
+//! An append-only, log-structured storage engine in the style of Bitcask.
+//!
+//! Every mapping is appended to the active segment file as a framed entry whose value is the
+//! encoded [Record]. An in-memory key directory remembers, for every live key, which segment
+//! holds its latest record and where, so an insert is a single sequential append and a lookup is
+//! a single positioned read.
+//!
+//! Each entry on disk looks like this (all integers little-endian):
+//!
+//! ```text
+//! +-------+------+---------+-----------+-----+-------+
+//! | crc32 | kind | key len | value len | key | value |
+//! |  u32  |  u8  |   u32   |    u32    |     |       |
+//! +-------+------+---------+-----------+-----+-------+
+//! ```
+//!
+//! The checksum covers everything after itself. Removals are written as tombstone entries
+//! without a value. The chunks of large values are logged the same way, as entries of their own
+//! kinds whose key is the chunk id. A write batch is logged as a single entry with an empty key
+//! whose value holds the framed entries of the batch without their checksums, so that the one
+//! checksum of the batch entry covers all of them. A torn entry at the end of the newest segment (for example after a crash
+//! mid-append) is truncated away when the store is opened.
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::durability::Syncer;
+use crate::{Backend, BatchOp, Options, Record, Store};
+
+/// The size at which the active segment is closed and a new one is started.
+pub const DEFAULT_SEGMENT_SIZE: u64 = 4 * 1024 * 1024;
+
+const SEGMENT_EXTENSION: &str = "log";
+const HEADER_LEN: usize = 13;
+const KIND_PUT: u8 = 1;
+const KIND_DELETE: u8 = 2;
+const KIND_PUT_CHUNK: u8 = 3;
+const KIND_DELETE_CHUNK: u8 = 4;
+const KIND_BATCH: u8 = 5;
+/// The length of the header of an entry inside a batch, which has no checksum of its own.
+const BATCH_HEADER_LEN: usize = HEADER_LEN - 4;
+
+/// Where the latest value of a key lives on disk.
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    segment: u32,
+    offset: u64,
+    len: u32,
+}
+
+/// A decoded entry read back while replaying a segment.
+struct LogEntry {
+    kind: u8,
+    key: Vec<u8>,
+    value_offset: u64,
+    value_len: u32,
+    /// The offset right after this entry.
+    end: u64,
+}
+
+#[derive(Debug)]
+/// A backend that appends records to segment files instead of writing one file per key.
+pub struct Bitcask {
+    /// The directory holding the segment files.
+    path: PathBuf,
+    /// The latest location of every live key.
+    keydir: HashMap<Vec<u8>, Location>,
+    /// The latest location of every live chunk.
+    chunks: HashMap<Vec<u8>, Location>,
+    /// Read handles for every segment, including the active one.
+    readers: HashMap<u32, File>,
+    /// The segment new entries are appended to.
+    active: File,
+    active_id: u32,
+    active_len: u64,
+    max_segment_size: u64,
+    syncer: Syncer,
+}
+
+/// A key-value store backed by append-only segment files.
+pub type BitcaskStore = Store<Bitcask>;
+
+impl Bitcask {
+    /// Returns the number of segment files currently backing the store.
+    pub fn segment_count(&self) -> usize {
+        self.readers.len()
+    }
+
+    /// Rewrites every live entry into fresh segments and deletes the old ones, reclaiming the
+    /// space held by overwritten and removed values.
+    pub fn compact(&mut self) -> std::io::Result<()> {
+        let mut old_segments: Vec<u32> = self.readers.keys().copied().collect();
+        // Old segments go oldest first. A crash half-way then only leaves newer segments behind,
+        // so a tombstone is never lost while an older segment still holds the value it removed.
+        old_segments.sort_unstable();
+        let live: Vec<(u8, Vec<u8>, Location)> = self
+            .keydir
+            .iter()
+            .map(|(k, l)| (KIND_PUT, k.clone(), *l))
+            .chain(
+                self.chunks
+                    .iter()
+                    .map(|(k, l)| (KIND_PUT_CHUNK, k.clone(), *l)),
+            )
+            .collect();
+
+        self.roll_over()?;
+        for (kind, key, location) in live {
+            let value = self.read_value(&location)?;
+            self.append(kind, &key, &value)?;
+        }
+        self.active.sync_all()?;
+
+        for id in old_segments {
+            self.readers.remove(&id);
+            fs::remove_file(segment_path(&self.path, id))?;
+        }
+        self.syncer.dir_changed(&self.path)
+    }
+
+    fn roll_over(&mut self) -> std::io::Result<()> {
+        self.active_id += 1;
+        let path = segment_path(&self.path, self.active_id);
+        self.active = OpenOptions::new().create(true).append(true).open(&path)?;
+        self.readers.insert(self.active_id, File::open(&path)?);
+        self.active_len = 0;
+        self.syncer.dir_changed(&self.path)
+    }
+
+    fn append(&mut self, kind: u8, key: &[u8], value: &[u8]) -> std::io::Result<()> {
+        if self.active_len > 0 && self.active_len >= self.max_segment_size {
+            self.roll_over()?;
+        }
+
+        let (key_len, value_len) = (entry_len(key)?, entry_len(value)?);
+        let mut entry = Vec::with_capacity(HEADER_LEN + key.len() + value.len());
+        entry.extend_from_slice(&[0; 4]);
+        entry.push(kind);
+        entry.extend_from_slice(&key_len.to_le_bytes());
+        entry.extend_from_slice(&value_len.to_le_bytes());
+        entry.extend_from_slice(key);
+        entry.extend_from_slice(value);
+        let crc = crc32fast::hash(&entry[4..]);
+        entry[0..4].copy_from_slice(&crc.to_le_bytes());
+
+        self.active.write_all(&entry)?;
+        self.syncer
+            .file_changed(&segment_path(&self.path, self.active_id))?;
+
+        let value_offset = self.active_len + (HEADER_LEN + key.len()) as u64;
+        self.active_len += entry.len() as u64;
+        let location = Location {
+            segment: self.active_id,
+            offset: value_offset,
+            len: value_len,
+        };
+        track(
+            &mut self.keydir,
+            &mut self.chunks,
+            kind,
+            key.to_vec(),
+            location,
+            value,
+        );
+        Ok(())
+    }
+
+    fn read_value(&self, location: &Location) -> std::io::Result<Vec<u8>> {
+        let mut file = match self.readers.get(&location.segment) {
+            Some(file) => file,
+            None => return Err(Error::other("Segment file does not exist!")),
+        };
+        let mut value = vec![0; location.len as usize];
+        file.seek(SeekFrom::Start(location.offset))?;
+        file.read_exact(&mut value)?;
+        Ok(value)
+    }
+}
+
+impl Backend for Bitcask {
+    /// Opens (or creates) a backend at `path` that rolls over to a new segment once the active
+    /// one grows past [Options::segment_size] bytes.
+    fn open(path: &str, options: &Options) -> std::io::Result<Bitcask> {
+        let root = PathBuf::from(path);
+        fs::create_dir_all(&root)?;
+
+        let segments = list_segments(&root)?;
+        let mut keydir = HashMap::new();
+        let mut chunks = HashMap::new();
+        let mut readers = HashMap::new();
+        let mut active_len = 0;
+
+        for (i, &id) in segments.iter().enumerate() {
+            let is_last = i + 1 == segments.len();
+            let valid_len = replay_segment(
+                &segment_path(&root, id),
+                id,
+                &mut keydir,
+                &mut chunks,
+                is_last,
+            )?;
+            if is_last {
+                active_len = valid_len;
+            }
+            readers.insert(id, File::open(segment_path(&root, id))?);
+        }
+
+        let active_id = segments.last().copied().unwrap_or(0);
+        let active = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(&root, active_id))?;
+        if let Entry::Vacant(slot) = readers.entry(active_id) {
+            slot.insert(File::open(segment_path(&root, active_id))?);
+        }
+
+        Ok(Bitcask {
+            path: root,
+            keydir,
+            chunks,
+            readers,
+            active,
+            active_id,
+            active_len,
+            max_segment_size: options.segment_size,
+            syncer: Syncer::new(options.durability),
+        })
+    }
+
+    fn put(&mut self, _hash: &str, record: &Record) -> std::io::Result<()> {
+        self.append(KIND_PUT, &record.key, &record.encode())
+    }
+
+    fn get(&self, _hash: &str, key: &[u8]) -> std::io::Result<Option<Record>> {
+        match self.keydir.get(key) {
+            Some(location) => Ok(Some(Record::decode(&self.read_value(location)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn delete(&mut self, _hash: &str, key: &[u8]) -> std::io::Result<bool> {
+        if !self.keydir.contains_key(key) {
+            return Ok(false);
+        }
+        self.append(KIND_DELETE, key, &[])?;
+        Ok(true)
+    }
+
+    fn exists(&self, _hash: &str, key: &[u8]) -> std::io::Result<bool> {
+        Ok(self.keydir.contains_key(key))
+    }
+
+    /// Appends the whole batch as a single entry, so that a torn batch is cut off as a whole
+    /// when the store is opened.
+    fn write_batch(&mut self, ops: &[BatchOp]) -> std::io::Result<()> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let mut batch = Vec::new();
+        for op in ops {
+            let (kind, key, value) = match op {
+                BatchOp::Put { record, .. } => (KIND_PUT, &record.key, record.encode()),
+                BatchOp::Delete { key, .. } => (KIND_DELETE, key, Vec::new()),
+            };
+            batch.push(kind);
+            batch.extend_from_slice(&entry_len(key)?.to_le_bytes());
+            batch.extend_from_slice(&entry_len(&value)?.to_le_bytes());
+            batch.extend_from_slice(key);
+            batch.extend_from_slice(&value);
+        }
+        self.append(KIND_BATCH, &[], &batch)
+    }
+
+    fn put_chunk(&mut self, id: &str, chunk: &[u8]) -> std::io::Result<()> {
+        self.append(KIND_PUT_CHUNK, id.as_bytes(), chunk)
+    }
+
+    fn get_chunk(&self, id: &str) -> std::io::Result<Option<Vec<u8>>> {
+        match self.chunks.get(id.as_bytes()) {
+            Some(location) => Ok(Some(self.read_value(location)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn delete_chunk(&mut self, id: &str) -> std::io::Result<bool> {
+        if !self.chunks.contains_key(id.as_bytes()) {
+            return Ok(false);
+        }
+        self.append(KIND_DELETE_CHUNK, id.as_bytes(), &[])?;
+        Ok(true)
+    }
+
+    fn scan(&self) -> std::io::Result<Vec<Vec<u8>>> {
+        Ok(self.keydir.keys().cloned().collect())
+    }
+
+    fn count(&self) -> std::io::Result<usize> {
+        Ok(self.keydir.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.syncer.flush()
+    }
+}
+
+/// Returns the length of the key or value of an entry as it is framed, or an
+/// [ErrorKind::InvalidInput] error if it is too long to be.
+fn entry_len(bytes: &[u8]) -> std::io::Result<u32> {
+    u32::try_from(bytes.len()).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Entry of {} bytes is too long for a segment, which allows at most {}!",
+                bytes.len(),
+                u32::MAX
+            ),
+        )
+    })
+}
+
+fn segment_path(root: &Path, id: u32) -> PathBuf {
+    root.join(format!("{:010}.{}", id, SEGMENT_EXTENSION))
+}
+
+/// Returns the ids of all segment files under `root`, oldest first.
+fn list_segments(root: &Path) -> std::io::Result<Vec<u32>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+            continue;
+        }
+        if let Some(id) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u32>().ok())
+        {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Records where the entry of the given kind for `key` lives, or forgets the key if the entry
+/// is a tombstone. `value` is the value of the entry, which holds the entries of a batch.
+fn track(
+    keydir: &mut HashMap<Vec<u8>, Location>,
+    chunks: &mut HashMap<Vec<u8>, Location>,
+    kind: u8,
+    key: Vec<u8>,
+    location: Location,
+    value: &[u8],
+) {
+    match kind {
+        KIND_BATCH => {
+            let mut start = 0;
+            while let Some(header) = value.get(start..start + BATCH_HEADER_LEN) {
+                let kind = header[0];
+                let key_len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+                let value_len = u32::from_le_bytes([header[5], header[6], header[7], header[8]]);
+                let key_start = start + BATCH_HEADER_LEN;
+                let value_start = key_start + key_len as usize;
+                let entry_location = Location {
+                    segment: location.segment,
+                    offset: location.offset + value_start as u64,
+                    len: value_len,
+                };
+                let key = value[key_start..value_start].to_vec();
+                let entry_value = &value[value_start..value_start + value_len as usize];
+                track(keydir, chunks, kind, key, entry_location, entry_value);
+                start = value_start + value_len as usize;
+            }
+        }
+        KIND_PUT => {
+            keydir.insert(key, location);
+        }
+        KIND_DELETE => {
+            keydir.remove(&key);
+        }
+        KIND_PUT_CHUNK => {
+            chunks.insert(key, location);
+        }
+        _ => {
+            chunks.remove(&key);
+        }
+    }
+}
+
+/// Replays one segment into the key and chunk directories and returns the length of its valid prefix.
+///
+/// A damaged entry in the newest segment is treated as a torn write and cut off; anywhere else
+/// it means the store is corrupt.
+fn replay_segment(
+    path: &Path,
+    id: u32,
+    keydir: &mut HashMap<Vec<u8>, Location>,
+    chunks: &mut HashMap<Vec<u8>, Location>,
+    is_last: bool,
+) -> std::io::Result<u64> {
+    let data = fs::read(path)?;
+    let mut offset = 0u64;
+
+    while (offset as usize) < data.len() {
+        match decode_entry(&data, offset) {
+            Some(entry) => {
+                let location = Location {
+                    segment: id,
+                    offset: entry.value_offset,
+                    len: entry.value_len,
+                };
+                let value = &data[entry.value_offset as usize..entry.end as usize];
+                track(keydir, chunks, entry.kind, entry.key, location, value);
+                offset = entry.end;
+            }
+            None if is_last => {
+                OpenOptions::new().write(true).open(path)?.set_len(offset)?;
+                break;
+            }
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Corrupt entry in segment {} at offset {}!", id, offset),
+                ))
+            }
+        }
+    }
+    Ok(offset)
+}
+
+fn decode_entry(data: &[u8], offset: u64) -> Option<LogEntry> {
+    let start = offset as usize;
+    let header = data.get(start..start + HEADER_LEN)?;
+    let crc = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let kind = header[4];
+    let key_len = u32::from_le_bytes([header[5], header[6], header[7], header[8]]) as usize;
+    let value_len = u32::from_le_bytes([header[9], header[10], header[11], header[12]]);
+
+    let key_start = start + HEADER_LEN;
+    let end = key_start + key_len + value_len as usize;
+    let body = data.get(start + 4..end)?;
+    if crc32fast::hash(body) != crc || !(KIND_PUT..=KIND_BATCH).contains(&kind) {
+        return None;
+    }
+
+    Some(LogEntry {
+        kind,
+        key: data[key_start..key_start + key_len].to_vec(),
+        value_offset: (key_start + key_len) as u64,
+        value_len,
+        end: end as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BitcaskStore;
+    use crate::{Operations, Options};
+    use std::fs;
+    use std::io::Write;
+
+    fn fresh(path: &str, max_segment_size: u64) -> BitcaskStore {
+        let _ = fs::remove_dir_all(path);
+        open(path, max_segment_size)
+    }
+
+    fn open(path: &str, max_segment_size: u64) -> BitcaskStore {
+        let options = Options {
+            segment_size: max_segment_size,
+            ..Options::default()
+        };
+        BitcaskStore::open(path, options).unwrap()
+    }
+
+    #[test]
+    fn insert_lookup_remove() {
+        let mut kv_store = fresh("./test-KV/bitcask1", super::DEFAULT_SEGMENT_SIZE);
+        kv_store.insert(String::from("Pizza"), 21_i32).unwrap();
+        kv_store.insert(String::from("Coffee"), 33_i32).unwrap();
+        assert!(kv_store.insert(String::from("Pizza"), 1_i32).is_err());
+        assert_eq!(kv_store.size(), 2);
+
+        assert_eq!(
+            kv_store
+                .lookup::<String, i32>(String::from("Pizza"))
+                .unwrap(),
+            21
+        );
+        assert_eq!(
+            kv_store
+                .remove::<String, i32>(String::from("Pizza"))
+                .unwrap(),
+            21
+        );
+        assert!(kv_store
+            .lookup::<String, i32>(String::from("Pizza"))
+            .is_err());
+        assert!(kv_store
+            .remove::<String, i32>(String::from("Pizza"))
+            .is_err());
+        assert_eq!(kv_store.size(), 1);
+    }
+
+    #[test]
+    fn reopen_replays_segments() {
+        let path = "./test-KV/bitcask2";
+        {
+            let mut kv_store = fresh(path, 64);
+            for i in 0..20 {
+                kv_store.insert(i, format!("value {}", i)).unwrap();
+            }
+            for i in 0..5 {
+                kv_store.remove::<i32, String>(i).unwrap();
+            }
+            assert!(kv_store.backend().segment_count() > 1);
+        }
+
+        let kv_store = open(path, 64);
+        assert_eq!(kv_store.size(), 15);
+        assert!(kv_store.lookup::<i32, String>(3).is_err());
+        assert_eq!(kv_store.lookup::<i32, String>(12).unwrap(), "value 12");
+    }
+
+    #[test]
+    fn compact_keeps_live_entries() {
+        let path = "./test-KV/bitcask3";
+        let mut kv_store = fresh(path, 64);
+        for i in 0..20 {
+            kv_store.insert(i, i * 10).unwrap();
+        }
+        for i in 0..18 {
+            kv_store.remove::<i32, i32>(i).unwrap();
+        }
+        let before = kv_store.backend().segment_count();
+        kv_store.backend_mut().compact().unwrap();
+        assert!(kv_store.backend().segment_count() < before);
+        assert_eq!(kv_store.lookup::<i32, i32>(19).unwrap(), 190);
+
+        let kv_store = open(path, 64);
+        assert_eq!(kv_store.size(), 2);
+        assert_eq!(kv_store.lookup::<i32, i32>(18).unwrap(), 180);
+    }
+
+    #[test]
+    fn torn_tail_is_truncated() {
+        let path = "./test-KV/bitcask4";
+        {
+            let mut kv_store = fresh(path, super::DEFAULT_SEGMENT_SIZE);
+            kv_store.insert(String::from("Earth"), 77_i32).unwrap();
+        }
+        let segment = super::segment_path(std::path::Path::new(path), 0);
+        let mut file = fs::OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(&[1, 2, 3, 4, 1, 9]).unwrap();
+
+        let mut kv_store = open(path, super::DEFAULT_SEGMENT_SIZE);
+        assert_eq!(kv_store.size(), 1);
+        kv_store.insert(String::from("Mars"), 4_i32).unwrap();
+
+        let kv_store = open(path, super::DEFAULT_SEGMENT_SIZE);
+        assert_eq!(
+            kv_store
+                .lookup::<String, i32>(String::from("Mars"))
+                .unwrap(),
+            4
+        );
+        assert_eq!(
+            kv_store
+                .lookup::<String, i32>(String::from("Earth"))
+                .unwrap(),
+            77
+        );
+    }
+}
@@ -0,0 +1,212 @@
+//! The formats values can be serialized with.
+//!
+//! A store serializes new values with the codec it was created with, and every [crate::Record]
+//! remembers which codec wrote it. Lookups always decode with the codec from the record, so a
+//! value stays readable no matter which codec the store is opened with later.
+//!
+//! Keys are always serialized as JSON, so a key is found under the same hash whatever codec its
+//! value was written with.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{Error, Result};
+
+/// A serialization format for values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    /// JSON, through `serde_json`. Readable, but the largest and slowest.
+    #[default]
+    Json,
+    /// bincode, a compact binary format without field names.
+    Bincode,
+    /// CBOR (RFC 8949), through `ciborium`.
+    Cbor,
+    /// MessagePack, through `rmp-serde`. Structs keep their field names.
+    MessagePack,
+}
+
+impl Codec {
+    const ALL: [Codec; 4] = [Codec::Json, Codec::Bincode, Codec::Cbor, Codec::MessagePack];
+
+    /// Returns the id stored in the header of every record the codec wrote.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Codec::Json => 0,
+            Codec::Bincode => 1,
+            Codec::Cbor => 2,
+            Codec::MessagePack => 3,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Codec> {
+        Codec::ALL.iter().copied().find(|codec| codec.id() == id)
+    }
+
+    /// Returns the name recorded in the store manifest.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Codec::Json => "json",
+            Codec::Bincode => "bincode",
+            Codec::Cbor => "cbor",
+            Codec::MessagePack => "msgpack",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<Codec> {
+        Codec::ALL
+            .iter()
+            .copied()
+            .find(|codec| codec.name() == name)
+    }
+
+    pub(crate) fn encode<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>> {
+        let encoded = match self {
+            Codec::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Codec::Bincode => bincode::serialize(value).map_err(|e| e.to_string()),
+            Codec::Cbor => {
+                let mut out = Vec::new();
+                ciborium::ser::into_writer(value, &mut out)
+                    .map(|()| out)
+                    .map_err(|e| e.to_string())
+            }
+            Codec::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+        };
+        encoded.map_err(|e| {
+            Error::codec(format!(
+                "Value cannot be serialized as {}: {}",
+                self.name(),
+                e
+            ))
+        })
+    }
+
+    /// Deserializes `bytes`, reporting bytes that do not parse as the requested type as an
+    /// [Error::Codec] error.
+    pub(crate) fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T> {
+        let decoded = match self {
+            Codec::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Codec::Bincode => bincode::deserialize(bytes).map_err(|e| e.to_string()),
+            Codec::Cbor => ciborium::de::from_reader(bytes).map_err(|e| e.to_string()),
+            Codec::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+        };
+        decoded.map_err(|e| {
+            Error::codec(format!(
+                "Stored value cannot be deserialized as {}: {}",
+                std::any::type_name::<T>(),
+                e
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Codec;
+    use crate::{BitcaskStore, KVStore, MemoryStore, Operations, Options, Store};
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+    use std::fs;
+
+    #[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+    struct Sample {
+        name: String,
+        bytes: Vec<u8>,
+        scores: HashMap<String, f64>,
+        nested: Option<Box<Sample>>,
+    }
+
+    fn sample() -> Sample {
+        let mut scores = HashMap::new();
+        scores.insert(String::from("pi"), 3.25);
+        Sample {
+            name: String::from("outer"),
+            bytes: vec![0, 1, 2, 255],
+            scores,
+            nested: Some(Box::new(Sample {
+                name: String::from("inner"),
+                ..Sample::default()
+            })),
+        }
+    }
+
+    #[test]
+    fn every_codec_round_trips() {
+        for codec in Codec::ALL.iter().copied() {
+            assert_eq!(Codec::from_id(codec.id()), Some(codec));
+            assert_eq!(Codec::from_name(codec.name()), Some(codec));
+
+            let options = Options {
+                codec,
+                ..Options::default()
+            };
+            let mut kv_store: MemoryStore = Store::open("", options).unwrap();
+            kv_store.insert(String::from("sample"), sample()).unwrap();
+            kv_store.insert(7_u8, vec![1_u8; 64]).unwrap();
+            assert_eq!(
+                kv_store
+                    .lookup::<String, Sample>(String::from("sample"))
+                    .unwrap(),
+                sample()
+            );
+            assert_eq!(kv_store.remove::<u8, Vec<u8>>(7).unwrap(), vec![1_u8; 64]);
+        }
+    }
+
+    #[test]
+    fn store_keeps_the_codec_it_was_created_with() {
+        let path = "./test-KV/codec1";
+        let _ = fs::remove_dir_all(path);
+        let options = Options {
+            codec: Codec::Bincode,
+            ..Options::default()
+        };
+        {
+            let mut kv_store = KVStore::open(path, options).unwrap();
+            kv_store
+                .insert(String::from("bytes"), vec![9_u8; 16])
+                .unwrap();
+        }
+        let mut kv_store = KVStore::new(path).unwrap();
+        assert_eq!(kv_store.codec(), Codec::Bincode);
+        kv_store
+            .insert(String::from("more"), vec![8_u8; 16])
+            .unwrap();
+        assert_eq!(
+            kv_store
+                .lookup::<String, Vec<u8>>(String::from("bytes"))
+                .unwrap(),
+            vec![9_u8; 16]
+        );
+    }
+
+    #[test]
+    fn records_are_decoded_with_the_codec_that_wrote_them() {
+        let path = "./test-KV/codec2";
+        let _ = fs::remove_dir_all(path);
+        for codec in Codec::ALL.iter().copied() {
+            let options = Options {
+                codec,
+                ..Options::default()
+            };
+            let mut kv_store = BitcaskStore::open(path, options).unwrap();
+            kv_store.insert(codec.name(), codec.id()).unwrap();
+        }
+        let kv_store = BitcaskStore::new(path).unwrap();
+        for codec in Codec::ALL.iter().copied() {
+            assert_eq!(
+                kv_store.lookup::<&str, u8>(codec.name()).unwrap(),
+                codec.id()
+            );
+        }
+    }
+
+    #[test]
+    fn binary_codecs_are_compact() {
+        let bytes = vec![200_u8; 256];
+        let json = Codec::Json.encode(&bytes).unwrap().len();
+        for codec in [Codec::Bincode, Codec::Cbor, Codec::MessagePack].iter() {
+            assert!(codec.encode(&bytes).unwrap().len() < json);
+        }
+    }
+}
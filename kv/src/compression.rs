@@ -0,0 +1,183 @@
+//! Optional compression of serialized values.
+//!
+//! Values at least [crate::Options::compression_threshold] bytes long are compressed on insert
+//! with the store's [Compression]. Whether and how a value was compressed is marked in the flags
+//! of its [crate::Record], so a store holding a mix of compressed and uncompressed values (for
+//! example after its compression setting changed) reads every one of them correctly.
+
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind};
+
+/// Record flag of a value compressed with LZ4.
+pub(crate) const FLAG_LZ4: u8 = 0b01;
+/// Record flag of a value compressed with zstd.
+pub(crate) const FLAG_ZSTD: u8 = 0b10;
+
+/// How values are compressed before they are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Store values as they are.
+    #[default]
+    None,
+    /// LZ4 through `lz4_flex`: fast, with a moderate ratio.
+    Lz4,
+    /// zstd at the given level (1 to 22; 3 is a good default): slower, with a better ratio.
+    Zstd(i32),
+}
+
+impl Compression {
+    /// Compresses `value` if it is at least `threshold` bytes long and compression actually
+    /// makes it smaller. Returns the bytes to store and the record flags that mark them.
+    pub(crate) fn compress(
+        self,
+        value: Vec<u8>,
+        threshold: usize,
+    ) -> std::io::Result<(Vec<u8>, u8)> {
+        if value.len() < threshold {
+            return Ok((value, 0));
+        }
+        let (compressed, flag) = match self {
+            Compression::None => return Ok((value, 0)),
+            Compression::Lz4 => (lz4_flex::compress_prepend_size(&value), FLAG_LZ4),
+            Compression::Zstd(level) => (zstd::bulk::compress(&value, level)?, FLAG_ZSTD),
+        };
+        if compressed.len() < value.len() {
+            Ok((compressed, flag))
+        } else {
+            Ok((value, 0))
+        }
+    }
+}
+
+/// Undoes the compression the record `flags` mark `value` with.
+pub(crate) fn decompress(value: Vec<u8>, flags: u8) -> std::io::Result<Vec<u8>> {
+    let decompressed = if flags & FLAG_LZ4 != 0 {
+        decompress_lz4(&value)
+    } else if flags & FLAG_ZSTD != 0 {
+        zstd::stream::decode_all(&value[..]).map_err(|e| e.to_string())
+    } else {
+        return Ok(value);
+    };
+    decompressed.map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Stored value does not decompress: {}", e),
+        )
+    })
+}
+
+/// How many bytes LZ4 can decode from a single byte of input at most.
+const LZ4_MAX_RATIO: usize = 255;
+
+/// Decompresses an LZ4 block prefixed with its decompressed size, which is checked against what
+/// the block could possibly hold before anything is allocated for it.
+fn decompress_lz4(value: &[u8]) -> Result<Vec<u8>, String> {
+    let (size, block) = match value.get(..4) {
+        Some(size) => (
+            u32::from_le_bytes(size.try_into().unwrap()) as usize,
+            &value[4..],
+        ),
+        None => return Err(String::from("the size prefix is missing")),
+    };
+    if size > block.len().saturating_mul(LZ4_MAX_RATIO) {
+        return Err(format!(
+            "a block of {} bytes cannot hold {} bytes",
+            block.len(),
+            size
+        ));
+    }
+    lz4_flex::decompress(block, size).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decompress, Compression, FLAG_LZ4, FLAG_ZSTD};
+    use crate::{Backend, Codec, KVStore, Operations, Options};
+    use std::fs;
+    use std::io::ErrorKind;
+
+    fn document(words: usize) -> String {
+        (0..words)
+            .map(|i| format!("word{} ", i % 10))
+            .collect::<String>()
+    }
+
+    #[test]
+    fn values_round_trip_through_every_compression() {
+        let value = document(500).into_bytes();
+        for (compression, flag) in [
+            (Compression::None, 0),
+            (Compression::Lz4, FLAG_LZ4),
+            (Compression::Zstd(3), FLAG_ZSTD),
+        ]
+        .iter()
+        {
+            let (stored, flags) = compression.compress(value.clone(), 64).unwrap();
+            assert_eq!(flags, *flag);
+            assert_eq!(decompress(stored, flags).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn damaged_size_prefixes_are_rejected() {
+        let (mut stored, flags) = Compression::Lz4
+            .compress(document(500).into_bytes(), 0)
+            .unwrap();
+        stored[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = decompress(stored, flags).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(decompress(vec![1, 0], FLAG_LZ4).is_err());
+
+        let zeros = vec![0; 1 << 20];
+        let (stored, flags) = Compression::Lz4.compress(zeros.clone(), 0).unwrap();
+        assert_eq!(decompress(stored, flags).unwrap(), zeros);
+    }
+
+    #[test]
+    fn small_and_incompressible_values_are_left_alone() {
+        let (stored, flags) = Compression::Zstd(3)
+            .compress(b"short".to_vec(), 64)
+            .unwrap();
+        assert_eq!((stored, flags), (b"short".to_vec(), 0));
+
+        let noise: Vec<u8> = (0..256_u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let (stored, flags) = Compression::Lz4.compress(noise.clone(), 0).unwrap();
+        assert_eq!((stored, flags), (noise, 0));
+    }
+
+    #[test]
+    fn mixed_stores_read_back_correctly() {
+        let path = "./test-KV/compression1";
+        let _ = fs::remove_dir_all(path);
+        let settings = [
+            (Compression::None, "plain"),
+            (Compression::Lz4, "lz4"),
+            (Compression::Zstd(3), "zstd"),
+        ];
+        for (compression, key) in settings.iter() {
+            let options = Options {
+                compression: *compression,
+                compression_threshold: 128,
+                ..Options::default()
+            };
+            let mut kv_store = KVStore::open(path, options).unwrap();
+            kv_store.insert(*key, document(200)).unwrap();
+            kv_store.insert(format!("{}-small", key), 1_i32).unwrap();
+        }
+
+        let kv_store = KVStore::new(path).unwrap();
+        let hash = crate::hash_key(&serde_json::to_string("zstd").unwrap());
+        let record = kv_store.backend().get(&hash, b"\"zstd\"").unwrap().unwrap();
+        assert_eq!(record.flags, FLAG_ZSTD);
+        assert_eq!(record.codec, Codec::Json.id());
+        for (_, key) in settings.iter() {
+            assert_eq!(kv_store.lookup::<&str, String>(key).unwrap(), document(200));
+            assert_eq!(
+                kv_store
+                    .lookup::<String, i32>(format!("{}-small", key))
+                    .unwrap(),
+                1
+            );
+        }
+    }
+}
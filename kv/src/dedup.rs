@@ -0,0 +1,258 @@
+//! Content-addressed deduplication of values.
+//!
+//! A store opened with [crate::Options::deduplicate] does not keep values with the keys they are
+//! inserted under. Every value is split into chunks like a large value (a value up to
+//! [crate::Options::chunk_size] bytes is a single chunk), each chunk is stored under the hash of
+//! its contents, and the record of the key only lists the chunks. Keys that map to identical
+//! values therefore share one copy of it, and large values that differ only in places share
+//! every chunk they have in common.
+//!
+//! Every shared chunk has a reference count, which is stored as a small chunk of its own under
+//! the id `<id>.refs` and holds a little-endian u64. Inserting a value increments the count of
+//! each of its chunks, writing the chunk if it is new, and removing a value decrements them,
+//! deleting a chunk once nothing refers to it any more.
+//!
+//! Counts are raised before the record that refers to the chunk is written and lowered after it
+//! is deleted. A crash in between can only leave a count too high, which keeps a chunk alive for
+//! too long, and never too low, which would delete a chunk that is still in use.
+//!
+//! In an encrypted store, chunks are addressed by a keyed HMAC of their contents, so chunk ids
+//! do not reveal which values are stored.
+
+use std::convert::TryInto;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use crate::{Backend, Error, Result, Store};
+
+/// Record flag of a record whose value is a list of shared chunks.
+pub(crate) const FLAG_SHARED: u8 = 0b1_0000;
+
+impl<B: Backend> Store<B> {
+    /// Returns the id a chunk with the given contents is shared under.
+    pub(crate) fn content_id(&self, chunk: &[u8]) -> String {
+        match &self.cipher {
+            Some(cipher) => cipher.hash(chunk),
+            None => {
+                let mut hasher = Sha256::new();
+                hasher.input(chunk);
+                hasher.result_str()
+            }
+        }
+    }
+
+    /// Takes a reference to the shared chunk `id`, storing `chunk` as its contents if nothing
+    /// refers to it yet.
+    ///
+    /// The count is read and written back under the store lock, so that a value streamed through
+    /// another handle at the same time cannot lose the reference.
+    pub(crate) fn share_chunk(&mut self, id: &str, chunk: Vec<u8>) -> Result<()> {
+        self.locked(|store| {
+            let references = store.references(id)?;
+            if references == 0 {
+                store.write_chunk(id, chunk)?;
+            }
+            store.set_references(id, references + 1)
+        })
+    }
+
+    /// Drops a reference to the shared chunk `id`, deleting the chunk if it was the last one.
+    pub(crate) fn release_chunk(&mut self, id: &str) -> Result<()> {
+        self.locked(|store| {
+            let references = store.references(id)?;
+            // The count goes first: a chunk without a count is simply written again by the next
+            // value that shares it, while a count without its chunk would never be noticed.
+            store.set_references(id, references.saturating_sub(1))?;
+            if references <= 1 {
+                store.backend.delete_chunk(id)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns how many values refer to the shared chunk `id`.
+    fn references(&self, id: &str) -> Result<u64> {
+        match self.backend.get_chunk(&references_id(id))? {
+            None => Ok(0),
+            Some(count) => match count.as_slice().try_into() {
+                Ok(count) => Ok(u64::from_le_bytes(count)),
+                Err(_) => Err(Error::corrupt(format!(
+                    "Reference count of chunk {} is corrupt!",
+                    id
+                ))),
+            },
+        }
+    }
+
+    fn set_references(&mut self, id: &str, references: u64) -> Result<()> {
+        if references == 0 {
+            self.backend.delete_chunk(&references_id(id))?;
+            Ok(())
+        } else {
+            self.backend
+                .put_chunk(&references_id(id), &references.to_le_bytes())?;
+            Ok(())
+        }
+    }
+}
+
+fn references_id(id: &str) -> String {
+    format!("{}.refs", id)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Backend, BitcaskStore, EncryptionKey, KVStore, MemoryStore, Operations, Options, Store,
+    };
+    use std::collections::BTreeMap;
+    use std::fs;
+    use walkdir::WalkDir;
+
+    fn options() -> Options {
+        Options {
+            deduplicate: true,
+            ..Options::default()
+        }
+    }
+
+    fn config() -> BTreeMap<String, String> {
+        let mut config = BTreeMap::new();
+        config.insert(String::from("theme"), String::from("dark"));
+        config.insert(String::from("language"), String::from("en"));
+        config
+    }
+
+    fn chunk_files(path: &str) -> usize {
+        WalkDir::new(path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().extension().is_some_and(|x| x == "chunk"))
+            .count()
+    }
+
+    #[test]
+    fn identical_values_are_stored_once() {
+        let path = "./test-KV/dedup1";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::open(path, options()).unwrap();
+        for user in 0..5_i32 {
+            kv_store.insert(user, config()).unwrap();
+        }
+        kv_store.insert(99_i32, String::from("other")).unwrap();
+        // One chunk and one reference count per distinct value.
+        assert_eq!(chunk_files(path), 4);
+
+        let mut kv_store = KVStore::open(path, options()).unwrap();
+        for user in 0..4_i32 {
+            assert_eq!(
+                kv_store
+                    .remove::<i32, BTreeMap<String, String>>(user)
+                    .unwrap(),
+                config()
+            );
+        }
+        assert_eq!(chunk_files(path), 4);
+        assert_eq!(
+            kv_store.lookup::<i32, BTreeMap<String, String>>(4).unwrap(),
+            config()
+        );
+        assert!(kv_store.delete(4_i32).unwrap());
+        assert!(kv_store.delete(99_i32).unwrap());
+        assert_eq!(chunk_files(path), 0);
+    }
+
+    #[test]
+    fn handles_streaming_the_same_value_keep_every_reference() {
+        let path = "./test-KV/dedup3";
+        let _ = fs::remove_dir_all(path);
+        let options = Options {
+            chunk_size: 16,
+            ..options()
+        };
+        let value: Vec<u8> = (0..64_u8).collect();
+        drop(KVStore::open(path, options.clone()).unwrap());
+        let writers: Vec<_> = (0..4_u32)
+            .map(|thread| {
+                let (options, value) = (options.clone(), value.clone());
+                std::thread::spawn(move || {
+                    let mut kv_store = KVStore::open(path, options).unwrap();
+                    for i in 0..10_u32 {
+                        kv_store.insert_from(thread * 10 + i, &value[..]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let mut kv_store = KVStore::open(path, options).unwrap();
+        let first = kv_store.content_id(&value[..16]);
+        assert_eq!(kv_store.references(&first).unwrap(), 40);
+        for key in 0..39_u32 {
+            assert!(kv_store.delete(key).unwrap());
+        }
+        assert_eq!(kv_store.read_range(39_u32, 0..64).unwrap(), value);
+        assert!(kv_store.delete(39_u32).unwrap());
+        assert_eq!(chunk_files(path), 0);
+    }
+
+    #[test]
+    fn large_values_share_common_chunks() {
+        let path = "./test-KV/dedup2";
+        let _ = fs::remove_dir_all(path);
+        let options = Options {
+            chunk_size: 100,
+            ..options()
+        };
+        let mut kv_store = BitcaskStore::open(path, options.clone()).unwrap();
+        let mut first = vec![1_u8; 300];
+        first.extend_from_slice(&[2; 100]);
+        let mut second = vec![1_u8; 300];
+        second.extend_from_slice(&[3; 100]);
+        kv_store.insert_from("first", &first[..]).unwrap();
+        kv_store.insert_from("second", &second[..]).unwrap();
+        let common = kv_store.content_id(&[1; 100]);
+        assert_eq!(kv_store.references(&common).unwrap(), 6);
+        kv_store.delete("first").unwrap();
+        assert_eq!(kv_store.references(&common).unwrap(), 3);
+        kv_store.backend_mut().compact().unwrap();
+
+        let kv_store = BitcaskStore::open(path, options).unwrap();
+        assert_eq!(
+            kv_store.read_range("second", 250..400).unwrap(),
+            &second[250..]
+        );
+        assert_eq!(kv_store.backend().segment_count(), 1);
+    }
+
+    #[test]
+    fn deduplicated_and_plain_values_mix() {
+        let key = EncryptionKey::from_bytes([9; 32]);
+        let encrypted = Options {
+            encryption_key: Some(key),
+            ..Options::default()
+        };
+        let mut kv_store: MemoryStore = Store::open("", encrypted).unwrap();
+        kv_store.insert("plain", config()).unwrap();
+        kv_store.deduplicate = true;
+        kv_store.insert("shared", config()).unwrap();
+        kv_store.insert("again", config()).unwrap();
+        let id = kv_store.content_id(&serde_json::to_vec(&config()).unwrap());
+        assert_eq!(kv_store.references(&id).unwrap(), 2);
+
+        kv_store.deduplicate = false;
+        for key in ["plain", "shared", "again"].iter() {
+            assert_eq!(
+                kv_store
+                    .remove::<&str, BTreeMap<String, String>>(key)
+                    .unwrap(),
+                config()
+            );
+        }
+        assert_eq!(kv_store.references(&id).unwrap(), 0);
+        assert!(kv_store.backend().get_chunk(&id).unwrap().is_none());
+    }
+}
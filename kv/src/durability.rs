@@ -0,0 +1,223 @@
+//! Atomic file replacement and the fsync policy behind it.
+//!
+//! Files are never written in place. New contents go to a temporary file next to the target,
+//! which is then renamed over it, so readers only ever see the old or the new contents and never
+//! a truncated mix. How much of that reaches the disk before an operation returns is decided by
+//! the [Durability] level the store was opened with.
+
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How eagerly changes are flushed to stable storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Never fsync. Writes are still atomic with respect to a crashing process, but a power loss
+    /// can lose or tear recent changes.
+    None,
+    /// Fsync every file and directory an operation touched before it returns.
+    #[default]
+    PerOperation,
+    /// Group fsyncs: changes are flushed together once the given interval has passed since the
+    /// last flush, when [crate::Store::flush] is called, and when the store is dropped. A power
+    /// loss can lose the changes of the last interval, but never leaves a replaced file torn.
+    Periodic(Duration),
+}
+
+/// Applies a [Durability] level to the files a backend writes.
+#[derive(Debug)]
+pub(crate) struct Syncer {
+    durability: Durability,
+    /// Files and directories written since the last flush, for [Durability::Periodic].
+    pending: BTreeSet<PathBuf>,
+    last_flush: Instant,
+}
+
+impl Syncer {
+    pub(crate) fn new(durability: Durability) -> Syncer {
+        Syncer {
+            durability,
+            pending: BTreeSet::new(),
+            last_flush: Instant::now(),
+        }
+    }
+
+    /// Replaces the contents of `path` with `bytes` through a temporary file and a rename.
+    ///
+    /// Unless the level is [Durability::None], the temporary file reaches the disk before it is
+    /// renamed, since a rename that is flushed before the contents it names would leave an empty
+    /// or torn file behind after a power loss. Only the rename itself is left to the level.
+    pub(crate) fn write_atomic(&mut self, path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+        let tmp = temp_path(path);
+        let mut file = File::create(&tmp)?;
+        file.write_all(bytes)?;
+        if self.durability != Durability::None {
+            file.sync_all()?;
+        }
+        drop(file);
+
+        if let Err(e) = fs::rename(&tmp, path) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        self.dir_changed(parent(path))
+    }
+
+    /// Records that the contents of `path` changed outside of [Syncer::write_atomic], for
+    /// example by appending to it.
+    pub(crate) fn file_changed(&mut self, path: &Path) -> std::io::Result<()> {
+        match self.durability {
+            Durability::None => Ok(()),
+            Durability::PerOperation => sync_path(path),
+            Durability::Periodic(_) => {
+                self.pending.insert(path.to_path_buf());
+                self.maybe_flush()
+            }
+        }
+    }
+
+    /// Records that an entry was added to or removed from the directory `dir`.
+    pub(crate) fn dir_changed(&mut self, dir: &Path) -> std::io::Result<()> {
+        match self.durability {
+            Durability::None => Ok(()),
+            Durability::PerOperation => sync_dir(dir),
+            Durability::Periodic(_) => {
+                self.pending.insert(dir.to_path_buf());
+                self.maybe_flush()
+            }
+        }
+    }
+
+    /// Flushes everything written since the last flush.
+    pub(crate) fn flush(&mut self) -> std::io::Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        for path in pending {
+            if path.is_dir() {
+                sync_dir(&path)?;
+            } else if path.is_file() {
+                sync_path(&path)?;
+            }
+        }
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    fn maybe_flush(&mut self) -> std::io::Result<()> {
+        if let Durability::Periodic(interval) = self.durability {
+            if self.last_flush.elapsed() >= interval {
+                return self.flush();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy())
+        .unwrap_or_default();
+    parent(path).join(format!(".{}.tmp", name))
+}
+
+fn parent(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+fn sync_path(path: &Path) -> std::io::Result<()> {
+    File::open(path)?.sync_all()
+}
+
+/// Makes a rename or removal inside `dir` durable. Only meaningful on Unix; other platforms
+/// cannot open directories and are skipped.
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    if cfg!(unix) {
+        File::open(dir)?.sync_all()
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Durability, Syncer};
+    use crate::{KVStore, Operations, Options};
+    use std::fs;
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn write_atomic_replaces_without_leftovers() {
+        let dir = Path::new("./test-KV/durability1");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+
+        let mut syncer = Syncer::new(Durability::PerOperation);
+        let path = dir.join("file.value");
+        syncer.write_atomic(&path, b"first").unwrap();
+        syncer.write_atomic(&path, b"second").unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn periodic_durability_groups_flushes() {
+        let dir = Path::new("./test-KV/durability2");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+
+        let mut syncer = Syncer::new(Durability::Periodic(Duration::from_secs(3600)));
+        syncer.write_atomic(&dir.join("a"), b"a").unwrap();
+        syncer.write_atomic(&dir.join("b"), b"b").unwrap();
+        // Both files were synced before they were renamed, which leaves their directory.
+        assert_eq!(syncer.pending.len(), 1);
+
+        syncer.flush().unwrap();
+        assert!(syncer.pending.is_empty());
+    }
+
+    #[test]
+    fn every_durability_level_round_trips() {
+        let levels = [
+            Durability::None,
+            Durability::PerOperation,
+            Durability::Periodic(Duration::from_millis(0)),
+        ];
+        for (i, durability) in levels.iter().enumerate() {
+            let path = format!("./test-KV/durability3-{}", i);
+            let _ = fs::remove_dir_all(&path);
+            let options = Options {
+                durability: *durability,
+                ..Options::default()
+            };
+            {
+                let mut kv_store = KVStore::open(&path, options.clone()).unwrap();
+                kv_store.insert(String::from("Sine"), 360_i32).unwrap();
+                kv_store.insert(String::from("Wave"), 180_i32).unwrap();
+                kv_store
+                    .remove::<String, i32>(String::from("Wave"))
+                    .unwrap();
+            }
+            let kv_store = KVStore::open(&path, options).unwrap();
+            assert_eq!(kv_store.size(), 1);
+            assert_eq!(
+                kv_store
+                    .lookup::<String, i32>(String::from("Sine"))
+                    .unwrap(),
+                360
+            );
+        }
+    }
+}
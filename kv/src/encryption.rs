@@ -0,0 +1,249 @@
+//! Encryption at rest with ChaCha20-Poly1305.
+//!
+//! A store opened with an [EncryptionKey] encrypts the serialized key and the value of every
+//! record, and locates record files by a keyed HMAC-SHA256 of the key instead of its plain
+//! SHA-256 digest, so neither file contents nor file names reveal which keys are stored.
+//!
+//! Encryption is deterministic in the style of SIV: a 16 byte salt is derived from an HMAC over
+//! the plaintext, and the salt in turn derives the one-time ChaCha20-Poly1305 key. The same key
+//! therefore always encrypts to the same bytes, which is what lets backends compare stored keys,
+//! while different plaintexts never share a cipher key. A sealed payload looks like this:
+//!
+//! ```text
+//! +------+-----+------------+
+//! | salt | tag | ciphertext |
+//! | 16 B | 16B |            |
+//! +------+-----+------------+
+//! ```
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+
+/// Record flag of a record whose key and value are encrypted.
+pub(crate) const FLAG_ENCRYPTED: u8 = 0b100;
+
+const SALT_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+
+/// The secret a store is encrypted with.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    /// Wraps 32 bytes of key material, which should come from a secure random source or a key
+    /// derivation function.
+    pub fn from_bytes(bytes: [u8; 32]) -> EncryptionKey {
+        EncryptionKey(bytes)
+    }
+
+    /// Returns a short fingerprint that is recorded in the manifest to recognise the right key.
+    pub(crate) fn check_value(&self) -> String {
+        hex(&hmac(&self.0, &[b"kv key check"])[..8])
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// The keys derived from an [EncryptionKey] for hashing and sealing.
+#[derive(Clone)]
+pub(crate) struct Cipher {
+    hash_key: [u8; 32],
+    salt_key: [u8; 32],
+    seal_key: [u8; 32],
+}
+
+impl Cipher {
+    pub(crate) fn new(key: &EncryptionKey) -> Cipher {
+        Cipher {
+            hash_key: hmac(&key.0, &[b"kv hash"]),
+            salt_key: hmac(&key.0, &[b"kv salt"]),
+            seal_key: hmac(&key.0, &[b"kv seal"]),
+        }
+    }
+
+    /// Returns the hex HMAC-SHA256 used to locate a serialized key.
+    pub(crate) fn hash(&self, serialized_key: &[u8]) -> String {
+        hex(&hmac(&self.hash_key, &[serialized_key]))
+    }
+
+    /// Encrypts and authenticates `plaintext`. `context` is authenticated as well, so a payload
+    /// only opens in the context it was sealed for.
+    pub(crate) fn seal(&self, context: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let context_len = (context.len() as u64).to_le_bytes();
+        let salt = hmac(&self.salt_key, &[&context_len, context, plaintext]);
+        let salt = &salt[..SALT_LEN];
+
+        let mut sealed = vec![0; SALT_LEN + TAG_LEN + plaintext.len()];
+        let (header, ciphertext) = sealed.split_at_mut(SALT_LEN + TAG_LEN);
+        header[..SALT_LEN].copy_from_slice(salt);
+        let mut aead = ChaCha20Poly1305::new(&hmac(&self.seal_key, &[salt]), &[0; 8], context);
+        aead.encrypt(plaintext, ciphertext, &mut header[SALT_LEN..]);
+        sealed
+    }
+
+    /// Decrypts a payload sealed by [Cipher::seal], reporting one that fails authentication as
+    /// corrupt data.
+    pub(crate) fn open(&self, context: &[u8], sealed: &[u8]) -> std::io::Result<Vec<u8>> {
+        if sealed.len() < SALT_LEN + TAG_LEN {
+            return Err(tampered());
+        }
+        let (header, ciphertext) = sealed.split_at(SALT_LEN + TAG_LEN);
+        let salt = &header[..SALT_LEN];
+        let mut plaintext = vec![0; ciphertext.len()];
+        let mut aead = ChaCha20Poly1305::new(&hmac(&self.seal_key, &[salt]), &[0; 8], context);
+        if !aead.decrypt(ciphertext, &mut plaintext, &header[SALT_LEN..]) {
+            return Err(tampered());
+        }
+        Ok(plaintext)
+    }
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cipher { .. }")
+    }
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac = Hmac::new(Sha256::new(), key);
+    for part in parts {
+        mac.input(part);
+    }
+    let mut out = [0; 32];
+    out.copy_from_slice(mac.result().code());
+    out
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn tampered() -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        "Stored data fails authentication; it was tampered with or damaged!",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cipher, EncryptionKey};
+    use crate::record::Record;
+    use crate::{hash_key, KVStore, Operations, Options};
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::path::{Path, PathBuf};
+    use walkdir::WalkDir;
+
+    fn options(key: u8) -> Options {
+        Options {
+            encryption_key: Some(EncryptionKey::from_bytes([key; 32])),
+            ..Options::default()
+        }
+    }
+
+    fn record_files(path: &str) -> Vec<PathBuf> {
+        WalkDir::new(path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().extension().is_some_and(|x| x == "rec"))
+            .map(|e| e.into_path())
+            .collect()
+    }
+
+    #[test]
+    fn seal_is_deterministic_and_authenticated() {
+        let cipher = Cipher::new(&EncryptionKey::from_bytes([1; 32]));
+        let sealed = cipher.seal(b"key", b"\"secret\"");
+        assert_eq!(sealed, cipher.seal(b"key", b"\"secret\""));
+        assert_ne!(sealed, cipher.seal(b"key", b"\"secrets\""));
+        assert_eq!(cipher.open(b"key", &sealed).unwrap(), b"\"secret\"");
+        assert!(cipher.open(b"value", &sealed).is_err());
+
+        let other = Cipher::new(&EncryptionKey::from_bytes([2; 32]));
+        assert!(other.open(b"key", &sealed).is_err());
+    }
+
+    #[test]
+    fn nothing_is_stored_in_plaintext() {
+        let path = "./test-KV/encryption1";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::open(path, options(7)).unwrap();
+        kv_store
+            .insert(String::from("password"), String::from("hunter2"))
+            .unwrap();
+        assert_eq!(
+            kv_store
+                .lookup::<String, String>(String::from("password"))
+                .unwrap(),
+            "hunter2"
+        );
+
+        let files = record_files(path);
+        assert_eq!(files.len(), 1);
+        let plain_hash = hash_key(&serde_json::to_string("password").unwrap());
+        assert!(!files[0].to_string_lossy().contains(&plain_hash));
+        let bytes = String::from_utf8_lossy(&fs::read(&files[0]).unwrap()).into_owned();
+        assert!(!bytes.contains("password"));
+        assert!(!bytes.contains("hunter2"));
+
+        drop(kv_store);
+        let mut kv_store = KVStore::open(path, options(7)).unwrap();
+        assert_eq!(kv_store.size(), 1);
+        assert_eq!(
+            kv_store
+                .remove::<String, String>(String::from("password"))
+                .unwrap(),
+            "hunter2"
+        );
+    }
+
+    #[test]
+    fn wrong_or_missing_keys_are_refused() {
+        let path = "./test-KV/encryption2";
+        let _ = fs::remove_dir_all(path);
+        KVStore::open(path, options(1))
+            .unwrap()
+            .insert(1_i32, 1_i32)
+            .unwrap();
+
+        let wrong = KVStore::open(path, options(2)).unwrap_err();
+        assert_eq!(wrong.kind(), ErrorKind::PermissionDenied);
+        let missing = KVStore::new(path).unwrap_err();
+        assert_eq!(missing.kind(), ErrorKind::PermissionDenied);
+
+        let plain = "./test-KV/encryption3";
+        let _ = fs::remove_dir_all(plain);
+        KVStore::new(plain).unwrap();
+        let keyed = KVStore::open(plain, options(1)).unwrap_err();
+        assert_eq!(keyed.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn tampered_values_are_detected() {
+        let path = "./test-KV/encryption4";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::open(path, options(3)).unwrap();
+        kv_store.insert(String::from("balance"), 100_i32).unwrap();
+
+        let file = record_files(path).remove(0);
+        let mut record = Record::decode(&fs::read(&file).unwrap()).unwrap();
+        *record.value.last_mut().unwrap() ^= 1;
+        fs::write(Path::new(&file), record.encode()).unwrap();
+
+        let err = kv_store
+            .lookup::<String, i32>(String::from("balance"))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
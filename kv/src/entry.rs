@@ -0,0 +1,258 @@
+//! A [std::collections::HashMap]-style entry API.
+//!
+//! [Store::entry] only prepares the operation. Nothing is read until the entry is resolved with
+//! [Entry::or_insert], [Entry::or_insert_with] or [Entry::or_default], which then reads the
+//! mapping, modifies or inserts it and writes it back under the backend lock, in a single step
+//! that no other handle on the same storage can interleave with.
+
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{serialize_key, Backend, Result, Store};
+
+impl<B: Backend> Store<B> {
+    /// Returns the entry of `key`, for getting or inserting its value and modifying it in place.
+    ///
+    /// ```
+    /// use kv::{MemoryStore, Options, Store};
+    ///
+    /// let mut store: MemoryStore = Store::open("", Options::default()).unwrap();
+    /// for word in ["to", "be", "or", "not", "to", "be"].iter() {
+    ///     store.entry(word).unwrap().and_modify(|n| *n += 1).or_insert(1_u32).unwrap();
+    /// }
+    /// assert_eq!(store.entry("be").unwrap().or_default::<u32>().unwrap(), 2);
+    /// ```
+    pub fn entry<K: Serialize + ?Sized>(&mut self, key: &K) -> Result<Entry<'_, B>> {
+        let key = serialize_key(key)?;
+        Ok(Entry { store: self, key })
+    }
+}
+
+/// The entry of a key in a store, returned by [Store::entry].
+///
+/// Unlike the entry of a [std::collections::HashMap], it resolves to the value now stored rather
+/// than to a reference to it, as the value lives in the store.
+#[derive(Debug)]
+pub struct Entry<'a, B: Backend> {
+    store: &'a mut Store<B>,
+    /// The key, serialized.
+    key: Vec<u8>,
+}
+
+impl<'a, B: Backend> Entry<'a, B> {
+    /// Makes `modify` change the value if one is stored already, when the entry is resolved.
+    pub fn and_modify<V, F>(self, modify: F) -> ModifiedEntry<'a, B, V>
+    where
+        F: FnOnce(&mut V) + 'a,
+    {
+        ModifiedEntry {
+            entry: self,
+            modify: vec![Box::new(modify)],
+        }
+    }
+
+    /// Returns the value stored under the key, first storing `default` if there is none.
+    pub fn or_insert<V>(self, default: V) -> Result<V>
+    where
+        V: Serialize + DeserializeOwned,
+    {
+        self.resolve(Vec::new(), || default)
+    }
+
+    /// Returns the value stored under the key, first storing the result of `default` if there
+    /// is none. `default` is only called if there is none.
+    pub fn or_insert_with<V, F>(self, default: F) -> Result<V>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce() -> V,
+    {
+        self.resolve(Vec::new(), default)
+    }
+
+    /// Returns the value stored under the key, first storing `V::default()` if there is none.
+    pub fn or_default<V>(self) -> Result<V>
+    where
+        V: Serialize + DeserializeOwned + Default,
+    {
+        self.resolve(Vec::new(), V::default)
+    }
+
+    /// Reads the value stored under the key and applies `modify` to it, or stores the result of
+    /// `default` if there is none, all while holding the backend lock.
+    fn resolve<V, F>(self, modify: Vec<Modify<'a, V>>, default: F) -> Result<V>
+    where
+        V: Serialize + DeserializeOwned,
+        F: FnOnce() -> V,
+    {
+        let Entry { store, key } = self;
+        let (hash, stored_key) = store.locate(&key);
+        store.locked(|store| {
+            let old = store.get_record(&hash, &stored_key)?;
+            let value = match &old {
+                None => default(),
+                Some(old) => {
+                    let mut value = store.record_value(&hash, old)?;
+                    if modify.is_empty() {
+                        return Ok(value);
+                    }
+                    for modify in modify {
+                        modify(&mut value);
+                    }
+                    value
+                }
+            };
+            let bytes = store.codec.encode(&value)?;
+            store.write_value(&hash, stored_key, old, &bytes)?;
+            Ok(value)
+        })
+    }
+}
+
+/// A change [Entry::and_modify] makes to a stored value.
+type Modify<'a, V> = Box<dyn FnOnce(&mut V) + 'a>;
+
+/// The entry of a key with changes to make to a stored value, returned by [Entry::and_modify].
+pub struct ModifiedEntry<'a, B: Backend, V> {
+    entry: Entry<'a, B>,
+    /// The changes to make, in order.
+    modify: Vec<Modify<'a, V>>,
+}
+
+impl<'a, B: Backend, V> ModifiedEntry<'a, B, V>
+where
+    V: Serialize + DeserializeOwned,
+{
+    /// Makes `modify` change the value as well, after the changes added before.
+    pub fn and_modify<F: FnOnce(&mut V) + 'a>(mut self, modify: F) -> ModifiedEntry<'a, B, V> {
+        self.modify.push(Box::new(modify));
+        self
+    }
+
+    /// Returns the modified value stored under the key, or stores `default` unmodified if there
+    /// is none and returns it.
+    pub fn or_insert(self, default: V) -> Result<V> {
+        self.entry.resolve(self.modify, || default)
+    }
+
+    /// Returns the modified value stored under the key, or stores the result of `default`
+    /// unmodified if there is none and returns it. `default` is only called if there is none.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> Result<V> {
+        self.entry.resolve(self.modify, default)
+    }
+
+    /// Returns the modified value stored under the key, or stores `V::default()` unmodified if
+    /// there is none and returns it.
+    pub fn or_default(self) -> Result<V>
+    where
+        V: Default,
+    {
+        self.entry.resolve(self.modify, V::default)
+    }
+}
+
+impl<B: Backend + fmt::Debug, V> fmt::Debug for ModifiedEntry<'_, B, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ModifiedEntry")
+            .field("entry", &self.entry)
+            .field("modify", &self.modify.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Error, KVStore, KeyValue, MemoryStore, Operations, Options, Store};
+    use std::fs;
+    use std::thread;
+
+    #[test]
+    fn entries_insert_only_when_missing() {
+        let mut kv_store: MemoryStore = Store::open("", Options::default()).unwrap();
+        assert_eq!(kv_store.entry("a").unwrap().or_insert(1).unwrap(), 1);
+        assert_eq!(kv_store.entry("a").unwrap().or_insert(2).unwrap(), 1);
+        assert_eq!(
+            kv_store
+                .entry("a")
+                .unwrap()
+                .or_insert_with::<i32, _>(|| panic!("called for a stored value"))
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            kv_store
+                .entry("b")
+                .unwrap()
+                .or_default::<Vec<u8>>()
+                .unwrap(),
+            Vec::<u8>::new()
+        );
+        assert_eq!(
+            kv_store
+                .entry("c")
+                .unwrap()
+                .or_insert_with(|| String::from("c"))
+                .unwrap(),
+            "c"
+        );
+        assert_eq!(kv_store.len(), 3);
+        assert_eq!(kv_store.lookup_versioned("a").unwrap(), Some((1, 1)));
+    }
+
+    #[test]
+    fn modifications_only_apply_to_stored_values() {
+        let mut kv_store: MemoryStore = Store::open("", Options::default()).unwrap();
+        let modified = |kv_store: &mut MemoryStore| {
+            kv_store
+                .entry("list")
+                .unwrap()
+                .and_modify(|list: &mut Vec<u8>| list.push(1))
+                .and_modify(|list| list.push(2))
+                .or_insert(vec![0])
+                .unwrap()
+        };
+        assert_eq!(modified(&mut kv_store), vec![0]);
+        assert_eq!(modified(&mut kv_store), vec![0, 1, 2]);
+        assert_eq!(kv_store.get("list").unwrap(), Some(vec![0_u8, 1, 2]));
+
+        // A stored value of another type is reported and left in place.
+        kv_store.take::<_, Vec<u8>>("list").unwrap();
+        kv_store.put("list", &String::from("list")).unwrap();
+        let e = kv_store
+            .entry("list")
+            .unwrap()
+            .and_modify(|list: &mut Vec<u8>| list.clear())
+            .or_default()
+            .unwrap_err();
+        assert!(matches!(e, Error::Codec { .. }), "{}", e);
+        assert_eq!(kv_store.get("list").unwrap(), Some(String::from("list")));
+    }
+
+    #[test]
+    fn concurrent_entries_are_atomic() {
+        let path = "./test-KV/entry1";
+        let _ = fs::remove_dir_all(path);
+        let kv_store = KVStore::new(path).unwrap();
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                thread::spawn(move || {
+                    let mut kv_store = KVStore::new(path).unwrap();
+                    for _ in 0..25 {
+                        kv_store
+                            .entry("counter")
+                            .unwrap()
+                            .and_modify(|counter| *counter += 1)
+                            .or_insert(1_u32)
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(kv_store.get("counter").unwrap(), Some(100_u32));
+    }
+}
@@ -0,0 +1,311 @@
+//! The errors a store reports.
+//!
+//! Store operations return an [Error] that says what went wrong and, where they are known, which
+//! key (by the hash its record is located by) and which file it concerns. An [Error] converts
+//! into a [std::io::Error] of the matching [ErrorKind] for code written against
+//! [crate::Operations], and converts back without losing anything: a [Error] that was passed
+//! through a [std::io::Result], for example by a [crate::Backend], comes out as it went in.
+
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// The result of a store operation.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A failure of a store operation.
+///
+/// Every variant carries the hash of the key it concerns and the file it concerns, if they are
+/// known.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// No value is stored under the key.
+    NotFound {
+        hash: Option<String>,
+        path: Option<PathBuf>,
+    },
+    /// A value is stored under the key already.
+    AlreadyExists {
+        hash: Option<String>,
+        path: Option<PathBuf>,
+    },
+    /// The key changed after it was read, or does not hold what a conditional write expects, so
+    /// the change is not made.
+    Conflict {
+        hash: Option<String>,
+        path: Option<PathBuf>,
+    },
+    /// Stored data fails validation: a checksum does not match, a header is malformed or
+    /// encrypted data fails authentication.
+    Corrupt {
+        hash: Option<String>,
+        path: Option<PathBuf>,
+        reason: String,
+    },
+    /// A key or value cannot be serialized, or a stored value cannot be deserialized as the
+    /// requested type.
+    Codec {
+        hash: Option<String>,
+        path: Option<PathBuf>,
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    /// Reading or writing the store failed, or the store refused to open.
+    Io {
+        hash: Option<String>,
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+    /// The store cannot be created or opened at the given path.
+    InvalidPath {
+        hash: Option<String>,
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+}
+
+impl Error {
+    pub(crate) fn not_found() -> Error {
+        Error::NotFound {
+            hash: None,
+            path: None,
+        }
+    }
+
+    pub(crate) fn already_exists() -> Error {
+        Error::AlreadyExists {
+            hash: None,
+            path: None,
+        }
+    }
+
+    pub(crate) fn conflict() -> Error {
+        Error::Conflict {
+            hash: None,
+            path: None,
+        }
+    }
+
+    pub(crate) fn corrupt<R: Into<String>>(reason: R) -> Error {
+        Error::Corrupt {
+            hash: None,
+            path: None,
+            reason: reason.into(),
+        }
+    }
+
+    pub(crate) fn codec<E: Into<Box<dyn std::error::Error + Send + Sync>>>(source: E) -> Error {
+        Error::Codec {
+            hash: None,
+            path: None,
+            source: source.into(),
+        }
+    }
+
+    pub(crate) fn io(source: io::Error, path: &Path) -> Error {
+        Error::from(source).at_path(path)
+    }
+
+    pub(crate) fn invalid_path(source: io::Error, path: &Path) -> Error {
+        Error::InvalidPath {
+            hash: None,
+            path: Some(path.to_path_buf()),
+            source,
+        }
+    }
+
+    /// Returns the hash of the key the error concerns.
+    pub fn hash(&self) -> Option<&str> {
+        let (hash, _) = self.context();
+        hash.as_deref()
+    }
+
+    /// Returns the file the error concerns.
+    pub fn path(&self) -> Option<&Path> {
+        let (_, path) = self.context();
+        path.as_deref()
+    }
+
+    /// Returns the [ErrorKind] the error has when it is converted into a [std::io::Error].
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::NotFound { .. } => ErrorKind::NotFound,
+            Error::AlreadyExists { .. } => ErrorKind::AlreadyExists,
+            Error::Conflict { .. } => ErrorKind::ResourceBusy,
+            Error::Corrupt { .. } | Error::Codec { .. } => ErrorKind::InvalidData,
+            Error::Io { source, .. } | Error::InvalidPath { source, .. } => source.kind(),
+        }
+    }
+
+    /// Records the hash of the key the error concerns, unless one is recorded already.
+    pub(crate) fn for_key(mut self, key_hash: &str) -> Error {
+        let (hash, _) = self.context_mut();
+        hash.get_or_insert_with(|| key_hash.to_string());
+        self
+    }
+
+    /// Records the file the error concerns, unless one is recorded already.
+    pub(crate) fn at_path(mut self, file: &Path) -> Error {
+        let (_, path) = self.context_mut();
+        path.get_or_insert_with(|| file.to_path_buf());
+        self
+    }
+
+    fn context(&self) -> (&Option<String>, &Option<PathBuf>) {
+        match self {
+            Error::NotFound { hash, path }
+            | Error::AlreadyExists { hash, path }
+            | Error::Conflict { hash, path }
+            | Error::Corrupt { hash, path, .. }
+            | Error::Codec { hash, path, .. }
+            | Error::Io { hash, path, .. }
+            | Error::InvalidPath { hash, path, .. } => (hash, path),
+        }
+    }
+
+    fn context_mut(&mut self) -> (&mut Option<String>, &mut Option<PathBuf>) {
+        match self {
+            Error::NotFound { hash, path }
+            | Error::AlreadyExists { hash, path }
+            | Error::Conflict { hash, path }
+            | Error::Corrupt { hash, path, .. }
+            | Error::Codec { hash, path, .. }
+            | Error::Io { hash, path, .. }
+            | Error::InvalidPath { hash, path, .. } => (hash, path),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound { .. } => write!(f, "Value file does not exist!")?,
+            Error::AlreadyExists { .. } => write!(f, "Key file already exists!")?,
+            Error::Conflict { .. } => write!(f, "Value changed since it was read!")?,
+            Error::Corrupt { reason, .. } => write!(f, "{}", reason)?,
+            Error::Codec { source, .. } => write!(f, "Serialization failed: {}", source)?,
+            Error::Io { source, .. } => write!(f, "{}", source)?,
+            Error::InvalidPath { source, .. } => {
+                write!(f, "Store path cannot be used: {}", source)?
+            }
+        }
+        match (self.hash(), self.path()) {
+            (Some(hash), Some(path)) => write!(f, " (key {}, {})", hash, path.display()),
+            (Some(hash), None) => write!(f, " (key {})", hash),
+            (None, Some(path)) => write!(f, " ({})", path.display()),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Codec { source, .. } => Some(source.as_ref()),
+            Error::Io { source, .. } | Error::InvalidPath { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    /// Recovers an [Error] that was converted into a [std::io::Error], or wraps any other one as
+    /// [Error::Io]. [ErrorKind::InvalidData] errors are reported as [Error::Corrupt].
+    fn from(e: io::Error) -> Error {
+        if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+        if e.kind() == ErrorKind::InvalidData {
+            return Error::corrupt(e.to_string());
+        }
+        Error::Io {
+            hash: None,
+            path: None,
+            source: e,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        io::Error::new(e.kind(), e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+    use crate::{hash_key, KVStore, KeyValue, Operations};
+    use std::fs;
+    use std::io::{self, ErrorKind};
+    use std::path::Path;
+
+    #[test]
+    fn errors_survive_the_round_trip_through_io() {
+        let error = Error::corrupt("Record file is corrupt: bad checksum")
+            .for_key("abc")
+            .at_path(Path::new("ab/abc.rec"));
+        let io_error = io::Error::from(error);
+        assert_eq!(io_error.kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            io_error.to_string(),
+            "Record file is corrupt: bad checksum (key abc, ab/abc.rec)"
+        );
+
+        match Error::from(io_error) {
+            Error::Corrupt { hash, path, reason } => {
+                assert_eq!(hash.as_deref(), Some("abc"));
+                assert_eq!(path.as_deref(), Some(Path::new("ab/abc.rec")));
+                assert_eq!(reason, "Record file is corrupt: bad checksum");
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn failures_can_be_matched() {
+        let path = "./test-KV/error1";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        kv_store.put("Salt", &3_i32).unwrap();
+        let hash = hash_key(&serde_json::to_string("Salt").unwrap());
+
+        let err = kv_store.put_new("Salt", &4_i32).unwrap_err();
+        assert!(matches!(err, Error::AlreadyExists { .. }));
+        assert_eq!(err.hash(), Some(hash.as_str()));
+        let err = kv_store.get::<_, String>("Salt").unwrap_err();
+        assert!(matches!(err, Error::Codec { .. }));
+        assert_eq!(err.hash(), Some(hash.as_str()));
+
+        let file = Path::new(path)
+            .join(&hash[0..2])
+            .join(format!("{}.rec", hash));
+        let mut bytes = fs::read(&file).unwrap();
+        *bytes.last_mut().unwrap() = b'4';
+        fs::write(&file, bytes).unwrap();
+        let err = kv_store.get::<_, i32>("Salt").unwrap_err();
+        assert!(matches!(err, Error::Corrupt { .. }));
+        assert_eq!(err.hash(), Some(hash.as_str()));
+        assert_eq!(err.path(), Some(file.as_path()));
+
+        // The original interface still reports plain I/O errors.
+        let err = kv_store.lookup::<&str, i32>("Pepper").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let pepper = hash_key(&serde_json::to_string("Pepper").unwrap());
+        assert_eq!(Error::from(err).hash(), Some(pepper.as_str()));
+        let err = kv_store.remove::<&str, i32>("Pepper").unwrap_err();
+        assert_eq!(Error::from(err).hash(), Some(pepper.as_str()));
+    }
+
+    #[test]
+    fn unusable_paths_are_reported() {
+        let path = "./test-KV/error2";
+        let _ = fs::remove_dir_all(path);
+        fs::create_dir_all("./test-KV").unwrap();
+        fs::write(path, "a file, not a directory").unwrap();
+        let err = KVStore::open(path, Default::default()).unwrap_err();
+        assert!(matches!(err, Error::InvalidPath { .. }));
+        assert_eq!(err.path(), Some(Path::new(path)));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,420 @@
+//! A persisted count and index of the keys held by a [crate::DirBackend].
+//!
+//! The index lets a store open without walking its whole directory tree. It is written to the
+//! `INDEX` file on a clean shutdown (and on every [crate::Store::flush]), and every handle
+//! creates a `DIRTY.<id>` marker of its own before its first change after that, which it keeps
+//! locked for as long as it is open. Finding a marker on open means that some handle has changes
+//! the index does not know about yet, and the index is rebuilt from the record files instead.
+//! A marker nobody holds a lock on any more belongs to a handle that died, and is removed once
+//! an index that was rebuilt from the record files is saved.
+//!
+//! Handles on the same store save the index under the store lock, each merging the keys it
+//! changed into the index as the other handles last saved it, so no handle undoes the changes of
+//! another one.
+//!
+//! Opening reads the whole index and checks it against its checksum, and an index that does not
+//! match is rebuilt from the record files, like a missing one. The index file looks like this
+//! (all integers little-endian):
+//!
+//! ```text
+//! +-------+-------+-------+-----+-------+
+//! | count | crc32 | key 1 | ... | key n |
+//! |  u64  |  u32  |       |     |       |
+//! +-------+-------+-------+-----+-------+
+//! ```
+//!
+//! where every key is length-prefixed with a u32, and the checksum covers the count and the keys.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::durability::Syncer;
+
+pub(crate) const INDEX_FILE: &str = "INDEX";
+pub(crate) const DIRTY_FILE: &str = "DIRTY";
+
+const HEADER_LEN: usize = 12;
+
+/// Tells apart the dirty markers of the handles opened by this process.
+static NEXT_MARKER: AtomicUsize = AtomicUsize::new(0);
+
+/// The keys of a store and how many there are.
+#[derive(Debug)]
+pub(crate) struct KeyIndex {
+    root: PathBuf,
+    count: usize,
+    /// The keys, once they were needed.
+    keys: Option<BTreeSet<Vec<u8>>>,
+    /// The hash of every key this handle changed since the index was last saved, by key.
+    changed: BTreeMap<Vec<u8>, String>,
+    /// The dirty marker of this handle and the open file that holds the lock on it, while this
+    /// handle has changes the index does not know about.
+    marker: Option<(PathBuf, File)>,
+}
+
+impl KeyIndex {
+    /// Reads the index of a cleanly closed store at `root`.
+    ///
+    /// Returns `None` if the store was not closed cleanly, another handle has unsaved changes or
+    /// there is no index yet, or if it is damaged, in which case the index has to be rebuilt from
+    /// the record files.
+    pub(crate) fn load(root: &Path) -> std::io::Result<Option<KeyIndex>> {
+        if !dirty_markers(root)?.is_empty() {
+            return Ok(None);
+        }
+        let keys = match fs::read(root.join(INDEX_FILE)) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+            Ok(data) => match decode(&data) {
+                None => return Ok(None),
+                Some(keys) => keys,
+            },
+        };
+        Ok(Some(KeyIndex {
+            root: root.to_path_buf(),
+            count: keys.len(),
+            keys: Some(keys),
+            changed: BTreeMap::new(),
+            marker: None,
+        }))
+    }
+
+    /// Creates a placeholder for a store whose index has not been read yet. It holds no keys
+    /// until one is saved.
+    pub(crate) fn unloaded(root: &Path) -> KeyIndex {
+        KeyIndex {
+            root: root.to_path_buf(),
+            count: 0,
+            keys: None,
+            changed: BTreeMap::new(),
+            marker: None,
+        }
+    }
+
+    /// Reads the keys of the index as it was last saved, by any handle.
+    ///
+    /// Returns `None` if there is no index, if it is damaged, or if a handle died with changes it
+    /// does not know about, in which case the keys have to be read from the record files.
+    pub(crate) fn read_saved(root: &Path) -> std::io::Result<Option<BTreeSet<Vec<u8>>>> {
+        for marker in dirty_markers(root)? {
+            if is_stale(&marker)? {
+                return Ok(None);
+            }
+        }
+        match fs::read(root.join(INDEX_FILE)) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+            Ok(data) => Ok(decode(&data)),
+        }
+    }
+
+    pub(crate) fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Returns every key, reading them from the index file if they were not loaded yet, in
+    /// which case they include the changes other handles saved since this one was opened.
+    ///
+    /// Returns `None` if the index file turns out to be damaged.
+    pub(crate) fn keys(&self) -> std::io::Result<Option<Vec<Vec<u8>>>> {
+        match &self.keys {
+            Some(keys) => Ok(Some(keys.iter().cloned().collect())),
+            None => Ok(self.read_keys()?.map(|keys| keys.into_iter().collect())),
+        }
+    }
+
+    /// Loads the keys so that changes can be recorded.
+    ///
+    /// Returns `false` if the index file turns out to be damaged.
+    pub(crate) fn load_keys(&mut self) -> std::io::Result<bool> {
+        if self.keys.is_none() {
+            self.keys = self.read_keys()?;
+            if let Some(keys) = &self.keys {
+                self.count = keys.len();
+            }
+        }
+        Ok(self.keys.is_some())
+    }
+
+    /// Returns whether this handle changed the store since the index was last saved.
+    pub(crate) fn is_dirty(&self) -> bool {
+        self.marker.is_some()
+    }
+
+    /// Returns every key this handle changed since the index was last saved, with its hash.
+    pub(crate) fn changed(&self) -> &BTreeMap<Vec<u8>, String> {
+        &self.changed
+    }
+
+    /// Creates and locks the dirty marker of this handle, unless it has one already. Must be
+    /// called before the change is made.
+    pub(crate) fn mark_dirty(&mut self, syncer: &mut Syncer) -> std::io::Result<()> {
+        if self.marker.is_none() {
+            let id = NEXT_MARKER.fetch_add(1, Ordering::Relaxed);
+            let marker = self
+                .root
+                .join(format!("{}.{}.{}", DIRTY_FILE, process::id(), id));
+            syncer.write_atomic(&marker, &[])?;
+            let file = OpenOptions::new().write(true).open(&marker)?;
+            file.lock()?;
+            self.marker = Some((marker, file));
+        }
+        Ok(())
+    }
+
+    /// Records that `key`, located by `hash`, was added. The keys must have been loaded.
+    pub(crate) fn insert(&mut self, key: &[u8], hash: &str) {
+        if let Some(keys) = &mut self.keys {
+            if keys.insert(key.to_vec()) {
+                self.count += 1;
+            }
+            self.changed.insert(key.to_vec(), hash.to_string());
+        }
+    }
+
+    /// Records that `key`, located by `hash`, was removed. The keys must have been loaded.
+    pub(crate) fn remove(&mut self, key: &[u8], hash: &str) {
+        if let Some(keys) = &mut self.keys {
+            if keys.remove(key) {
+                self.count -= 1;
+            }
+            self.changed.insert(key.to_vec(), hash.to_string());
+        }
+    }
+
+    /// Writes `keys` as the index and removes the dirty marker of this handle, along with the
+    /// markers of handles that died. `keys` must take the changes of every such handle into
+    /// account, and the store lock must be held.
+    pub(crate) fn save(
+        &mut self,
+        syncer: &mut Syncer,
+        keys: BTreeSet<Vec<u8>>,
+    ) -> std::io::Result<()> {
+        let mut body = Vec::new();
+        for key in &keys {
+            body.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            body.extend_from_slice(key);
+        }
+        let count = (keys.len() as u64).to_le_bytes();
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&count);
+        hasher.update(&body);
+
+        let mut index = Vec::with_capacity(HEADER_LEN + body.len());
+        index.extend_from_slice(&count);
+        index.extend_from_slice(&hasher.finalize().to_le_bytes());
+        index.extend_from_slice(&body);
+        syncer.write_atomic(&self.root.join(INDEX_FILE), &index)?;
+
+        let own = self.marker.take().map(|(marker, _)| marker);
+        for marker in dirty_markers(&self.root)? {
+            if Some(&marker) == own.as_ref() || is_stale(&marker)? {
+                match fs::remove_file(&marker) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+        }
+        syncer.dir_changed(&self.root)?;
+        self.count = keys.len();
+        self.keys = Some(keys);
+        self.changed.clear();
+        Ok(())
+    }
+
+    fn read_keys(&self) -> std::io::Result<Option<BTreeSet<Vec<u8>>>> {
+        let data = match fs::read(self.root.join(INDEX_FILE)) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+            Ok(data) => data,
+        };
+        Ok(decode(&data))
+    }
+}
+
+/// Returns the dirty marker of every handle with unsaved changes, including handles that died.
+fn dirty_markers(root: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut markers = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let name = entry?.file_name();
+        if is_dirty_marker(&name.to_string_lossy()) {
+            markers.push(root.join(name));
+        }
+    }
+    Ok(markers)
+}
+
+/// Returns whether `marker` belongs to a handle that died, which no longer holds a lock on it.
+fn is_stale(marker: &Path) -> std::io::Result<bool> {
+    let file = match OpenOptions::new().write(true).open(marker) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+        Ok(file) => file,
+    };
+    match file.try_lock() {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Returns whether `name` is the name of a dirty marker.
+pub(crate) fn is_dirty_marker(name: &str) -> bool {
+    name == DIRTY_FILE || name.starts_with(&format!("{}.", DIRTY_FILE))
+}
+
+fn decode(data: &[u8]) -> Option<BTreeSet<Vec<u8>>> {
+    let count = u64::from_le_bytes(data.get(0..8)?.try_into().ok()?) as usize;
+    let crc = u32::from_le_bytes(data.get(8..12)?.try_into().ok()?);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&data[0..8]);
+    hasher.update(&data[HEADER_LEN..]);
+    if hasher.finalize() != crc {
+        return None;
+    }
+
+    let mut keys = BTreeSet::new();
+    let mut pos = HEADER_LEN;
+    for _ in 0..count {
+        let len = u32::from_le_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        pos += 4;
+        keys.insert(data.get(pos..pos + len)?.to_vec());
+        pos += len;
+    }
+    Some(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dirty_markers, DIRTY_FILE, INDEX_FILE};
+    use crate::{hash_key, KVStore, Operations};
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::path::Path;
+
+    fn record_file(path: &str, key: &str) -> std::path::PathBuf {
+        let hash = hash_key(&serde_json::to_string(key).unwrap());
+        Path::new(path)
+            .join(&hash[0..2])
+            .join(format!("{}.rec", hash))
+    }
+
+    #[test]
+    fn clean_open_trusts_the_index() {
+        let path = "./test-KV/index1";
+        let _ = fs::remove_dir_all(path);
+        {
+            let mut kv_store = KVStore::new(path).unwrap();
+            kv_store.insert("Red", 1_i32).unwrap();
+            kv_store.insert("Green", 2_i32).unwrap();
+            kv_store.insert("Blue", 3_i32).unwrap();
+            assert_eq!(dirty_markers(Path::new(path)).unwrap().len(), 1);
+        }
+        assert!(Path::new(path).join(INDEX_FILE).exists());
+        assert!(dirty_markers(Path::new(path)).unwrap().is_empty());
+
+        // Removing a record behind the store's back goes unnoticed by a clean open...
+        fs::remove_file(record_file(path, "Green")).unwrap();
+        assert_eq!(KVStore::new(path).unwrap().size(), 3);
+
+        // ...but is picked up by the rescan that follows an unclean shutdown.
+        fs::write(Path::new(path).join(DIRTY_FILE), "").unwrap();
+        let kv_store = KVStore::new(path).unwrap();
+        assert_eq!(kv_store.size(), 2);
+        assert!(dirty_markers(Path::new(path)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unclean_shutdown_rebuilds_the_index() {
+        let path = "./test-KV/index2";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        kv_store.insert("One", 1_i32).unwrap();
+        kv_store.flush().unwrap();
+        kv_store.insert("Two", 2_i32).unwrap();
+        kv_store.insert("Three", 3_i32).unwrap();
+        kv_store.remove::<&str, i32>("One").unwrap();
+        std::mem::forget(kv_store);
+
+        let mut kv_store = KVStore::new(path).unwrap();
+        assert_eq!(kv_store.size(), 2);
+        kv_store.insert("Four", 4_i32).unwrap();
+        drop(kv_store);
+        assert_eq!(KVStore::new(path).unwrap().size(), 3);
+    }
+
+    #[test]
+    fn damaged_index_is_rebuilt() {
+        let path = "./test-KV/index3";
+        let _ = fs::remove_dir_all(path);
+        {
+            let mut kv_store = KVStore::new(path).unwrap();
+            kv_store.insert("Up", 1_i32).unwrap();
+            kv_store.insert("Down", 2_i32).unwrap();
+        }
+        let index = Path::new(path).join(INDEX_FILE);
+        let mut bytes = fs::read(&index).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        fs::write(&index, bytes).unwrap();
+
+        let mut kv_store = KVStore::new(path).unwrap();
+        assert_eq!(kv_store.size(), 2);
+        kv_store.insert("Left", 3_i32).unwrap();
+        assert_eq!(kv_store.size(), 3);
+        drop(kv_store);
+        assert_eq!(KVStore::new(path).unwrap().size(), 3);
+
+        // A damaged count is caught as well, rather than trusted.
+        let mut bytes = fs::read(&index).unwrap();
+        bytes[0] = 7;
+        fs::write(&index, bytes).unwrap();
+        assert_eq!(KVStore::new(path).unwrap().size(), 3);
+    }
+
+    #[test]
+    fn handles_merge_their_changes() {
+        let path = "./test-KV/index4";
+        let _ = fs::remove_dir_all(path);
+        let mut first = KVStore::new(path).unwrap();
+        let mut second = KVStore::new(path).unwrap();
+        first.insert("x", 1_i32).unwrap();
+        second.insert("y", 2_i32).unwrap();
+        // Each handle counts the mappings the other one added as well.
+        assert_eq!((first.size(), second.size()), (2, 2));
+        first.remove::<&str, i32>("y").unwrap();
+        assert_eq!((first.size(), second.size()), (1, 1));
+        first.insert("y", 2_i32).unwrap();
+        drop(first);
+        drop(second);
+
+        let kv_store = KVStore::new(path).unwrap();
+        assert_eq!(kv_store.size(), 2);
+        let keys: BTreeSet<String> = kv_store.keys().unwrap().map(Result::unwrap).collect();
+        assert_eq!(keys, ["x", "y"].iter().map(|k| k.to_string()).collect());
+    }
+
+    #[test]
+    fn open_keeps_the_marker_of_a_live_handle() {
+        let path = "./test-KV/index5";
+        let _ = fs::remove_dir_all(path);
+        let mut first = KVStore::new(path).unwrap();
+        first.insert("x", 1_i32).unwrap();
+
+        // Opening rebuilds the index, as the first handle has not saved it yet...
+        let second = KVStore::new(path).unwrap();
+        assert_eq!(second.size(), 1);
+        drop(second);
+        assert_eq!(dirty_markers(Path::new(path)).unwrap().len(), 1);
+
+        // ...and the marker it leaves in place still tells the next open that the first handle
+        // died with unsaved changes.
+        first.insert("z", 2_i32).unwrap();
+        std::mem::forget(first);
+        assert_eq!(KVStore::new(path).unwrap().size(), 2);
+    }
+}
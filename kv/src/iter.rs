@@ -0,0 +1,290 @@
+//! Iteration over the mappings of a store.
+//!
+//! [Store::iter], [Store::keys] and [Store::values] take the list of stored keys from the
+//! backend when they are called and then read one mapping per step, so walking a large store
+//! never holds more than one value in memory. Every step yields a [Result]: a mapping whose key
+//! or value does not deserialize as the requested type is reported as an [Error::Codec] error
+//! for its key, and the walk carries on with the next mapping. Callers that only want the
+//! mappings that do decode can skip the errors with `filter_map(Result::ok)`.
+//!
+//! A mapping that is removed after the walk has started, for example by another process sharing
+//! the directory, is skipped rather than reported.
+
+use std::marker::PhantomData;
+use std::vec;
+
+use serde::de::{DeserializeOwned, IgnoredAny};
+
+use crate::{Backend, Error, Result, Snapshot, Store};
+
+impl<B: Backend> Store<B> {
+    /// Returns an iterator over every key-value mapping, deserialized as `K` and `V`.
+    ///
+    /// Mappings are yielded in no particular order.
+    pub fn iter<K, V>(&self) -> Result<Iter<'_, B, K, V>>
+    where
+        K: DeserializeOwned,
+        V: DeserializeOwned,
+    {
+        Ok(Iter::new(Source::Store(self), self.backend.scan()?))
+    }
+
+    /// Returns an iterator over every key, deserialized as `K`, without reading any values.
+    pub fn keys<K: DeserializeOwned>(&self) -> Result<Keys<'_, B, K>> {
+        Ok(Keys::new(Source::Store(self), self.backend.scan()?))
+    }
+
+    /// Returns an iterator over every value, deserialized as `V`.
+    pub fn values<V: DeserializeOwned>(&self) -> Result<Values<'_, B, V>> {
+        Ok(Values::new(self.iter()?))
+    }
+}
+
+/// Where an iterator reads the mappings it walks: the store as it is, or a snapshot of it.
+#[derive(Debug)]
+pub(crate) enum Source<'a, B: Backend> {
+    Store(&'a Store<B>),
+    Snapshot(&'a Snapshot<'a, B>),
+}
+
+impl<'a, B: Backend> Source<'a, B> {
+    fn store(&self) -> &'a Store<B> {
+        match self {
+            Source::Store(store) => store,
+            Source::Snapshot(snapshot) => snapshot.store(),
+        }
+    }
+
+    /// Returns whether a mapping is stored under `stored_key`, which `hash` locates.
+    fn contains(&self, hash: &str, stored_key: &[u8]) -> Result<bool> {
+        match self {
+            Source::Store(store) => store.exists(hash, stored_key),
+            Source::Snapshot(snapshot) => Ok(snapshot.contains(stored_key)),
+        }
+    }
+
+    /// Reads the mapping stored under `stored_key` and deserializes it, or returns `None` if it
+    /// has been removed since the key was listed.
+    fn mapping<K, V>(&self, stored_key: &[u8]) -> Result<Option<(K, V)>>
+    where
+        K: DeserializeOwned,
+        V: DeserializeOwned,
+    {
+        let store = self.store();
+        let (serialized_key, hash) = store.unlock_key(stored_key)?;
+        let record = match self {
+            Source::Store(store) => store.get_record(&hash, stored_key)?,
+            Source::Snapshot(snapshot) => snapshot.record(&hash, stored_key)?,
+        };
+        let record = match record {
+            None => return Ok(None),
+            Some(record) => record,
+        };
+        let key = decode_key(&serialized_key, &hash)?;
+        Ok(Some((key, store.record_value(&hash, &record)?)))
+    }
+}
+
+/// Deserializes a key from the JSON it is stored as.
+fn decode_key<K: DeserializeOwned>(serialized_key: &[u8], hash: &str) -> Result<K> {
+    serde_json::from_slice(serialized_key).map_err(|e| Error::codec(e).for_key(hash))
+}
+
+/// An iterator over the key-value mappings of a store, returned by [Store::iter] and
+/// [Snapshot::iter].
+#[derive(Debug)]
+pub struct Iter<'a, B: Backend, K, V> {
+    source: Source<'a, B>,
+    /// The stored keys that are still to be read.
+    stored_keys: vec::IntoIter<Vec<u8>>,
+    marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<'a, B: Backend, K, V> Iter<'a, B, K, V> {
+    pub(crate) fn new(source: Source<'a, B>, stored_keys: Vec<Vec<u8>>) -> Iter<'a, B, K, V> {
+        Iter {
+            source,
+            stored_keys: stored_keys.into_iter(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<B: Backend, K: DeserializeOwned, V: DeserializeOwned> Iterator for Iter<'_, B, K, V> {
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Result<(K, V)>> {
+        for stored_key in self.stored_keys.by_ref() {
+            match self.source.mapping(&stored_key) {
+                Ok(None) => continue,
+                Ok(Some(entry)) => return Some(Ok(entry)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.stored_keys.len()))
+    }
+}
+
+/// An iterator over the keys of a store, returned by [Store::keys] and [Snapshot::keys].
+#[derive(Debug)]
+pub struct Keys<'a, B: Backend, K> {
+    source: Source<'a, B>,
+    /// The stored keys that are still to be read.
+    stored_keys: vec::IntoIter<Vec<u8>>,
+    marker: PhantomData<fn() -> K>,
+}
+
+impl<'a, B: Backend, K> Keys<'a, B, K> {
+    pub(crate) fn new(source: Source<'a, B>, stored_keys: Vec<Vec<u8>>) -> Keys<'a, B, K> {
+        Keys {
+            source,
+            stored_keys: stored_keys.into_iter(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<B: Backend, K: DeserializeOwned> Iterator for Keys<'_, B, K> {
+    type Item = Result<K>;
+
+    fn next(&mut self) -> Option<Result<K>> {
+        for stored_key in self.stored_keys.by_ref() {
+            let (serialized_key, hash) = match self.source.store().unlock_key(&stored_key) {
+                Ok(unlocked) => unlocked,
+                Err(e) => return Some(Err(e)),
+            };
+            match self.source.contains(&hash, &stored_key) {
+                Ok(false) => continue,
+                Ok(true) => return Some(decode_key(&serialized_key, &hash)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.stored_keys.len()))
+    }
+}
+
+/// An iterator over the values of a store, returned by [Store::values] and [Snapshot::values].
+#[derive(Debug)]
+pub struct Values<'a, B: Backend, V> {
+    /// The mappings, with keys that are not deserialized at all.
+    entries: Iter<'a, B, IgnoredAny, V>,
+}
+
+impl<'a, B: Backend, V> Values<'a, B, V> {
+    pub(crate) fn new(entries: Iter<'a, B, IgnoredAny, V>) -> Values<'a, B, V> {
+        Values { entries }
+    }
+}
+
+impl<B: Backend, V: DeserializeOwned> Iterator for Values<'_, B, V> {
+    type Item = Result<V>;
+
+    fn next(&mut self) -> Option<Result<V>> {
+        self.entries
+            .next()
+            .map(|entry| entry.map(|(_, value)| value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{EncryptionKey, KVStore, KeyValue, MemoryStore, Operations, Options, Store};
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    #[test]
+    fn every_mapping_is_visited() {
+        let path = "./test-KV/iter1";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        for i in 0..20_u32 {
+            kv_store.put(&format!("key{}", i), &(i * i)).unwrap();
+        }
+
+        let kv_store = KVStore::new(path).unwrap();
+        let entries: BTreeMap<String, u32> = kv_store
+            .iter::<String, u32>()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let expected: BTreeMap<String, u32> =
+            (0..20_u32).map(|i| (format!("key{}", i), i * i)).collect();
+        assert_eq!(entries, expected);
+
+        let mut keys: Vec<String> = kv_store.keys().unwrap().map(Result::unwrap).collect();
+        keys.sort();
+        assert_eq!(keys, expected.keys().cloned().collect::<Vec<_>>());
+        let sum: u32 = kv_store.values::<u32>().unwrap().map(Result::unwrap).sum();
+        assert_eq!(sum, expected.values().sum::<u32>());
+    }
+
+    #[test]
+    fn undecodable_mappings_are_reported_and_skipped() {
+        let mut kv_store: MemoryStore = Store::open("", Options::default()).unwrap();
+        kv_store.put("one", &1_i32).unwrap();
+        kv_store.put("two", &2_i32).unwrap();
+        kv_store.put("name", &String::from("kv")).unwrap();
+        kv_store.put(&3_i32, &3_i32).unwrap();
+
+        let results: Vec<_> = kv_store.iter::<String, i32>().unwrap().collect();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results.iter().filter(|r| r.is_err()).count(), 2);
+        let mut entries: Vec<(String, i32)> =
+            kv_store.iter().unwrap().filter_map(Result::ok).collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![(String::from("one"), 1), (String::from("two"), 2)]
+        );
+    }
+
+    #[test]
+    fn encrypted_keys_are_decrypted() {
+        let options = Options {
+            encryption_key: Some(EncryptionKey::from_bytes([5; 32])),
+            ..Options::default()
+        };
+        let mut kv_store: MemoryStore = Store::open("", options).unwrap();
+        kv_store.put("secret", &vec![1_u8, 2, 3]).unwrap();
+        let entries: Vec<(String, Vec<u8>)> =
+            kv_store.iter().unwrap().map(Result::unwrap).collect();
+        assert_eq!(entries, vec![(String::from("secret"), vec![1, 2, 3])]);
+    }
+
+    #[test]
+    fn mappings_removed_during_the_walk_are_skipped() {
+        let path = "./test-KV/iter2";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        for i in 0..10_i32 {
+            kv_store.put(&i, &i).unwrap();
+        }
+
+        let mut keys = kv_store.keys::<i32>().unwrap();
+        let mut entries = kv_store.iter::<i32, i32>().unwrap();
+        // Another handle on the same directory removes mappings while both walks are under way.
+        let mut other = KVStore::new(path).unwrap();
+        for i in 0..5_i32 {
+            other.take::<_, i32>(&i).unwrap();
+        }
+
+        let mut seen: Vec<i32> = keys.by_ref().map(Result::unwrap).collect();
+        seen.sort_unstable();
+        assert_eq!(seen, (5..10).collect::<Vec<_>>());
+        let mut seen: Vec<i32> = entries.by_ref().map(|e| e.unwrap().1).collect();
+        seen.sort_unstable();
+        assert_eq!(seen, (5..10).collect::<Vec<_>>());
+    }
+}
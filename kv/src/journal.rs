@@ -0,0 +1,166 @@
+//! A write-ahead journal that makes multi-file changes all-or-nothing.
+//!
+//! Before [crate::DirBackend] touches any record file it writes the whole change to the
+//! journal. Once every file has been written the journal is deleted again. If the
+//! process dies in between, the journal is still there the next time the store is opened and the
+//! change is replayed from it. A journal that was itself only partly written fails its checksum
+//! and is discarded, which rolls the change back since no data file had been touched yet.
+//!
+//! The journal file looks like this (all integers little-endian):
+//!
+//! ```text
+//! +-------+-------+----------+-----+----------+
+//! | crc32 | count | intent 1 | ... | intent n |
+//! |  u32  |  u32  |          |     |          |
+//! +-------+-------+----------+-----+----------+
+//! ```
+//!
+//! where every intent is a kind byte followed by the length-prefixed hash, the u32 slot in the
+//! hash's collision chain and, for a put, the length-prefixed encoded [crate::Record].
+
+use std::convert::TryInto;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::durability::Syncer;
+
+pub(crate) const JOURNAL_FILE: &str = "journal";
+const KIND_PUT: u8 = 1;
+const KIND_DELETE: u8 = 2;
+
+/// A single change that is about to be made to the store.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Intent {
+    /// Write the encoded `record` into position `slot` of the chain for `hash`.
+    Put {
+        hash: String,
+        slot: u32,
+        record: Vec<u8>,
+    },
+    /// Delete whatever is stored in position `slot` of the chain for `hash`.
+    Delete { hash: String, slot: u32 },
+}
+
+/// The journal file under a store root.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    pub(crate) fn new(root: &Path) -> Journal {
+        Journal {
+            path: root.join(JOURNAL_FILE),
+        }
+    }
+
+    /// Records `intents` before any of them is applied.
+    pub(crate) fn begin(&self, syncer: &mut Syncer, intents: &[Intent]) -> std::io::Result<()> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&(intents.len() as u32).to_le_bytes());
+        for intent in intents {
+            match intent {
+                Intent::Put { hash, slot, record } => {
+                    body.push(KIND_PUT);
+                    put_bytes(&mut body, hash.as_bytes());
+                    body.extend_from_slice(&slot.to_le_bytes());
+                    put_bytes(&mut body, record);
+                }
+                Intent::Delete { hash, slot } => {
+                    body.push(KIND_DELETE);
+                    put_bytes(&mut body, hash.as_bytes());
+                    body.extend_from_slice(&slot.to_le_bytes());
+                }
+            }
+        }
+
+        let mut journal = crc32fast::hash(&body).to_le_bytes().to_vec();
+        journal.extend_from_slice(&body);
+        syncer.write_atomic(&self.path, &journal)
+    }
+
+    /// Marks the recorded intents as fully applied.
+    pub(crate) fn commit(&self, syncer: &mut Syncer) -> std::io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+            Ok(()) => syncer.dir_changed(self.path.parent().unwrap_or(&self.path)),
+        }
+    }
+
+    /// Returns the intents of a change that was interrupted before it was committed.
+    ///
+    /// A journal that was torn while being written is discarded and reported as empty.
+    pub(crate) fn pending(&self) -> std::io::Result<Vec<Intent>> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        match decode(&data) {
+            Some(intents) => Ok(intents),
+            None => {
+                fs::remove_file(&self.path)?;
+                Ok(Vec::new())
+            }
+        }
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn decode(data: &[u8]) -> Option<Vec<Intent>> {
+    let crc = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?);
+    let body = &data[4..];
+    if crc32fast::hash(body) != crc {
+        return None;
+    }
+
+    let mut reader = Reader { data: body, pos: 0 };
+    let count = reader.u32()?;
+    let mut intents = Vec::new();
+    for _ in 0..count {
+        let kind = reader.u8()?;
+        let hash = String::from_utf8(reader.bytes()?).ok()?;
+        let slot = reader.u32()?;
+        match kind {
+            KIND_PUT => {
+                let record = reader.bytes()?;
+                intents.push(Intent::Put { hash, slot, record });
+            }
+            KIND_DELETE => intents.push(Intent::Delete { hash, slot }),
+            _ => return None,
+        }
+    }
+    Some(intents)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Option<u8> {
+        let byte = *self.data.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.data.get(self.pos..self.pos + 4)?;
+        self.pos += 4;
+        Some(u32::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    fn bytes(&mut self) -> Option<Vec<u8>> {
+        let len = self.u32()? as usize;
+        let bytes = self.data.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes.to_vec())
+    }
+}
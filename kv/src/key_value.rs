@@ -0,0 +1,194 @@
+//! The typed interface of a store.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{serialize_key, Backend, Result, Store};
+
+/// A trait that defines the typed operations of a key-value store.
+///
+/// Keys are serialized as JSON and values with the store's [crate::Codec]. Unlike
+/// [crate::Operations], keys are taken by reference (so `&str` keys work without allocating),
+/// nothing requires [Default], and a missing key is reported as `None`. Errors are reserved for
+/// real failures, such as a damaged record or a failing disk, and are reported as a [crate::Error].
+///
+/// ```
+/// use kv::{KeyValue, MemoryStore, Options, Store};
+///
+/// let mut store: MemoryStore = Store::open("", Options::default()).unwrap();
+/// assert_eq!(store.put("answer", &41).unwrap(), None);
+/// assert_eq!(store.put("answer", &42).unwrap(), Some(41));
+/// assert_eq!(store.get::<_, i32>("answer").unwrap(), Some(42));
+/// assert_eq!(store.take::<_, i32>("question").unwrap(), None);
+/// ```
+pub trait KeyValue {
+    /// Returns the number of key-value mappings currently stored.
+    fn len(&self) -> usize;
+
+    /// Returns whether no key-value mappings are stored.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the value stored under `key`, or `None` if there is none.
+    fn get<K, V>(&self, key: &K) -> Result<Option<V>>
+    where
+        K: Serialize + ?Sized,
+        V: DeserializeOwned;
+
+    /// Returns whether a value is stored under `key`.
+    fn contains_key<K>(&self, key: &K) -> Result<bool>
+    where
+        K: Serialize + ?Sized;
+
+    /// Stores `value` under `key`, replacing any value stored there already, and returns the
+    /// value it replaced.
+    ///
+    /// The replaced value is read back as a `V`; if it does not deserialize as one, nothing is
+    /// written and an [crate::Error::Codec] error is returned.
+    fn put<K, V>(&mut self, key: &K, value: &V) -> Result<Option<V>>
+    where
+        K: Serialize + ?Sized,
+        V: Serialize + DeserializeOwned;
+
+    /// Stores `value` under `key` if no value is stored there yet.
+    ///
+    /// If there is one, this returns an [crate::Error::AlreadyExists] error and leaves it
+    /// untouched.
+    fn put_new<K, V>(&mut self, key: &K, value: &V) -> Result<()>
+    where
+        K: Serialize + ?Sized,
+        V: Serialize + ?Sized;
+
+    /// Removes the value stored under `key` and returns it, or returns `None` if there is none.
+    fn take<K, V>(&mut self, key: &K) -> Result<Option<V>>
+    where
+        K: Serialize + ?Sized,
+        V: DeserializeOwned;
+}
+
+impl<B: Backend> KeyValue for Store<B> {
+    fn len(&self) -> usize {
+        self.current_size()
+    }
+
+    fn get<K, V>(&self, key: &K) -> Result<Option<V>>
+    where
+        K: Serialize + ?Sized,
+        V: DeserializeOwned,
+    {
+        self.get_typed(&serialize_key(key)?)
+    }
+
+    fn contains_key<K>(&self, key: &K) -> Result<bool>
+    where
+        K: Serialize + ?Sized,
+    {
+        let (hash, stored_key) = self.locate(&serialize_key(key)?);
+        self.exists(&hash, &stored_key)
+    }
+
+    fn put<K, V>(&mut self, key: &K, value: &V) -> Result<Option<V>>
+    where
+        K: Serialize + ?Sized,
+        V: Serialize + DeserializeOwned,
+    {
+        let key = serialize_key(key)?;
+        let value = self.codec.encode(value)?;
+        self.locked(|store| {
+            let old = store.get_typed(&key)?;
+            store.put_bytes(&key, &value)?;
+            Ok(old)
+        })
+    }
+
+    fn put_new<K, V>(&mut self, key: &K, value: &V) -> Result<()>
+    where
+        K: Serialize + ?Sized,
+        V: Serialize + ?Sized,
+    {
+        let key = serialize_key(key)?;
+        let value = self.codec.encode(value)?;
+        self.insert_bytes(&key, &value)
+    }
+
+    fn take<K, V>(&mut self, key: &K) -> Result<Option<V>>
+    where
+        K: Serialize + ?Sized,
+        V: DeserializeOwned,
+    {
+        let key = serialize_key(key)?;
+        self.locked(|store| {
+            let old = store.get_typed(&key)?;
+            if old.is_some() {
+                store.delete_bytes(&key)?;
+            }
+            Ok(old)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyValue;
+    use crate::{KVStore, MemoryStore, Operations, Options, Store};
+    use serde::{Deserialize, Serialize};
+    use std::fs;
+    use std::io::ErrorKind;
+
+    /// A type without a sensible default, which [Operations] cannot store.
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Port(std::num::NonZeroU16);
+
+    fn port(number: u16) -> Port {
+        Port(std::num::NonZeroU16::new(number).unwrap())
+    }
+
+    #[test]
+    fn missing_keys_are_none() {
+        let mut kv_store: MemoryStore = Store::open("", Options::default()).unwrap();
+        assert_eq!(kv_store.get::<_, Port>("http").unwrap(), None);
+        assert!(!kv_store.contains_key("http").unwrap());
+        assert_eq!(kv_store.take::<_, Port>("http").unwrap(), None);
+
+        kv_store.put_new("http", &port(80)).unwrap();
+        assert!(kv_store.contains_key("http").unwrap());
+        assert_eq!(kv_store.get("http").unwrap(), Some(port(80)));
+        assert_eq!(kv_store.take("http").unwrap(), Some(port(80)));
+        assert!(kv_store.is_empty());
+    }
+
+    #[test]
+    fn put_overwrites_and_insert_does_not() {
+        let path = "./test-KV/kv1";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        assert_eq!(kv_store.put("https", &port(443)).unwrap(), None);
+        assert_eq!(kv_store.put("https", &port(8443)).unwrap(), Some(port(443)));
+        assert_eq!(kv_store.len(), 1);
+
+        let err = kv_store.put_new("https", &port(1)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(kv_store.get("https").unwrap(), Some(port(8443)));
+
+        // A previous value of another type is reported, and left in place.
+        let err = kv_store.put("https", &String::from("port")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(kv_store.get("https").unwrap(), Some(port(8443)));
+    }
+
+    #[test]
+    fn operations_report_error_kinds() {
+        let mut kv_store: MemoryStore = Store::open("", Options::default()).unwrap();
+        Operations::insert(&mut kv_store, String::from("key"), 1_i32).unwrap();
+        let err = Operations::insert(&mut kv_store, String::from("key"), 2_i32).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        let err = kv_store
+            .lookup::<String, i32>(String::from("other"))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err =
+            Operations::remove::<String, i32>(&mut kv_store, String::from("other")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+}
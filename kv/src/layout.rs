@@ -0,0 +1,78 @@
+//! How [crate::DirBackend] spreads record files over nested shard directories.
+
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// The number of hex characters in a key hash.
+const HASH_LEN: usize = 64;
+
+/// Which shard directories the record file of a key hash is placed in.
+///
+/// The first `depth` groups of `prefix_len` hash characters each name one level of
+/// sub-directory, so with `ShardLayout { prefix_len: 2, depth: 2 }` the hash `abcdef…` is stored
+/// under `ab/cd/`. A depth of 0 keeps every record file directly in the store root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardLayout {
+    /// How many hash characters name each level of sub-directory.
+    pub prefix_len: usize,
+    /// How many levels of sub-directories there are.
+    pub depth: usize,
+}
+
+impl ShardLayout {
+    /// The layout of stores created before the layout was configurable: a single level of
+    /// sub-directories named after the first ten characters of the hash.
+    pub const LEGACY: ShardLayout = ShardLayout {
+        prefix_len: 10,
+        depth: 1,
+    };
+
+    /// Returns an [ErrorKind::InvalidInput] error if the layout cannot be used.
+    pub(crate) fn validate(&self) -> std::io::Result<()> {
+        if self.depth > 0 && self.prefix_len == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Shard prefix length must not be 0!",
+            ));
+        }
+        if self.prefix_len * self.depth > HASH_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Shard layout uses more characters than a hash has!",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the directory under `root` that holds the record files of `hash`, or an
+    /// [crate::Error::InvalidPath] error if `hash` is too short to name every level.
+    pub(crate) fn shard_dir(&self, root: &Path, hash: &str) -> std::io::Result<PathBuf> {
+        let mut dir = root.to_path_buf();
+        for level in 0..self.depth {
+            match hash.get(level * self.prefix_len..(level + 1) * self.prefix_len) {
+                Some(prefix) => dir.push(prefix),
+                None => {
+                    let e = Error::new(
+                        ErrorKind::InvalidInput,
+                        "Key hash is too short for the shard layout!",
+                    );
+                    return Err(crate::Error::invalid_path(e, &root.join(hash))
+                        .for_key(hash)
+                        .into());
+                }
+            }
+        }
+        Ok(dir)
+    }
+}
+
+impl Default for ShardLayout {
+    /// 256 directories in a single level.
+    fn default() -> ShardLayout {
+        ShardLayout {
+            prefix_len: 2,
+            depth: 1,
+        }
+    }
+}
@@ -1,25 +1,444 @@
 extern crate crypto;
 
 use std::fmt::Debug;
-use std::fs;
+use std::sync::{Mutex, PoisonError};
 
-use std::io::{Error, ErrorKind};
-use std::path::Path;
+use std::io::ErrorKind;
 use self::crypto::digest::Digest;
 use self::crypto::sha2::Sha256;
-use walkdir::WalkDir;
+use encryption::{Cipher, FLAG_ENCRYPTED};
+use range::KeyOrder;
+use stream::{StoredValue, DEFAULT_CHUNK_SIZE};
+
+pub mod backend;
+mod batch;
+pub mod bitcask;
+mod codec;
+mod compression;
+mod dedup;
+mod durability;
+mod encryption;
+mod entry;
+mod error;
+mod index;
+mod iter;
+mod journal;
+mod key_value;
+mod layout;
+mod manifest;
+pub mod memory;
+mod options;
+mod ordered;
+mod range;
+mod record;
+mod snapshot;
+mod stream;
+mod transaction;
+mod version;
+
+pub use backend::{Backend, BatchOp, DirBackend};
+pub use batch::WriteBatch;
+pub use bitcask::{Bitcask, BitcaskStore};
+pub use codec::Codec;
+pub use compression::Compression;
+pub use durability::Durability;
+pub use encryption::EncryptionKey;
+pub use entry::{Entry, ModifiedEntry};
+pub use error::{Error, Result};
+pub use iter::{Iter, Keys, Values};
+pub use key_value::KeyValue;
+pub use layout::ShardLayout;
+pub use memory::{MemoryBackend, MemoryStore};
+pub use options::Options;
+pub use range::{Cursor, Page, ScanOptions};
+pub use record::Record;
+pub use snapshot::Snapshot;
+pub use stream::{ValueReader, ValueWriter};
+pub use transaction::Transaction;
 
 
 #[derive(Debug)]
-/// A struct that represents a key-value store.
-pub struct KVStore {
+/// A struct that represents a key-value store on top of any [Backend].
+///
+/// The store serializes and hashes keys and values, and hands the resulting bytes to the backend.
+pub struct Store<B: Backend> {
     /// The number of key-value mappings currently stored.
-    size: usize,
-    /// The location of the file system where key-value mappings are stored.
-    path: String,
+    size: Mutex<Size>,
+    /// The codec new values are serialized with.
+    codec: Codec,
+    /// How new values are compressed.
+    compression: Compression,
+    /// The size in bytes from which a serialized value is compressed.
+    compression_threshold: usize,
+    /// What keys and values are encrypted with, if the store is encrypted.
+    cipher: Option<Cipher>,
+    /// The size in bytes above which a value is split into chunks.
+    chunk_size: usize,
+    /// Whether values are stored once per distinct content and shared between keys.
+    deduplicate: bool,
+    /// The keys in order, built the first time a range or prefix scan needs them.
+    key_order: Mutex<KeyOrder>,
+    /// Where key-value mappings are stored.
+    backend: B,
+}
+
+/// The number of mappings a store holds, as counted at a [Backend::generation].
+#[derive(Debug)]
+struct Size {
+    generation: u64,
+    count: usize,
+}
+
+impl Size {
+    fn count<B: Backend>(backend: &B) -> std::io::Result<Size> {
+        // The generation is read first, so a change that comes in while the mappings are counted
+        // is counted again on the next call rather than missed.
+        let generation = backend.generation()?;
+        Ok(Size {
+            generation,
+            count: backend.count()?,
+        })
+    }
+}
+
+/// A key-value store that keeps its mappings in a sharded directory tree.
+pub type KVStore = Store<DirBackend>;
+
+impl<B: Backend> Store<B> {
+    /// Opens (or creates) a store at `path` with the given options.
+    pub fn open(path: &str, options: Options) -> Result<Store<B>> {
+        let backend = B::open(path, &options)?;
+        Ok(Store {
+            size: Mutex::new(Size::count(&backend)?),
+            codec: backend.codec().unwrap_or(options.codec),
+            compression: options.compression,
+            compression_threshold: options.compression_threshold,
+            cipher: options.encryption_key.as_ref().map(Cipher::new),
+            chunk_size: options.chunk_size.max(1),
+            deduplicate: options.deduplicate,
+            key_order: Mutex::default(),
+            backend,
+        })
+    }
+
+    /// Creates a store on top of an already opened backend, counting the mappings it holds.
+    pub fn with_backend(backend: B) -> Result<Store<B>> {
+        Ok(Store {
+            size: Mutex::new(Size::count(&backend)?),
+            codec: backend.codec().unwrap_or_default(),
+            compression: Compression::None,
+            compression_threshold: 0,
+            cipher: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            deduplicate: false,
+            key_order: Mutex::default(),
+            backend,
+        })
+    }
+
+    /// Returns the codec new values are serialized with.
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Returns the backend the store writes to.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Returns the backend the store writes to, for backend-specific maintenance.
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Forces every change made so far to stable storage, regardless of the durability level.
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.backend.flush()?)
+    }
+
+    /// Inserts a new mapping from raw key bytes to raw value bytes, without any serialization.
+    ///
+    /// If there is a mapping stored already under the same key, this returns an
+    /// [Error::AlreadyExists] error.
+    pub fn insert_bytes(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let (hash, stored_key) = self.locate(key);
+        self.locked(|store| {
+            if store.exists(&hash, &stored_key)? {
+                return Err(Error::already_exists().for_key(&hash));
+            }
+            store.write_value(&hash, stored_key, None, value).map(|_| ())
+        })
+    }
+
+    /// Stores raw value bytes under raw key bytes, replacing any value stored there already.
+    pub fn put_bytes(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let (hash, stored_key) = self.locate(key);
+        self.locked(|store| {
+            let old = store.get_record(&hash, &stored_key)?;
+            store.write_value(&hash, stored_key, old, value).map(|_| ())
+        })
+    }
+
+    /// Returns the raw value bytes stored under raw key bytes, or `None` if there are none.
+    ///
+    /// For a mapping inserted through [Operations::insert], the key bytes are the key serialized
+    /// as JSON and the value bytes are the value serialized with the store's codec.
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.read_bytes(key)?.map(|(_, bytes, _)| bytes))
+    }
+
+    /// Removes the mapping stored under raw key bytes.
+    ///
+    /// Returns whether there was a mapping to remove.
+    pub fn delete_bytes(&mut self, key: &[u8]) -> Result<bool> {
+        let (hash, stored_key) = self.locate(key);
+        self.locked(|store| match store.get_record(&hash, &stored_key)? {
+            None => Ok(false),
+            Some(record) => store.delete_record(&hash, record).map(|()| true),
+        })
+    }
+
+    /// Returns the number of key-value mappings stored, counting them again once another handle
+    /// changed the store. If they cannot be counted, the last count is returned.
+    fn current_size(&self) -> usize {
+        let mut size = self.size.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Ok(generation) = self.backend.generation() {
+            if generation != size.generation {
+                if let Ok(count) = self.backend.count() {
+                    *size = Size { generation, count };
+                }
+            }
+        }
+        size.count
+    }
+
+    /// Returns the number of mappings this handle counted, to adjust for a change it made.
+    fn size_mut(&mut self) -> &mut usize {
+        &mut self
+            .size
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .count
+    }
+
+    /// Runs `change` while holding the backend lock, so that no other handle on the same
+    /// storage changes what `change` reads before it is done writing.
+    fn locked<T, F>(&mut self, change: F) -> Result<T>
+    where
+        F: FnOnce(&mut Store<B>) -> Result<T>,
+    {
+        self.backend.lock()?;
+        let result = change(self);
+        let unlocked = self.backend.unlock();
+        let value = result?;
+        unlocked?;
+        Ok(value)
+    }
+
+    /// Stores `value` under `stored_key` in place of `old`, the record stored there now if there
+    /// is one, and returns the version of the new record.
+    fn write_value(
+        &mut self,
+        hash: &str,
+        stored_key: Vec<u8>,
+        old: Option<Record>,
+        value: &[u8],
+    ) -> Result<u64> {
+        let version = self.next_version(old.as_ref())?;
+        let old = match old {
+            None => None,
+            Some(old) => Some(self.stored_value(hash, &old).map_err(|e| e.for_key(hash))?),
+        };
+        self.put_value(hash, stored_key.clone(), value, version)?;
+        match old {
+            Some(old) => self.delete_chunks(&old)?,
+            None => {
+                self.track_key(&stored_key, true);
+                *self.size_mut() += 1;
+            }
+        }
+        Ok(version)
+    }
+
+    /// Removes `record`, which is stored under `hash`, together with the chunks of its value.
+    fn delete_record(&mut self, hash: &str, record: Record) -> Result<()> {
+        let stored = self
+            .stored_value(hash, &record)
+            .map_err(|e| e.for_key(hash))?;
+        self.backend.retire_version(record.version)?;
+        self.backend
+            .delete(hash, &record.key)
+            .map_err(|e| Error::from(e).for_key(hash))?;
+        self.track_key(&record.key, false);
+        self.delete_chunks(&stored).map_err(|e| e.for_key(hash))?;
+        *self.size_mut() -= 1;
+        Ok(())
+    }
+
+    /// Returns the hash that locates a serialized key together with the bytes it is stored as,
+    /// which are encrypted if the store is.
+    fn locate(&self, serialized_key: &[u8]) -> (String, Vec<u8>) {
+        match &self.cipher {
+            None => (hash_key(serialized_key), serialized_key.to_vec()),
+            Some(cipher) => (
+                cipher.hash(serialized_key),
+                cipher.seal(KEY_CONTEXT, serialized_key),
+            ),
+        }
+    }
+
+    /// Undoes [Store::locate]: returns the serialized key a stored key holds together with the
+    /// hash that locates it.
+    fn unlock_key(&self, stored_key: &[u8]) -> Result<(Vec<u8>, String)> {
+        match &self.cipher {
+            None => Ok((stored_key.to_vec(), hash_key(stored_key))),
+            Some(cipher) => {
+                let serialized_key = cipher.open(KEY_CONTEXT, stored_key)?;
+                let hash = cipher.hash(&serialized_key);
+                Ok((serialized_key, hash))
+            }
+        }
+    }
+
+    /// Returns whether a record is stored under `hash` for `stored_key`.
+    fn exists(&self, hash: &str, stored_key: &[u8]) -> Result<bool> {
+        self.backend
+            .exists(hash, stored_key)
+            .map_err(|e| Error::from(e).for_key(hash))
+    }
+
+    /// Reads the record stored under `hash` for `stored_key`.
+    fn get_record(&self, hash: &str, stored_key: &[u8]) -> Result<Option<Record>> {
+        self.backend
+            .get(hash, stored_key)
+            .map_err(|e| Error::from(e).for_key(hash))
+    }
+
+    /// Reads the record stored under `hash` for `stored_key` and opens its value. Returns the
+    /// id of the codec that wrote the value along with it.
+    fn get_stored(
+        &self,
+        hash: &str,
+        stored_key: &[u8],
+    ) -> Result<Option<(u8, StoredValue)>> {
+        let record = match self.get_record(hash, stored_key)? {
+            None => return Ok(None),
+            Some(record) => record,
+        };
+        let stored = self
+            .stored_value(hash, &record)
+            .map_err(|e| e.for_key(hash))?;
+        Ok(Some((record.codec, stored)))
+    }
+
+    /// Returns the value bytes stored under a serialized key together with the id of the codec
+    /// that wrote them and the hash that locates the key.
+    fn read_bytes(&self, key: &[u8]) -> Result<Option<(u8, Vec<u8>, String)>> {
+        let (hash, stored_key) = self.locate(key);
+        match self.get_stored(&hash, &stored_key)? {
+            None => Ok(None),
+            Some((codec, stored)) => match self.value_bytes(&stored) {
+                Err(e) => Err(e.for_key(&hash)),
+                Ok(bytes) => Ok(Some((codec, bytes, hash))),
+            },
+        }
+    }
+
+    /// Reads the value of `record`, which is stored under `hash`, and deserializes it with the
+    /// codec that wrote it.
+    fn record_value<V: serde::de::DeserializeOwned>(
+        &self,
+        hash: &str,
+        record: &Record,
+    ) -> Result<V> {
+        let bytes = self
+            .stored_value(hash, record)
+            .and_then(|stored| self.value_bytes(&stored))
+            .map_err(|e| e.for_key(hash))?;
+        decode_value(record.codec, &bytes, hash)
+    }
+
+    /// Looks up the value stored under a serialized key and deserializes it with the codec that
+    /// wrote it, reporting a value that does not parse as an [Error::Codec] error.
+    fn get_typed<V: serde::de::DeserializeOwned>(
+        &self,
+        key: &[u8],
+    ) -> Result<Option<V>> {
+        match self.read_bytes(key)? {
+            None => Ok(None),
+            Some((codec, bytes, hash)) => decode_value(codec, &bytes, &hash).map(Some),
+        }
+    }
+
+    /// Compresses and, if the store is encrypted, seals stored value bytes. `context` is what
+    /// the bytes belong to and has to be passed again to [Store::open_value].
+    ///
+    /// Returns the bytes to store and the record flags that mark them.
+    fn seal_value(&self, context: &[u8], bytes: Vec<u8>) -> Result<(Vec<u8>, u8)> {
+        let (mut bytes, mut flags) = self
+            .compression
+            .compress(bytes, self.compression_threshold)?;
+        if let Some(cipher) = &self.cipher {
+            bytes = cipher.seal(context, &bytes);
+            flags |= FLAG_ENCRYPTED;
+        }
+        Ok((bytes, flags))
+    }
+
+    /// Undoes [Store::seal_value].
+    fn open_value(&self, context: &[u8], bytes: Vec<u8>, flags: u8) -> Result<Vec<u8>> {
+        let mut bytes = bytes;
+        if flags & FLAG_ENCRYPTED != 0 {
+            bytes = match &self.cipher {
+                None => {
+                    return Err(std::io::Error::new(
+                        ErrorKind::PermissionDenied,
+                        "Stored value is encrypted!",
+                    )
+                    .into())
+                }
+                Some(cipher) => cipher.open(context, &bytes)?,
+            };
+        }
+        Ok(compression::decompress(bytes, flags)?)
+    }
+}
+
+/// The context stored keys are sealed in. Values are sealed in the context of their key's hash,
+/// so a value cannot be moved to another key without failing authentication.
+const KEY_CONTEXT: &[u8] = b"key";
+
+/// Returns the hex SHA-256 digest used to locate a serialized key.
+fn hash_key<T: AsRef<[u8]> + ?Sized>(serialized_key: &T) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(serialized_key.as_ref());
+    hasher.result_str()
+}
+
+/// Deserializes value bytes with the codec whose id the record stores, reporting the key `hash`
+/// with any failure.
+fn decode_value<V: serde::de::DeserializeOwned>(codec: u8, bytes: &[u8], hash: &str) -> Result<V> {
+    match Codec::from_id(codec) {
+        None => Err(Error::corrupt(format!(
+            "Stored value uses the unknown codec {}!",
+            codec
+        ))
+        .for_key(hash)),
+        Some(codec) => codec.decode(bytes).map_err(|e| e.for_key(hash)),
+    }
+}
+
+/// Serializes a typed key into the key bytes it is stored under.
+fn serialize_key<K: serde::Serialize + ?Sized>(key: &K) -> Result<Vec<u8>> {
+    serde_json::to_vec(key).map_err(Error::codec)
 }
 
 /// A trait that defines the operations that need to be supported.
+///
+/// This is the original interface of the store, kept so that existing code keeps working. It is
+/// implemented on top of [KeyValue], which new code should use instead: it does not require keys
+/// and values to implement [Default], reports missing keys as `None` rather than as an error, and
+/// can overwrite values.
 pub trait Operations {
     /// A function that initializes a KVStore instance.
     ///
@@ -39,7 +458,7 @@ pub trait Operations {
         Self: Sized;
 
     /// A function that returns the number of key-value mappings currently stored.
-    fn size(self: &Self) -> usize;
+    fn size(&self) -> usize;
 
     /// A function that inserts a new key-value mapping.
     ///
@@ -47,7 +466,7 @@ pub trait Operations {
     /// `Ok(())` if storing is successfully done.
     ///
     /// If there **is** a key-value mapping stored already with the same key, it should return an
-    /// [std::io::Error].
+    /// [std::io::Error] of kind [ErrorKind::AlreadyExists].
     ///
     /// Make sure you read and understand the assignment document regarding how to store key-value
     /// mappings using files as well as how to structure sub-directories.
@@ -56,7 +475,7 @@ pub trait Operations {
     ///
     /// Refer to [https://docs.serde.rs/serde/](https://docs.serde.rs/serde/)
     /// and [https://serde.rs](https://serde.rs) for serde.
-    fn insert<K, V>(self: &mut Self, key: K, value: V) -> std::io::Result<()>
+    fn insert<K, V>(&mut self, key: K, value: V) -> std::io::Result<()>
     where
         K: serde::Serialize + Default + Debug,
         V: serde::Serialize + Default + Debug;
@@ -67,13 +486,13 @@ pub trait Operations {
     /// the value.
     ///
     /// If there is **no** key-value mapping stored already with the same key, it should return
-    /// an [std::io::Error].
+    /// an [std::io::Error] of kind [ErrorKind::NotFound].
     ///
     /// Make sure you understand what the trait bounds mean for K and V.
     ///
     /// Refer to [https://docs.serde.rs/serde/](https://docs.serde.rs/serde/)
     /// and [https://serde.rs](https://serde.rs) for serde.
-    fn lookup<K, V>(self: &Self, key: K) -> std::io::Result<V>
+    fn lookup<K, V>(&self, key: K) -> std::io::Result<V>
     where
         K: serde::Serialize + Default + Debug,
         V: serde::de::DeserializeOwned + Default + Debug;
@@ -84,7 +503,7 @@ pub trait Operations {
     /// the value and delete the key-value mapping from the file system.
     ///
     /// If there is **no** key-value mapping stored already with the same key, it should
-    /// return an [std::io::Error].
+    /// return an [std::io::Error] of kind [ErrorKind::NotFound].
     ///
     /// If a sub-directory does not contain any key-value files, this should delete the
     /// sub-directory as well.
@@ -93,191 +512,56 @@ pub trait Operations {
     ///
     /// Refer to [https://docs.serde.rs/serde/](https://docs.serde.rs/serde/)
     /// and [https://serde.rs](https://serde.rs) for serde.
-    fn remove<K, V>(self: &mut Self, key: K) -> std::io::Result<V>
+    fn remove<K, V>(&mut self, key: K) -> std::io::Result<V>
     where
         K: serde::Serialize + Default + Debug,
         V: serde::de::DeserializeOwned + Default + Debug;
 }
 
-impl Operations for KVStore {
-
-    fn new(path: &str) -> std::io::Result<KVStore> {
+impl<B: Backend> Operations for Store<B> {
 
-        let curr_path = &path;
-        let sub_dir_path = Path::new(&path);
-        match fs::create_dir_all(&sub_dir_path) {
-            Err(_e) => return Err(Error::new(ErrorKind::Other, "Something went wrong creating the sub directory!")),
-            _ => (),
-        };
-
-        let mut count = 0;
-        for entry in WalkDir::new(curr_path)
-            .follow_links(true)
-            .into_iter()
-            .filter_map(|e| e.ok())
-        {
-                let file_name = entry.file_name().to_string_lossy();
-                if file_name.ends_with(".key"){
-                    println!("{}", file_name);
-                    count = count + 1;
-                }
-        }
-        println!("Key file count: {}", count);
-        Ok(KVStore {
-            size: count,
-            path: String::from(path),
-        })
+    fn new(path: &str) -> std::io::Result<Store<B>> {
+        Ok(Store::open(path, Options::default())?)
     }
 
     fn size(&self) -> usize {
-        self.size
+        self.current_size()
     }
 
-    fn insert<K, V>(self: &mut Self, key: K, value: V) -> std::io::Result<()>
+    fn insert<K, V>(&mut self, key: K, value: V) -> std::io::Result<()>
         where
             K: serde::Serialize + Default + Debug,
             V: serde::Serialize + Default + Debug
     {
-        let mut hasher = Sha256::new();
-
-        let serialized_value = serde_json::to_string(&value).unwrap();
-        let serialized_key = serde_json::to_string(&key).unwrap();
-
-        hasher.input_str(&serialized_key);
-        let sha_key = hasher.result_str();
-
-        let sha_key_slice = &sha_key[0..10];
-
-        let curr_path = &self.path;
-        let key_format = ".key";
-        let value_format = ".value";
-
-        let sub_dir_str = format!("{}/{}", curr_path, sha_key_slice);
-        let sub_dir_path = Path::new(&sub_dir_str);
-
-        match fs::create_dir_all(&sub_dir_path) {
-            Err(_e) => return Err(Error::new(ErrorKind::Other, "Something went wrong creating the sub directory!")),
-            _ => (),
-        };
-
-        let key_file = format!("{}/{}{}", sub_dir_str, sha_key, key_format);
-        let value_file = format!("{}/{}{}", sub_dir_str, sha_key, value_format);
-        let key_file_path = Path::new(&key_file);
-        let value_file_path = Path::new(&value_file);
-
-        if key_file_path.is_file() {
-            return Err(Error::new(ErrorKind::Other, "Key file already exists!"));
-        }
-        if value_file_path.is_file() {
-            return Err(Error::new(ErrorKind::Other, "Value file already exists!"));
-        }
-
-        match fs::write(key_file_path, serialized_key) {
-            Err(_e) => return Err(Error::new(ErrorKind::Other, "Something went wrong writing to the key file!")),
-            _ => (),
-        };
-        match fs::write(value_file_path, serialized_value) {
-            Err(_e) => return Err(Error::new(ErrorKind::Other, "Something went wronng writing to the value file!")),
-            _ => (),
-        };
-        self.size = self.size + 1;
-
-        Ok(())
+        Ok(KeyValue::put_new(self, &key, &value)?)
     }
 
-    fn lookup<K, V>(self: &Self, key: K) -> std::io::Result<V>
+    fn lookup<K, V>(&self, key: K) -> std::io::Result<V>
     where
         K: serde::Serialize + Default + Debug,
         V: serde::de::DeserializeOwned + Default + Debug
     {
-
-        let mut hasher = Sha256::new();
-
-        let serialized_key = serde_json::to_string(&key).unwrap();
-
-        hasher.input_str(&serialized_key);
-        let sha_key = hasher.result_str();
-        let sha_key_slice = &sha_key[0..10];
-
-        let curr_path = &self.path;
-        let value_format = ".value";
-
-        let sub_dir = format!("{}/{}", curr_path, sha_key_slice);
-
-        let value_file = format!("{}/{}{}", sub_dir, sha_key, value_format);
-        let value_file_path = Path::new(&value_file);
-
-        if !(value_file_path.is_file()) {
-            return Err(Error::new(ErrorKind::Other, "Value file does not exist!"));
+        match KeyValue::get(self, &key)? {
+            None => {
+                let (hash, _) = self.locate(&serialize_key(&key)?);
+                Err(Error::not_found().for_key(&hash).into())
+            }
+            Some(value) => Ok(value),
         }
-
-        let value;
-        match fs::read_to_string(value_file_path) {
-            Err(_e) => return Err(Error::new(ErrorKind::Other, "Something went wrong creating the sub directory!")),
-            _ => value = fs::read_to_string(value_file_path)?,
-        };
-
-        Ok(serde_json::from_str(&value).unwrap())
     }
 
-    fn remove<K, V>(self: &mut Self, key: K) -> std::io::Result<V>
+    fn remove<K, V>(&mut self, key: K) -> std::io::Result<V>
     where
         K: serde::Serialize + Default + Debug,
         V: serde::de::DeserializeOwned + Default + Debug
     {
-        let mut hasher = Sha256::new();
-        let serialized_key = serde_json::to_string(&key).unwrap();
-        hasher.input_str(&serialized_key);
-
-        let sha_key = hasher.result_str();
-        let sha_key_slice = &sha_key[0..10];
-
-        let curr_path = &self.path;
-        let key_format = ".key";
-        let value_format = ".value";
-
-        let sub_dir = format!("{}/{}", curr_path, sha_key_slice);
-        let key_file = format!("{}/{}{}", sub_dir, sha_key, key_format);
-        let value_file = format!("{}/{}{}", sub_dir, sha_key, value_format);
-
-        let sub_dir_path = Path::new(&sub_dir);
-        let key_file_path = Path::new(&key_file);
-        let value_file_path = Path::new(&value_file);
-
-        if !(sub_dir_path.is_dir()) {
-            return Err(Error::new(ErrorKind::Other, "Sub directory does not exist!"));
-        }
-        if !(key_file_path.is_file()) {
-            return Err(Error::new(ErrorKind::Other, "Key file does not exist!"));
-        }
-        if !(value_file_path.is_file()) {
-            return Err(Error::new(ErrorKind::Other, "Value file does not exist!"));
-        }
-
-        let value;
-        match fs::read_to_string(value_file_path) {
-            Err(_e) => return Err(Error::new(ErrorKind::Other, "Something went wrong creating the sub directory!")),
-            _ => value = fs::read_to_string(value_file_path)?,
-        };
-
-        match fs::remove_file(key_file_path) {
-            Err(_e) => return Err(Error::new(ErrorKind::Other, "Something went wrong removing the key file!")),
-            _ => (),
-        };
-        match fs::remove_file(value_file_path) {
-            Err(_e) => return Err(Error::new(ErrorKind::Other, "Something went wrong removing the value file!")),
-            _ => (),
-        };
-        self.size = self.size - 1;
-
-        if sub_dir_path.read_dir()?.next().is_none() {
-            match fs::remove_dir_all(&sub_dir_path) {
-                Err(_e) => return Err(Error::new(ErrorKind::Other, "Something went wrong removing the sub directory!")),
-                _ => (),
-            };
+        match KeyValue::take(self, &key)? {
+            None => {
+                let (hash, _) = self.locate(&serialize_key(&key)?);
+                Err(Error::not_found().for_key(&hash).into())
+            }
+            Some(value) => Ok(value),
         }
-
-        Ok(serde_json::from_str(&value).unwrap())
     }
 }
 
@@ -289,8 +573,44 @@ use super::KVStore;
 use super::Operations;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fs;
 
+    /// Opens a store at `path` after clearing anything a previous test run left there.
+    fn fresh_store(path: &str) -> std::io::Result<KVStore> {
+        let _ = fs::remove_dir_all(path);
+        KVStore::new(path)
+    }
 
+    #[test]
+    fn raw_bytes_round_trip() {
+        let mut kv_store = fresh_store("./test-KV/bytes1").unwrap();
+        let key = [0_u8, 159, 146, 150];
+        kv_store.put_bytes(&key, b"first").unwrap();
+        kv_store.put_bytes(&key, &[255, 0, 255]).unwrap();
+        assert_eq!(kv_store.size(), 1);
+        assert_eq!(kv_store.get_bytes(&key).unwrap().unwrap(), [255, 0, 255]);
+        assert!(kv_store.insert_bytes(&key, b"again").is_err());
+
+        let mut kv_store = KVStore::new("./test-KV/bytes1").unwrap();
+        assert!(kv_store.delete_bytes(&key).unwrap());
+        assert!(!kv_store.delete_bytes(&key).unwrap());
+        assert_eq!(kv_store.get_bytes(&key).unwrap(), None);
+        assert_eq!(kv_store.size(), 0);
+    }
+
+    #[test]
+    fn typed_api_is_layered_on_bytes() {
+        let mut kv_store = fresh_store("./test-KV/bytes2").unwrap();
+        kv_store.insert(String::from("answer"), 42_i32).unwrap();
+        assert_eq!(kv_store.get_bytes(b"\"answer\"").unwrap().unwrap(), b"42");
+
+        kv_store.insert_bytes(b"\"list\"", b"[1,2,3]").unwrap();
+        let list = kv_store.lookup::<String, Vec<i32>>(String::from("list")).unwrap();
+        assert_eq!(list, [1, 2, 3]);
+        assert_eq!(kv_store.size(), 2);
+        assert!(kv_store.delete_bytes(b"\"answer\"").unwrap());
+        assert!(kv_store.lookup::<String, i32>(String::from("answer")).is_err());
+    }
 
     #[test]
     fn insert_with_empty_path() {
@@ -306,15 +626,15 @@ use std::collections::HashMap;
     fn check_insert_size_update() {
         
         let owned_string = "./test-KV/data1".to_string();
-        let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+        let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
             
             process::exit(1);
         });
-        kv_store.insert(String::from("Pizza"), 21 as i32).unwrap();
+        kv_store.insert(String::from("Pizza"), 21_i32).unwrap();
         assert_eq!( kv_store.size(), 1);
-        kv_store.insert(String::from("Coffee"), 33 as i32).unwrap();
+        kv_store.insert(String::from("Coffee"), 33_i32).unwrap();
         assert_eq!( kv_store.size(), 2);
-        kv_store.insert(String::from("Candy"), 54 as i32).unwrap();
+        kv_store.insert(String::from("Candy"), 54_i32).unwrap();
         assert_eq!( kv_store.size(), 3);
     }
 
@@ -322,13 +642,13 @@ use std::collections::HashMap;
     fn inserting_already_existing_key() {
         
         let owned_string = "./test-KV/data2".to_string();
-        let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+        let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
             
             process::exit(1);
         });
 
-        kv_store.insert(String::from("Hello World"), 2 as i32).unwrap();
-        match  kv_store.insert(String::from("Hello World"), 2 as i32) {
+        kv_store.insert(String::from("Hello World"), 2_i32).unwrap();
+        match  kv_store.insert(String::from("Hello World"), 2_i32) {
             Ok(_) => assert_eq!(false, false),
             Err(_e) => assert_eq!(true, true),
         }
@@ -338,23 +658,23 @@ use std::collections::HashMap;
     fn lookup_existing_key() {
         
         let owned_string = "./test-KV/data3".to_string();
-        let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+        let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
             
             process::exit(1);
         });
-        kv_store.insert(String::from("Future"), 90 as i32).unwrap();
-        assert_eq!( kv_store.lookup::<String, i32>(String::from("Future")).unwrap(), 90 as i32);
+        kv_store.insert(String::from("Future"), 90_i32).unwrap();
+        assert_eq!( kv_store.lookup::<String, i32>(String::from("Future")).unwrap(), 90_i32);
     }
 
     #[test]
     fn lookup_non_existing_key() {
         
         let owned_string = "./test-KV/data4".to_string();
-        let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+        let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
             
             process::exit(1);
         });
-        kv_store.insert(String::from("Past"), 20 as i32).unwrap();
+        kv_store.insert(String::from("Past"), 20_i32).unwrap();
         match  kv_store.lookup::<String, i32>(String::from("Present")) {
             Ok(_) => assert_eq!(false, false),
             Err(_e) => assert_eq!(true, true),
@@ -365,11 +685,11 @@ use std::collections::HashMap;
     fn lookup_empty_key() {
         
         let owned_string = "./test-KV/data5".to_string();
-        let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+        let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
             
             process::exit(1);
         });
-        kv_store.insert(String::from("Past"), 20 as i32).unwrap();
+        kv_store.insert(String::from("Past"), 20_i32).unwrap();
         match  kv_store.lookup::<String, i32>(String::from("")) {
             Ok(_) => assert_eq!(false, false),
             Err(_e) => assert_eq!(true, true),
@@ -380,25 +700,25 @@ use std::collections::HashMap;
     fn remove_existing_key() {
         
         let owned_string = "./test-KV/data6".to_string();
-        let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+        let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
             
             process::exit(1);
         });
-        kv_store.insert(String::from("Cold"), 86 as i32).unwrap();
-        kv_store.insert(String::from("Water"), 90 as i32).unwrap();
-        assert_eq!( kv_store.remove::<String, i32>(String::from("Water")).unwrap(), 90 as i32);
+        kv_store.insert(String::from("Cold"), 86_i32).unwrap();
+        kv_store.insert(String::from("Water"), 90_i32).unwrap();
+        assert_eq!( kv_store.remove::<String, i32>(String::from("Water")).unwrap(), 90_i32);
     }
 
     #[test]
     fn remove_non_existing_key() {
         
         let owned_string = "./test-KV/data7".to_string();
-        let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+        let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
             
             process::exit(1);
         });
-        kv_store.insert(String::from("Infinite"), 20 as i32).unwrap();
-        kv_store.insert(String::from("Time"), 20 as i32).unwrap();
+        kv_store.insert(String::from("Infinite"), 20_i32).unwrap();
+        kv_store.insert(String::from("Time"), 20_i32).unwrap();
         match  kv_store.remove::<String, i32>(String::from("This key does not exist")) {
             Ok(_) => assert_eq!(false, false),
             Err(_e) => assert_eq!(true, true),
@@ -409,15 +729,15 @@ use std::collections::HashMap;
     fn check_size_when_remove_existing_key() {
         
         let owned_string = "./test-KV/data8".to_string();
-        let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+        let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
             
             process::exit(1);
         });
-        kv_store.insert(String::from("Sine"), 360 as i32).unwrap();
+        kv_store.insert(String::from("Sine"), 360_i32).unwrap();
         assert_eq!( kv_store.size(), 1);
-        kv_store.insert(String::from("Wave"), 180 as i32).unwrap();
+        kv_store.insert(String::from("Wave"), 180_i32).unwrap();
         assert_eq!( kv_store.size(), 2);
-        assert_eq!( kv_store.remove::<String, i32>(String::from("Sine")).unwrap(), 360 as i32);
+        assert_eq!( kv_store.remove::<String, i32>(String::from("Sine")).unwrap(), 360_i32);
         assert_eq!( kv_store.size(), 1);
     }
 
@@ -425,26 +745,26 @@ use std::collections::HashMap;
     fn remove_existing_key2() {
         
         let owned_string = "./test-KV/data9".to_string();
-        let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+        let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
             
             process::exit(1);
         });
-        kv_store.insert(String::from("Earth"), 77 as i32).unwrap();
-        assert_eq!( kv_store.remove::<String, i32>(String::from("Earth")).unwrap(), 77 as i32);
+        kv_store.insert(String::from("Earth"), 77_i32).unwrap();
+        assert_eq!( kv_store.remove::<String, i32>(String::from("Earth")).unwrap(), 77_i32);
     }
 
     #[test]
     fn insert_i32() {
         
         let owned_string = "./test-KV/data".to_string();
-        let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+        let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
             
             process::exit(1);
         });
 
-        kv_store.insert(String::from("key"), 2 as i32).unwrap();
+        kv_store.insert(String::from("key"), 2_i32).unwrap();
 
-        assert_eq!( kv_store.lookup::<String, i32>(String::from("key")).unwrap(), 2 as i32);
+        assert_eq!( kv_store.lookup::<String, i32>(String::from("key")).unwrap(), 2_i32);
 
     }
 
@@ -469,7 +789,7 @@ use std::collections::HashMap;
 
 
             let owned_string = "./test-KV/test1".to_string();
-            let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+            let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
                 
                 process::exit(1);
             });
@@ -523,15 +843,15 @@ use std::collections::HashMap;
     fn insert_bool_true() {
         
         let owned_string = "./test-KV/test2".to_string();
-        let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+        let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
             
             process::exit(1);
         });
 
         let t_bool:bool = true;
-        kv_store.insert(String::from("key"), t_bool as bool).unwrap();
+        kv_store.insert(String::from("key"), t_bool).unwrap();
 
-        assert_eq!( kv_store.lookup::<String, bool>(String::from("key")).unwrap(), true);
+        assert!( kv_store.lookup::<String, bool>(String::from("key")).unwrap());
 
     }
 
@@ -539,15 +859,15 @@ use std::collections::HashMap;
     fn insert_bool_false() {
         
         let owned_string = "./test-KV/test3".to_string();
-        let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+        let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
             
             process::exit(1);
         });
 
         let f_bool:bool = false;
-        kv_store.insert(String::from("key"), f_bool as bool).unwrap();
+        kv_store.insert(String::from("key"), f_bool).unwrap();
 
-        assert_eq!( kv_store.lookup::<String, bool>(String::from("key")).unwrap(), false);
+        assert!( !kv_store.lookup::<String, bool>(String::from("key")).unwrap());
 
     }
 
@@ -555,7 +875,7 @@ use std::collections::HashMap;
     fn insert_array() {
         
         let owned_string = "./test-KV/test4".to_string();
-        let mut kv_store = KVStore::new(&owned_string).unwrap_or_else(|_err| {
+        let mut kv_store = fresh_store(&owned_string).unwrap_or_else(|_err| {
             process::exit(1)
         });
 
@@ -571,7 +891,7 @@ use std::collections::HashMap;
     fn insert_hashmap() {
         
         let owned_string = "./test-KV/test5".to_string();
-        let mut kv_store = KVStore::new(&owned_string).unwrap_or_else(|_err| {
+        let mut kv_store = fresh_store(&owned_string).unwrap_or_else(|_err| {
             process::exit(1)
         });
 
@@ -593,12 +913,12 @@ use std::collections::HashMap;
     fn invalid_path_lookup() {
         
         let owned_string = "./test-KV/invalidfolder2".to_string();
-        let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+        let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
             
             process::exit(1);
         });
 
-        kv_store.insert(String::from("key"), 3 as i32).expect("Insert Failed");
+        kv_store.insert(String::from("key"), 3_i32).expect("Insert Failed");
 
         match  kv_store.lookup::<String, i32>(String::from("key")) {
             Ok(_) => assert_eq!(false, false),
@@ -610,12 +930,12 @@ use std::collections::HashMap;
     fn invalid_path_insert() {
         
         let owned_string = "./test-KV/invalidfolder".to_string();
-        let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+        let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
             
             process::exit(1);
         });
 
-        match  kv_store.insert(String::from("key"), 3 as i32) {
+        match  kv_store.insert(String::from("key"), 3_i32) {
             Ok(_) => assert_eq!(false, false),
             Err(_e) => assert_eq!(true, true),
         }
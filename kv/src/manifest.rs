@@ -0,0 +1,249 @@
+//! The manifest file at the root of a store, which records how the store was created.
+//!
+//! The manifest is a small JSON document that is written when a store is created and replaced
+//! atomically whenever its settings change. Settings in the manifest win over the [crate::Options]
+//! a store is opened with, so a store always keeps the layout it was created with. A store whose
+//! manifest asks for a format, hash algorithm or codec this version does not support is refused
+//! instead of being misread.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use crate::durability::Syncer;
+use crate::record::now_millis;
+use crate::{Codec, EncryptionKey, ShardLayout};
+
+pub(crate) const MANIFEST_FILE: &str = "MANIFEST";
+
+/// The on-disk format version of the store as a whole.
+pub(crate) const STORE_FORMAT_VERSION: u32 = 1;
+/// The algorithm keys of unencrypted stores are hashed with to find their record files.
+pub(crate) const HASH_SHA256: &str = "sha256";
+/// The algorithm keys of encrypted stores are hashed with.
+pub(crate) const HASH_HMAC_SHA256: &str = "hmac-sha256";
+/// The cipher encrypted stores seal keys and values with.
+pub(crate) const CIPHER_CHACHA20POLY1305: &str = "chacha20poly1305";
+
+/// The persisted settings of a store.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    /// The on-disk format version the store was written with.
+    pub(crate) format_version: u32,
+    /// Where record files are placed.
+    pub(crate) layout: ShardLayout,
+    /// The layout an interrupted re-shard was migrating to, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) resharding_to: Option<ShardLayout>,
+    /// How many times `layout` or `resharding_to` changed, so that open handles can tell that the
+    /// layout they read has moved on.
+    #[serde(default)]
+    pub(crate) layout_generation: u64,
+    /// The algorithm keys are hashed with.
+    pub(crate) hash: String,
+    /// The name of the [Codec] new values are serialized with.
+    pub(crate) codec: String,
+    /// When the store was created, in milliseconds since the Unix epoch.
+    pub(crate) created: u64,
+    /// The cipher keys and values are encrypted with, if the store is encrypted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) encryption: Option<String>,
+    /// The fingerprint of the [EncryptionKey] of an encrypted store.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) key_check: Option<String>,
+}
+
+impl Manifest {
+    /// Describes a store that is created right now with the given layout and codec, and that is
+    /// encrypted if it has a `key`.
+    pub(crate) fn new(layout: ShardLayout, codec: Codec, key: Option<&EncryptionKey>) -> Manifest {
+        Manifest {
+            format_version: STORE_FORMAT_VERSION,
+            layout,
+            resharding_to: None,
+            layout_generation: 0,
+            hash: match key {
+                Some(_) => HASH_HMAC_SHA256,
+                None => HASH_SHA256,
+            }
+            .to_string(),
+            codec: codec.name().to_string(),
+            created: now_millis(),
+            encryption: key.map(|_| CIPHER_CHACHA20POLY1305.to_string()),
+            key_check: key.map(EncryptionKey::check_value),
+        }
+    }
+
+    /// Checks that the store is opened with the key it was created with, or without one if it is
+    /// not encrypted.
+    pub(crate) fn check_key(&self, key: Option<&EncryptionKey>) -> std::io::Result<()> {
+        match (&self.key_check, key) {
+            (None, None) => Ok(()),
+            (None, Some(_)) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Store was created without encryption and cannot be opened with a key!",
+            )),
+            (Some(_), None) => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Store is encrypted and has to be opened with its key!",
+            )),
+            (Some(check), Some(key)) if *check != key.check_value() => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "Store is encrypted with a different key!",
+            )),
+            (Some(_), Some(_)) => Ok(()),
+        }
+    }
+
+    /// Reads and validates the manifest of the store at `root`, or returns `None` if it has none.
+    pub(crate) fn load(root: &Path) -> std::io::Result<Option<Manifest>> {
+        let bytes = match fs::read(root.join(MANIFEST_FILE)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        // Check the version before anything else, so a newer store is reported as such rather
+        // than as a manifest that fails to parse.
+        let document: serde_json::Value = match serde_json::from_slice(&bytes) {
+            Err(e) => return Err(corrupt(e)),
+            Ok(document) => document,
+        };
+        match document.get("format_version").and_then(|v| v.as_u64()) {
+            None => return Err(corrupt("missing format version")),
+            Some(version) if version > u64::from(STORE_FORMAT_VERSION) => {
+                return Err(unsupported(format!(
+                    "Store was written in format version {}, but only versions up to {} are supported!",
+                    version, STORE_FORMAT_VERSION
+                )))
+            }
+            Some(_) => {}
+        }
+
+        let manifest: Manifest = serde_json::from_value(document).map_err(corrupt)?;
+        manifest.validate()?;
+        Ok(Some(manifest))
+    }
+
+    /// Replaces the manifest of the store at `root`.
+    pub(crate) fn save(&self, root: &Path, syncer: &mut Syncer) -> std::io::Result<()> {
+        let bytes = serde_json::to_vec_pretty(self).map_err(Error::other)?;
+        syncer.write_atomic(&root.join(MANIFEST_FILE), &bytes)
+    }
+
+    /// Returns an [ErrorKind::Unsupported] error if this version cannot open the store.
+    fn validate(&self) -> std::io::Result<()> {
+        let encrypted = self.encryption.is_some() || self.key_check.is_some();
+        let expected_hash = if encrypted {
+            HASH_HMAC_SHA256
+        } else {
+            HASH_SHA256
+        };
+        if self.hash != expected_hash {
+            return Err(unsupported(format!(
+                "Store was created with the unsupported hash algorithm {}!",
+                self.hash
+            )));
+        }
+        if let Some(cipher) = &self.encryption {
+            if cipher != CIPHER_CHACHA20POLY1305 || self.key_check.is_none() {
+                return Err(unsupported(format!(
+                    "Store was encrypted with the unsupported cipher {}!",
+                    cipher
+                )));
+            }
+        }
+        if Codec::from_name(&self.codec).is_none() {
+            return Err(unsupported(format!(
+                "Store was created with the unsupported codec {}!",
+                self.codec
+            )));
+        }
+        self.layout.validate()?;
+        if let Some(layout) = self.resharding_to {
+            layout.validate()?;
+        }
+        Ok(())
+    }
+}
+
+fn corrupt<E: std::fmt::Display>(e: E) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Manifest file is corrupt: {}", e),
+    )
+}
+
+fn unsupported(message: String) -> Error {
+    Error::new(ErrorKind::Unsupported, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Manifest, MANIFEST_FILE};
+    use crate::{Codec, KVStore, Operations, ShardLayout};
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::path::Path;
+
+    fn open_with_manifest(path: &str, edit: impl FnOnce(&mut serde_json::Value)) -> ErrorKind {
+        let _ = fs::remove_dir_all(path);
+        fs::create_dir_all(path).unwrap();
+        let mut document =
+            serde_json::to_value(Manifest::new(ShardLayout::default(), Codec::Json, None)).unwrap();
+        edit(&mut document);
+        fs::write(
+            Path::new(path).join(MANIFEST_FILE),
+            serde_json::to_vec(&document).unwrap(),
+        )
+        .unwrap();
+        KVStore::new(path).unwrap_err().kind()
+    }
+
+    #[test]
+    fn new_store_writes_a_manifest() {
+        let path = "./test-KV/manifest1";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        kv_store.insert(String::from("Moon"), 1_i32).unwrap();
+
+        let manifest = Manifest::load(Path::new(path)).unwrap().unwrap();
+        assert_eq!(manifest.format_version, super::STORE_FORMAT_VERSION);
+        assert_eq!(manifest.hash, "sha256");
+        assert_eq!(manifest.codec, "json");
+        assert_eq!(manifest.layout, ShardLayout::default());
+        assert!(manifest.created > 0);
+
+        let reopened = KVStore::new(path).unwrap();
+        assert_eq!(reopened.size(), 1);
+        let again = Manifest::load(Path::new(path)).unwrap().unwrap();
+        assert_eq!(again.created, manifest.created);
+    }
+
+    #[test]
+    fn incompatible_stores_are_refused() {
+        let newer = open_with_manifest("./test-KV/manifest2", |m| {
+            m["format_version"] = 99.into();
+        });
+        assert_eq!(newer, ErrorKind::Unsupported);
+        let hash = open_with_manifest("./test-KV/manifest3", |m| m["hash"] = "md5".into());
+        assert_eq!(hash, ErrorKind::Unsupported);
+        let codec = open_with_manifest("./test-KV/manifest4", |m| m["codec"] = "xml".into());
+        assert_eq!(codec, ErrorKind::Unsupported);
+        let garbage = open_with_manifest("./test-KV/manifest5", |m| *m = "garbage".into());
+        assert_eq!(garbage, ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn foreign_directories_are_refused() {
+        let path = "./test-KV/manifest6";
+        let _ = fs::remove_dir_all(path);
+        fs::create_dir_all(path).unwrap();
+        fs::write(Path::new(path).join("notes.txt"), "not a store").unwrap();
+        assert_eq!(
+            KVStore::new(path).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! A backend that keeps every mapping in memory.
+//!
+//! Nothing is written to disk, so a [MemoryStore] starts empty every time and never shares state
+//! with another instance. It has the same semantics as the on-disk stores, which makes it a good
+//! fit for unit tests and for caches.
+
+use std::collections::HashMap;
+
+use crate::{Backend, Options, Record, Store};
+
+/// A backend that holds records in a hash map keyed by their serialized key.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    entries: HashMap<Vec<u8>, Record>,
+    chunks: HashMap<String, Vec<u8>>,
+}
+
+/// A key-value store that lives entirely in memory.
+pub type MemoryStore = Store<MemoryBackend>;
+
+impl MemoryBackend {
+    /// Creates an empty backend.
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+}
+
+impl Backend for MemoryBackend {
+    /// Creates an empty backend; the path and options are ignored.
+    fn open(_path: &str, _options: &Options) -> std::io::Result<MemoryBackend> {
+        Ok(MemoryBackend::new())
+    }
+
+    fn put(&mut self, _hash: &str, record: &Record) -> std::io::Result<()> {
+        self.entries.insert(record.key.clone(), record.clone());
+        Ok(())
+    }
+
+    fn get(&self, _hash: &str, key: &[u8]) -> std::io::Result<Option<Record>> {
+        Ok(self.entries.get(key).cloned())
+    }
+
+    fn delete(&mut self, _hash: &str, key: &[u8]) -> std::io::Result<bool> {
+        Ok(self.entries.remove(key).is_some())
+    }
+
+    fn exists(&self, _hash: &str, key: &[u8]) -> std::io::Result<bool> {
+        Ok(self.entries.contains_key(key))
+    }
+
+    fn put_chunk(&mut self, id: &str, chunk: &[u8]) -> std::io::Result<()> {
+        self.chunks.insert(id.to_string(), chunk.to_vec());
+        Ok(())
+    }
+
+    fn get_chunk(&self, id: &str) -> std::io::Result<Option<Vec<u8>>> {
+        Ok(self.chunks.get(id).cloned())
+    }
+
+    fn delete_chunk(&mut self, id: &str) -> std::io::Result<bool> {
+        Ok(self.chunks.remove(id).is_some())
+    }
+
+    fn scan(&self) -> std::io::Result<Vec<Vec<u8>>> {
+        Ok(self.entries.keys().cloned().collect())
+    }
+
+    fn count(&self) -> std::io::Result<usize> {
+        Ok(self.entries.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryStore;
+    use crate::Operations;
+    use std::collections::HashMap;
+
+    #[test]
+    fn inserting_already_existing_key() {
+        let mut kv_store = MemoryStore::new("").unwrap();
+        kv_store.insert(String::from("Hello World"), 2_i32).unwrap();
+        assert!(kv_store.insert(String::from("Hello World"), 3_i32).is_err());
+        assert_eq!(
+            kv_store
+                .lookup::<String, i32>(String::from("Hello World"))
+                .unwrap(),
+            2
+        );
+        assert_eq!(kv_store.size(), 1);
+    }
+
+    #[test]
+    fn missing_keys_are_errors() {
+        let mut kv_store = MemoryStore::new("").unwrap();
+        kv_store.insert(String::from("Past"), 20_i32).unwrap();
+        assert!(kv_store
+            .lookup::<String, i32>(String::from("Present"))
+            .is_err());
+        assert!(kv_store
+            .remove::<String, i32>(String::from("Present"))
+            .is_err());
+        assert_eq!(kv_store.size(), 1);
+    }
+
+    #[test]
+    fn size_tracks_inserts_and_removes() {
+        let mut kv_store = MemoryStore::new("").unwrap();
+        let mut scores: HashMap<String, isize> = HashMap::new();
+        scores.insert(String::from("Blue"), 10);
+
+        kv_store.insert(String::from("Sine"), 360_i32).unwrap();
+        kv_store.insert(String::from("scores"), scores).unwrap();
+        assert_eq!(kv_store.size(), 2);
+
+        assert_eq!(
+            kv_store
+                .remove::<String, i32>(String::from("Sine"))
+                .unwrap(),
+            360
+        );
+        assert_eq!(kv_store.size(), 1);
+        let scores = kv_store.remove::<String, HashMap<String, isize>>(String::from("scores"));
+        assert_eq!(scores.unwrap()["Blue"], 10);
+        assert_eq!(kv_store.size(), 0);
+    }
+
+    #[test]
+    fn instances_do_not_share_state() {
+        let mut first = MemoryStore::new("./test-KV/memory").unwrap();
+        first.insert(String::from("key"), true).unwrap();
+        let second = MemoryStore::new("./test-KV/memory").unwrap();
+        assert_eq!(second.size(), 0);
+        assert!(second.lookup::<String, bool>(String::from("key")).is_err());
+    }
+}
@@ -0,0 +1,64 @@
+//! Settings chosen when a store is opened.
+
+use crate::bitcask::DEFAULT_SEGMENT_SIZE;
+use crate::stream::DEFAULT_CHUNK_SIZE;
+use crate::{Codec, Compression, Durability, EncryptionKey, ShardLayout};
+
+/// Settings that control how a store behaves once it is opened.
+///
+/// Start from [Options::default] and override the fields you care about:
+///
+/// ```
+/// use kv::{Durability, Options};
+///
+/// let options = Options {
+///     durability: Durability::None,
+///     ..Options::default()
+/// };
+/// ```
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// How eagerly changes are flushed to stable storage.
+    pub durability: Durability,
+    /// The size at which a [crate::Bitcask] segment is closed and a new one is started.
+    pub segment_size: u64,
+    /// Where a [crate::DirBackend] places record files. Only used when a store is created; an
+    /// existing store keeps the layout recorded in its manifest.
+    pub shard_layout: ShardLayout,
+    /// The codec new values are serialized with. A store that records the codec it was created
+    /// with keeps using that one.
+    pub codec: Codec,
+    /// How new values are compressed.
+    pub compression: Compression,
+    /// The size in bytes from which a serialized value is compressed.
+    pub compression_threshold: usize,
+    /// The key to encrypt keys and values with, or `None` to store them in plaintext.
+    ///
+    /// A [crate::DirBackend] records whether it is encrypted and refuses to open with the wrong
+    /// key or without one; other backends leave that to the caller.
+    pub encryption_key: Option<EncryptionKey>,
+    /// The size in bytes above which a value is stored as separate chunks of this size, so that
+    /// it can be streamed and read in ranges without loading all of it.
+    pub chunk_size: usize,
+    /// Whether to store identical values only once, shared by every key that maps to them.
+    ///
+    /// Values written without deduplication stay readable when it is turned on, and the other
+    /// way round.
+    pub deduplicate: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            durability: Durability::default(),
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            shard_layout: ShardLayout::default(),
+            codec: Codec::default(),
+            compression: Compression::default(),
+            compression_threshold: 1024,
+            encryption_key: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            deduplicate: false,
+        }
+    }
+}
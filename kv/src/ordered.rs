@@ -0,0 +1,232 @@
+//! An order-preserving encoding of keys.
+//!
+//! Keys are stored as JSON, whose bytes do not sort like the keys they encode (`10` sorts
+//! before `9`, and `"a\"` escapes sort anywhere). The sorted index behind [crate::Store::range]
+//! therefore orders keys by a second encoding of the same JSON, whose bytes compare like the
+//! values they encode:
+//!
+//! ```text
+//! null           0x01
+//! false, true    0x02, 0x03
+//! integer        0x04 | i128, big-endian, sign bit flipped
+//! float          0x05 | f64 bits, big-endian, all bits flipped if negative, else the sign bit
+//! string         0x06 | UTF-8, every 0x00 escaped as 0x00 0xff | 0x00 0x00
+//! array          0x07 | element ... | 0x00
+//! object         0x08 | key value ... | 0x00
+//! ```
+//!
+//! Values of the same type sort naturally, strings by code point, and arrays and objects
+//! element by element with a shorter one first. Object fields keep the order they were
+//! serialized in, so struct keys sort by their first field, then their second, and so on.
+//!
+//! The encoding is computed from the stored JSON rather than from the typed key, so the index
+//! can be rebuilt from stored keys without knowing their type.
+
+use std::fmt;
+
+use serde::de::{DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+
+const NULL: u8 = 0x01;
+const FALSE: u8 = 0x02;
+const TRUE: u8 = 0x03;
+const INTEGER: u8 = 0x04;
+const FLOAT: u8 = 0x05;
+const STRING: u8 = 0x06;
+const ARRAY: u8 = 0x07;
+const OBJECT: u8 = 0x08;
+const END: u8 = 0x00;
+
+/// Returns the order-preserving encoding of a key serialized as JSON, or `None` if the bytes are
+/// not JSON.
+pub(crate) fn encode(serialized_key: &[u8]) -> Option<Vec<u8>> {
+    transcode(serialized_key).map(|encoder| encoder.bytes)
+}
+
+/// Returns the encoding of a key serialized as JSON without the terminators it ends with, so
+/// that it is a prefix of the encoding of every key that extends it: a string prefix of longer
+/// strings, and an array prefix of longer arrays.
+pub(crate) fn encode_prefix(serialized_key: &[u8]) -> Option<Vec<u8>> {
+    transcode(serialized_key).map(|mut encoder| {
+        encoder
+            .bytes
+            .truncate(encoder.bytes.len() - encoder.closing);
+        encoder.bytes
+    })
+}
+
+/// Returns the smallest encoding that is greater than every encoding starting with `prefix`, or
+/// `None` if there is none.
+pub(crate) fn successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut bytes = prefix.to_vec();
+    while let Some(last) = bytes.pop() {
+        if last < u8::MAX {
+            bytes.push(last + 1);
+            return Some(bytes);
+        }
+    }
+    None
+}
+
+fn transcode(serialized_key: &[u8]) -> Option<Encoder> {
+    let mut encoder = Encoder::default();
+    let mut deserializer = serde_json::Deserializer::from_slice(serialized_key);
+    Transcoder(&mut encoder)
+        .deserialize(&mut deserializer)
+        .ok()?;
+    deserializer.end().ok()?;
+    Some(encoder)
+}
+
+#[derive(Default)]
+struct Encoder {
+    bytes: Vec<u8>,
+    /// How many of the last bytes only close strings, arrays and objects.
+    closing: usize,
+}
+
+impl Encoder {
+    fn push(&mut self, tag: u8, payload: &[u8]) {
+        self.bytes.push(tag);
+        self.bytes.extend_from_slice(payload);
+        self.closing = 0;
+    }
+
+    fn close(&mut self, terminator: &[u8]) {
+        self.bytes.extend_from_slice(terminator);
+        self.closing += terminator.len();
+    }
+
+    fn integer(&mut self, value: i128) {
+        let flipped = (value as u128) ^ (1 << 127);
+        self.push(INTEGER, &flipped.to_be_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        let mut escaped = Vec::with_capacity(value.len());
+        for &byte in value.as_bytes() {
+            escaped.push(byte);
+            if byte == 0 {
+                escaped.push(u8::MAX);
+            }
+        }
+        self.push(STRING, &escaped);
+        self.close(&[END, END]);
+    }
+}
+
+/// Writes the encoding of whatever JSON value it deserializes into an [Encoder].
+struct Transcoder<'a>(&'a mut Encoder);
+
+impl<'de> DeserializeSeed<'de> for Transcoder<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Transcoder<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a JSON value")
+    }
+
+    fn visit_unit<E>(self) -> Result<(), E> {
+        self.0.push(NULL, &[]);
+        Ok(())
+    }
+
+    fn visit_bool<E>(self, value: bool) -> Result<(), E> {
+        self.0.push(if value { TRUE } else { FALSE }, &[]);
+        Ok(())
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<(), E> {
+        self.0.integer(value.into());
+        Ok(())
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<(), E> {
+        self.0.integer(value.into());
+        Ok(())
+    }
+
+    fn visit_f64<E>(self, value: f64) -> Result<(), E> {
+        let bits = value.to_bits();
+        let flipped = if bits >> 63 == 1 {
+            !bits
+        } else {
+            bits ^ (1 << 63)
+        };
+        self.0.push(FLOAT, &flipped.to_be_bytes());
+        Ok(())
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<(), E> {
+        self.0.string(value);
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        self.0.push(ARRAY, &[]);
+        while seq.next_element_seed(Transcoder(self.0))?.is_some() {}
+        self.0.close(&[END]);
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        self.0.push(OBJECT, &[]);
+        while map.next_key_seed(Transcoder(self.0))?.is_some() {
+            map.next_value_seed(Transcoder(self.0))?;
+        }
+        self.0.close(&[END]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, encode_prefix, successor};
+    use serde::Serialize;
+
+    fn encoded<T: Serialize>(key: T) -> Vec<u8> {
+        encode(&serde_json::to_vec(&key).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn encoding_sorts_like_the_keys() {
+        let numbers = [i64::MIN, -300, -1, 0, 1, 9, 10, 255, 256, i64::MAX];
+        for pair in numbers.windows(2) {
+            assert!(encoded(pair[0]) < encoded(pair[1]), "{:?}", pair);
+        }
+        assert!(encoded(i64::MAX) < encoded(u64::MAX));
+        let floats = [f64::MIN, -2.5, -0.5, 0.0, 0.5, 2.5, f64::MAX];
+        for pair in floats.windows(2) {
+            assert!(encoded(pair[0]) < encoded(pair[1]), "{:?}", pair);
+        }
+        let strings = ["", "\u{0}", "\u{0}a", "a", "a\"", "ab", "b", "é"];
+        for pair in strings.windows(2) {
+            assert!(encoded(pair[0]) < encoded(pair[1]), "{:?}", pair);
+        }
+        assert!(encoded(("user", 9)) < encoded(("user", 10)));
+        assert!(encoded(("user", 10)) < encoded(("user", 10, 0)));
+        assert!(encoded(("user", 10, 0)) < encoded(("users", 0)));
+    }
+
+    #[test]
+    fn prefixes_match_the_keys_that_extend_them() {
+        let prefix = encode_prefix(&serde_json::to_vec("user:42:").unwrap()).unwrap();
+        assert!(encoded("user:42:name").starts_with(&prefix));
+        assert!(encoded("user:42:").starts_with(&prefix));
+        assert!(!encoded("user:420").starts_with(&prefix));
+
+        let prefix = encode_prefix(&serde_json::to_vec(&("user", 42)).unwrap()).unwrap();
+        assert!(encoded(("user", 42, "name")).starts_with(&prefix));
+        assert!(!encoded(("user", 43)).starts_with(&prefix));
+
+        assert_eq!(successor(&[1, 2, 255]), Some(vec![1, 3]));
+        assert_eq!(successor(&[255, 255]), None);
+        assert_eq!(encode(b"not json"), None);
+    }
+}
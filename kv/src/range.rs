@@ -0,0 +1,399 @@
+//! Ordered range and prefix scans over keys.
+//!
+//! Records are placed by the hash of their key, so the backends keep them in no useful order.
+//! Range and prefix scans go through a sorted index instead, which maps the
+//! [order-preserving encoding](crate::ordered) of every key to the JSON the key is stored as.
+//! The index is built from [Backend::scan] the first time a scan needs it, which keeps opening a
+//! store cheap, and is kept up to date by every insert and removal through the same handle after
+//! that. Once another handle on the same storage changed it, which [Backend::generation] tells,
+//! the next scan builds the index again. Keys written through the raw byte API that are not JSON
+//! are left out of it.
+//!
+//! A scan returns one [Page] at a time. Setting [ScanOptions::limit] caps the size of a page, and
+//! the [Cursor] of a page that stopped early continues the same scan where it left off.
+
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::ops::{Bound, RangeBounds};
+use std::sync::{MutexGuard, PoisonError};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::ordered;
+use crate::{decode_value, serialize_key, Backend, Error, Result, Store};
+
+/// The sorted index of a store.
+#[derive(Debug, Default)]
+pub(crate) struct KeyOrder {
+    /// The [Backend::generation] the keys were read at, or `None` before they first are.
+    generation: Option<u64>,
+    /// The order-preserving encoding of every key, mapped to the JSON the key is serialized as.
+    keys: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+/// How [Store::range] and [Store::scan_prefix] walk the keys they match.
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    /// Whether to walk from the largest key down instead of from the smallest key up.
+    pub reverse: bool,
+    /// The most mappings one page holds, or `None` for every mapping that matches. A limit of
+    /// 0 is refused.
+    pub limit: Option<usize>,
+    /// Where the previous page of the same scan stopped, or `None` to start from the beginning.
+    pub cursor: Option<Cursor>,
+}
+
+/// The position a scan stopped at: the last key of a [Page] that did not hold every match.
+///
+/// A cursor is only meaningful to the scan that returned it, with the same range or prefix and
+/// direction. Its bytes can be stored or sent elsewhere and turned back into a cursor later.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor(Vec<u8>);
+
+impl Cursor {
+    /// Returns the bytes of the cursor.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Restores a cursor from the bytes returned by [Cursor::as_bytes].
+    pub fn from_bytes(bytes: Vec<u8>) -> Cursor {
+        Cursor(bytes)
+    }
+}
+
+/// One page of the mappings a scan matched, in scan order.
+#[derive(Debug)]
+pub struct Page<K, V> {
+    /// The mappings on this page.
+    pub entries: Vec<(K, V)>,
+    /// Where to continue the scan, or `None` if this page holds the last match.
+    pub next: Option<Cursor>,
+}
+
+impl<B: Backend> Store<B> {
+    /// Returns the mappings whose keys fall in `range`, ordered by key.
+    ///
+    /// Keys of the same type are ordered naturally: numbers by value, strings by code point, and
+    /// tuples and structs field by field.
+    ///
+    /// ```
+    /// use kv::{KeyValue, MemoryStore, Options, ScanOptions, Store};
+    ///
+    /// let mut store: MemoryStore = Store::open("", Options::default()).unwrap();
+    /// for year in 1990..2000 {
+    ///     store.put(&year, &format!("{}s", year / 10 * 10)).unwrap();
+    /// }
+    /// let page = store
+    ///     .range::<i32, String, _>(1995..1998, &ScanOptions::default())
+    ///     .unwrap();
+    /// let years: Vec<i32> = page.entries.into_iter().map(|(year, _)| year).collect();
+    /// assert_eq!(years, vec![1995, 1996, 1997]);
+    /// ```
+    pub fn range<K, V, R>(&self, range: R, options: &ScanOptions) -> Result<Page<K, V>>
+    where
+        K: Serialize + DeserializeOwned,
+        V: DeserializeOwned,
+        R: RangeBounds<K>,
+    {
+        let lower = encode_bound(range.start_bound())?;
+        let upper = encode_bound(range.end_bound())?;
+        self.scan_ordered(lower, upper, options)
+    }
+
+    /// Returns the mappings whose keys start with `prefix`, ordered by key.
+    ///
+    /// A string prefix matches every string key that starts with it, and a tuple prefix every
+    /// tuple key whose first elements equal it, with the last element matched as a prefix if it
+    /// is a string.
+    pub fn scan_prefix<P, K, V>(&self, prefix: &P, options: &ScanOptions) -> Result<Page<K, V>>
+    where
+        P: Serialize + ?Sized,
+        K: DeserializeOwned,
+        V: DeserializeOwned,
+    {
+        let serialized = serialize_key(prefix)?;
+        let prefix = ordered::encode_prefix(&serialized)
+            .ok_or_else(|| Error::codec("Key prefix is not valid JSON!"))?;
+        let upper = match ordered::successor(&prefix) {
+            Some(successor) => Bound::Excluded(successor),
+            None => Bound::Unbounded,
+        };
+        self.scan_ordered(Bound::Included(prefix), upper, options)
+    }
+
+    /// Updates the sorted index, if it has been built, after the mapping of `stored_key` was
+    /// added (`present`) or removed.
+    pub(crate) fn track_key(&mut self, stored_key: &[u8], present: bool) {
+        if self.key_order_mut().generation.is_none() {
+            return;
+        }
+        let key = match self.unlock_key(stored_key) {
+            Ok((key, _)) => key,
+            Err(_) => return,
+        };
+        if let Some(encoded) = ordered::encode(&key) {
+            let keys = &mut self.key_order_mut().keys;
+            if present {
+                keys.insert(encoded, key);
+            } else {
+                keys.remove(&encoded);
+            }
+        }
+    }
+
+    fn key_order_mut(&mut self) -> &mut KeyOrder {
+        self.key_order
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the sorted index, building it on first use and again once another handle changed
+    /// the store.
+    fn key_order(&self) -> Result<MutexGuard<'_, KeyOrder>> {
+        let mut order = self
+            .key_order
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // The generation is read first, so a change that comes in while the keys are read is
+        // picked up again by the next scan rather than missed.
+        let generation = self.backend.generation()?;
+        if order.generation != Some(generation) {
+            let mut keys = BTreeMap::new();
+            for stored_key in self.backend.scan()? {
+                let (key, _) = self.unlock_key(&stored_key)?;
+                if let Some(encoded) = ordered::encode(&key) {
+                    keys.insert(encoded, key);
+                }
+            }
+            *order = KeyOrder {
+                generation: Some(generation),
+                keys,
+            };
+        }
+        Ok(order)
+    }
+
+    /// Reads one page of the mappings whose encoded keys lie between `lower` and `upper`.
+    fn scan_ordered<K, V>(
+        &self,
+        mut lower: Bound<Vec<u8>>,
+        mut upper: Bound<Vec<u8>>,
+        options: &ScanOptions,
+    ) -> Result<Page<K, V>>
+    where
+        K: DeserializeOwned,
+        V: DeserializeOwned,
+    {
+        if options.limit == Some(0) {
+            let e = std::io::Error::new(ErrorKind::InvalidInput, "Scan limit must not be 0!");
+            return Err(e.into());
+        }
+        if let Some(cursor) = &options.cursor {
+            if options.reverse {
+                upper = tighten(upper, &cursor.0, |bound| bound < cursor.0.as_slice());
+            } else {
+                lower = tighten(lower, &cursor.0, |bound| bound > cursor.0.as_slice());
+            }
+        }
+        let mut page = Page {
+            entries: Vec::new(),
+            next: None,
+        };
+        if is_empty(&lower, &upper) {
+            return Ok(page);
+        }
+        let order = self.key_order()?;
+        let range = order.keys.range((lower, upper));
+        let keys: Box<dyn Iterator<Item = (&Vec<u8>, &Vec<u8>)>> = if options.reverse {
+            Box::new(range.rev())
+        } else {
+            Box::new(range)
+        };
+        let limit = options.limit.unwrap_or(usize::MAX);
+        let mut last = options.cursor.clone();
+        for (encoded, key) in keys {
+            if page.entries.len() == limit {
+                page.next = last;
+                break;
+            }
+            // A mapping removed by another handle on the same store is skipped.
+            if let Some((codec, bytes, hash)) = self.read_bytes(key)? {
+                let typed_key =
+                    serde_json::from_slice(key).map_err(|e| Error::codec(e).for_key(&hash))?;
+                let value = decode_value(codec, &bytes, &hash)?;
+                page.entries.push((typed_key, value));
+                last = Some(Cursor(encoded.clone()));
+            }
+        }
+        Ok(page)
+    }
+}
+
+/// Encodes a bound of a typed key range.
+fn encode_bound<K: Serialize>(bound: Bound<&K>) -> Result<Bound<Vec<u8>>> {
+    let encode = |key: &K| -> Result<Vec<u8>> {
+        ordered::encode(&serialize_key(key)?).ok_or_else(|| Error::codec("Key is not valid JSON!"))
+    };
+    Ok(match bound {
+        Bound::Included(key) => Bound::Included(encode(key)?),
+        Bound::Excluded(key) => Bound::Excluded(encode(key)?),
+        Bound::Unbounded => Bound::Unbounded,
+    })
+}
+
+/// Narrows `bound` so that it excludes `cursor` and everything before it in scan order, unless it
+/// already does. `inside` tells whether a bound value lies strictly after the cursor.
+fn tighten<F: Fn(&[u8]) -> bool>(
+    bound: Bound<Vec<u8>>,
+    cursor: &[u8],
+    inside: F,
+) -> Bound<Vec<u8>> {
+    match &bound {
+        Bound::Included(value) | Bound::Excluded(value) if inside(value) => bound,
+        _ => Bound::Excluded(cursor.to_vec()),
+    }
+}
+
+/// Returns whether no key lies between `lower` and `upper`, which [BTreeMap::range] does not
+/// accept.
+fn is_empty(lower: &Bound<Vec<u8>>, upper: &Bound<Vec<u8>>) -> bool {
+    match (lower, upper) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cursor, ScanOptions};
+    use crate::{Error, KVStore, KeyValue, MemoryStore, Operations, Options, Store};
+    use std::fs;
+
+    fn years(page: Vec<(i32, String)>) -> Vec<i32> {
+        page.into_iter().map(|(year, _)| year).collect()
+    }
+
+    #[test]
+    fn ranges_follow_key_order() {
+        let mut kv_store: MemoryStore = Store::open("", Options::default()).unwrap();
+        for year in (1900..2000).rev() {
+            kv_store.put(&year, &year.to_string()).unwrap();
+        }
+        let all = ScanOptions::default();
+        let page = kv_store.range::<i32, String, _>(998..1002, &all).unwrap();
+        assert!(page.entries.is_empty());
+        let page = kv_store.range::<i32, String, _>(1995.., &all).unwrap();
+        assert_eq!(years(page.entries), vec![1995, 1996, 1997, 1998, 1999]);
+        let reverse = ScanOptions {
+            reverse: true,
+            ..ScanOptions::default()
+        };
+        let page = kv_store.range::<i32, String, _>(..=1902, &reverse).unwrap();
+        assert_eq!(years(page.entries), vec![1902, 1901, 1900]);
+
+        kv_store.take::<_, String>(&1996).unwrap();
+        kv_store.put(&2000, &String::from("2000")).unwrap();
+        let page = kv_store.range::<i32, String, _>(1995.., &all).unwrap();
+        assert_eq!(years(page.entries), vec![1995, 1997, 1998, 1999, 2000]);
+        assert!(page.next.is_none());
+    }
+
+    #[test]
+    fn prefixes_select_related_keys() {
+        let path = "./test-KV/range1";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        for user in [7, 42, 420].iter() {
+            for field in ["name", "email", "age"].iter() {
+                let key = format!("user:{}:{}", user, field);
+                kv_store.put(&key, &key.len()).unwrap();
+            }
+        }
+        Operations::insert(&mut kv_store, (String::from("user"), 42), 1_usize).unwrap();
+
+        let kv_store = KVStore::new(path).unwrap();
+        let page = kv_store
+            .scan_prefix::<_, String, usize>("user:42:", &ScanOptions::default())
+            .unwrap();
+        let keys: Vec<String> = page.entries.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["user:42:age", "user:42:email", "user:42:name"]);
+
+        let page = kv_store
+            .scan_prefix::<_, (String, u32), usize>(&("user",), &ScanOptions::default())
+            .unwrap();
+        assert_eq!(page.entries, vec![((String::from("user"), 42), 1)]);
+    }
+
+    #[test]
+    fn pages_continue_where_they_stopped() {
+        let mut kv_store: MemoryStore = Store::open("", Options::default()).unwrap();
+        for year in 1990..2000 {
+            kv_store.put(&year, &year.to_string()).unwrap();
+        }
+        for reverse in [false, true].iter() {
+            let mut options = ScanOptions {
+                reverse: *reverse,
+                limit: Some(4),
+                cursor: None,
+            };
+            let mut seen = Vec::new();
+            loop {
+                let page = kv_store
+                    .range::<i32, String, _>(1991..1999, &options)
+                    .unwrap();
+                assert!(page.entries.len() <= 4);
+                seen.extend(years(page.entries));
+                match page.next {
+                    None => break,
+                    Some(cursor) => {
+                        options.cursor = Some(Cursor::from_bytes(cursor.as_bytes().to_vec()))
+                    }
+                }
+            }
+            let mut expected: Vec<i32> = (1991..1999).collect();
+            if *reverse {
+                expected.reverse();
+            }
+            assert_eq!(seen, expected);
+        }
+
+        let options = ScanOptions {
+            limit: Some(0),
+            ..ScanOptions::default()
+        };
+        let e = kv_store
+            .range::<i32, String, _>(1991..1999, &options)
+            .unwrap_err();
+        assert!(matches!(e, Error::Io { .. }), "{}", e);
+    }
+
+    #[test]
+    fn scans_see_changes_of_other_handles() {
+        let path = "./test-KV/range2";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        let mut other = KVStore::new(path).unwrap();
+        kv_store.put(&1, &String::from("1")).unwrap();
+        let all = ScanOptions::default();
+        let page = kv_store.range::<i32, String, _>(.., &all).unwrap();
+        assert_eq!(years(page.entries), vec![1]);
+
+        other.put(&2, &String::from("2")).unwrap();
+        other.put(&3, &String::from("3")).unwrap();
+        let page = kv_store.range::<i32, String, _>(.., &all).unwrap();
+        assert_eq!(years(page.entries), vec![1, 2, 3]);
+
+        // Changes through the handle itself still keep the index up to date.
+        kv_store.take::<_, String>(&2).unwrap();
+        kv_store.put(&4, &String::from("4")).unwrap();
+        let page = kv_store.range::<i32, String, _>(.., &all).unwrap();
+        assert_eq!(years(page.entries), vec![1, 3, 4]);
+        let page = other.range::<i32, String, _>(.., &all).unwrap();
+        assert_eq!(years(page.entries), vec![1, 3, 4]);
+    }
+}
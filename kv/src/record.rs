@@ -0,0 +1,203 @@
+//! The on-disk format of a single key-value mapping.
+//!
+//! Every mapping is stored as one record: a fixed-size header followed by the serialized key and
+//! the serialized value. All integers are little-endian.
+//!
+//! ```text
+//! +-------+--------+-------+-------+----------+---------+-----------+----------+---------+----------+---------+
+//! | magic | format | codec | flags | reserved | key len | value len | checksum | created | modified | version |
+//! |  4 B  |   u8   |  u8   |  u8   |    u8    |   u32   |    u64    |   u32    |   u64   |   u64    |   u64   |
+//! +-------+--------+-------+-------+----------+---------+-----------+----------+---------+----------+---------+
+//! ```
+//!
+//! The checksum is a CRC32 over the header (with the checksum field zeroed), the key and the
+//! value. The timestamps are milliseconds since the Unix epoch. Records of format version 1 lack
+//! the version field and are read as version 1.
+
+use std::convert::{TryFrom, TryInto};
+use std::io::{Error, ErrorKind};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The bytes every record starts with.
+pub const MAGIC: [u8; 4] = *b"KVR1";
+/// The record format version written by this crate.
+pub const FORMAT_VERSION: u8 = 2;
+/// The size of the fixed record header in bytes.
+pub const HEADER_LEN: usize = 48;
+
+/// The size of the header of format version 1, which ends before the version field.
+const V1_HEADER_LEN: usize = 40;
+
+const CHECKSUM_AT: usize = 20;
+
+/// A serialized key-value mapping together with its metadata.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    /// The serialized key.
+    pub key: Vec<u8>,
+    /// The serialized value.
+    pub value: Vec<u8>,
+    /// The id of the [crate::Codec] the value was serialized with.
+    pub codec: u8,
+    /// Per-record flags, such as how the value was compressed.
+    pub flags: u8,
+    /// When the mapping was first written, in milliseconds since the Unix epoch.
+    pub created: u64,
+    /// When the mapping was last written, in milliseconds since the Unix epoch.
+    pub modified: u64,
+    /// How many times the mapping has been written since it was inserted, starting at 1.
+    pub version: u64,
+}
+
+impl Record {
+    /// Creates a record for a mapping that is written right now.
+    pub fn new(key: Vec<u8>, value: Vec<u8>, codec: u8) -> Record {
+        let now = now_millis();
+        Record {
+            key,
+            value,
+            codec,
+            flags: 0,
+            created: now,
+            modified: now,
+            version: 1,
+        }
+    }
+
+    /// Serializes the record into its on-disk form.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.key.len() + self.value.len());
+        out.extend_from_slice(&MAGIC);
+        out.push(FORMAT_VERSION);
+        out.push(self.codec);
+        out.push(self.flags);
+        out.push(0);
+        out.extend_from_slice(&(self.key.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(self.value.len() as u64).to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&self.created.to_le_bytes());
+        out.extend_from_slice(&self.modified.to_le_bytes());
+        out.extend_from_slice(&self.version.to_le_bytes());
+        out.extend_from_slice(&self.key);
+        out.extend_from_slice(&self.value);
+
+        let checksum = crc32fast::hash(&out);
+        out[CHECKSUM_AT..CHECKSUM_AT + 4].copy_from_slice(&checksum.to_le_bytes());
+        out
+    }
+
+    /// Parses a record from its on-disk form, verifying the checksum.
+    ///
+    /// Returns an [ErrorKind::InvalidData] error if the bytes are not an intact record.
+    pub fn decode(bytes: &[u8]) -> std::io::Result<Record> {
+        if bytes.len() < V1_HEADER_LEN || bytes[0..4] != MAGIC {
+            return Err(corrupt("Record header is missing or damaged!"));
+        }
+        let header_len = match bytes[4] {
+            1 => V1_HEADER_LEN,
+            FORMAT_VERSION if bytes.len() >= HEADER_LEN => HEADER_LEN,
+            FORMAT_VERSION => return Err(corrupt("Record header is missing or damaged!")),
+            _ => {
+                return Err(corrupt(
+                    "Record was written in an unsupported format version!",
+                ))
+            }
+        };
+
+        let key_len = u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize;
+        let value_len = u64::from_le_bytes(bytes[12..20].try_into().unwrap());
+        let stored = u32::from_le_bytes(bytes[20..24].try_into().unwrap());
+        // The lengths are not covered by the checksum yet, so a damaged header must not be able
+        // to overflow them.
+        let len = usize::try_from(value_len)
+            .ok()
+            .and_then(|value_len| header_len.checked_add(key_len)?.checked_add(value_len));
+        if len != Some(bytes.len()) {
+            return Err(corrupt("Record length does not match its header!"));
+        }
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&bytes[..CHECKSUM_AT]);
+        hasher.update(&[0; 4]);
+        hasher.update(&bytes[CHECKSUM_AT + 4..]);
+        if hasher.finalize() != stored {
+            return Err(corrupt("Record checksum does not match!"));
+        }
+
+        let key_end = header_len + key_len;
+        let version = match header_len {
+            HEADER_LEN => u64::from_le_bytes(bytes[40..48].try_into().unwrap()),
+            _ => 1,
+        };
+        Ok(Record {
+            key: bytes[header_len..key_end].to_vec(),
+            value: bytes[key_end..].to_vec(),
+            codec: bytes[5],
+            flags: bytes[6],
+            created: u64::from_le_bytes(bytes[24..32].try_into().unwrap()),
+            modified: u64::from_le_bytes(bytes[32..40].try_into().unwrap()),
+            version,
+        })
+    }
+}
+
+/// Returns the error reported for data that failed validation.
+pub(crate) fn corrupt(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Record, CHECKSUM_AT, HEADER_LEN, V1_HEADER_LEN};
+    use crate::Codec;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn round_trip() {
+        let record = Record::new(b"\"key\"".to_vec(), b"[1,2,3]".to_vec(), Codec::Json.id());
+        let bytes = record.encode();
+        assert_eq!(bytes.len(), HEADER_LEN + 5 + 7);
+        assert_eq!(Record::decode(&bytes).unwrap(), record);
+    }
+
+    #[test]
+    fn format_1_records_are_read_as_version_1() {
+        let mut record = Record::new(b"\"key\"".to_vec(), b"1".to_vec(), Codec::Json.id());
+        record.version = 7;
+        let mut bytes = record.encode();
+        // A format 1 record is the same without the version field.
+        bytes.drain(V1_HEADER_LEN..HEADER_LEN);
+        bytes[4] = 1;
+        bytes[CHECKSUM_AT..CHECKSUM_AT + 4].copy_from_slice(&[0; 4]);
+        let checksum = crc32fast::hash(&bytes);
+        bytes[CHECKSUM_AT..CHECKSUM_AT + 4].copy_from_slice(&checksum.to_le_bytes());
+
+        record.version = 1;
+        assert_eq!(Record::decode(&bytes).unwrap(), record);
+    }
+
+    #[test]
+    fn damage_is_detected() {
+        let bytes = Record::new(b"\"key\"".to_vec(), b"true".to_vec(), Codec::Json.id()).encode();
+
+        let mut flipped = bytes.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        let truncated = &bytes[..bytes.len() - 1];
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        let mut huge_value = bytes.clone();
+        huge_value[12..20].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        for damaged in [&flipped[..], truncated, &bad_magic[..], &huge_value[..]].iter() {
+            let err = Record::decode(damaged).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Point-in-time read snapshots.
+//!
+//! [Store::snapshot] pins the backend through [Backend::pin] and lists the keys of every mapping
+//! while holding the backend lock, so neither catches another handle halfway through a change.
+//! Only the keys are held in memory. Records and values are read when they are asked for,
+//! through [Backend::get_pinned], so writes made after the snapshot was taken, through any other
+//! handle, stay invisible to it.
+//!
+//! A [crate::DirBackend] keeps a copy of every record that any handle replaces or deletes in the
+//! meantime, and every chunk that any handle deletes, until the last snapshot of the store, in
+//! any process, is dropped.
+
+use std::collections::HashSet;
+
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::Serialize;
+
+use crate::iter::Source;
+use crate::{serialize_key, Backend, Error, Iter, Keys, Record, Result, Store, Values};
+
+impl<B: Backend> Store<B> {
+    /// Takes a read-only view of every mapping as it is stored right now.
+    ///
+    /// ```
+    /// use kv::{KeyValue, MemoryStore, Options, Store};
+    ///
+    /// let mut store: MemoryStore = Store::open("", Options::default()).unwrap();
+    /// store.put("stock", &10).unwrap();
+    ///
+    /// let snapshot = store.snapshot().unwrap();
+    /// assert_eq!(snapshot.get("stock").unwrap(), Some(10));
+    /// assert_eq!(snapshot.len(), 1);
+    /// ```
+    pub fn snapshot(&self) -> Result<Snapshot<'_, B>> {
+        self.backend.lock()?;
+        let snapshot = self
+            .backend
+            .pin()
+            .map_err(Error::from)
+            .and_then(|sequence| match self.backend.scan() {
+                Ok(keys) => Ok(Snapshot {
+                    store: self,
+                    sequence,
+                    keys: keys.into_iter().collect(),
+                }),
+                Err(e) => {
+                    let _ = self.backend.unpin();
+                    Err(e.into())
+                }
+            });
+        let unlocked = self.backend.unlock();
+        let snapshot = snapshot?;
+        unlocked?;
+        Ok(snapshot)
+    }
+}
+
+/// A read-only view of the mappings of a store at the moment it was taken, returned by
+/// [Store::snapshot].
+///
+/// The snapshot keeps the data it needs until it is dropped.
+#[derive(Debug)]
+pub struct Snapshot<'a, B: Backend> {
+    store: &'a Store<B>,
+    /// What [Backend::pin] returned, to read the records with.
+    sequence: u64,
+    /// The stored key of every mapping.
+    keys: HashSet<Vec<u8>>,
+}
+
+impl<'a, B: Backend> Snapshot<'a, B> {
+    /// Returns the number of key-value mappings in the snapshot.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Returns whether the snapshot holds no key-value mappings.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Returns the value stored under `key` when the snapshot was taken, or `None` if there was
+    /// none.
+    pub fn get<K, V>(&self, key: &K) -> Result<Option<V>>
+    where
+        K: Serialize + ?Sized,
+        V: DeserializeOwned,
+    {
+        let (hash, stored_key) = self.store.locate(&serialize_key(key)?);
+        match self.record(&hash, &stored_key)? {
+            None => Ok(None),
+            Some(record) => self.store.record_value(&hash, &record).map(Some),
+        }
+    }
+
+    /// Returns whether a value was stored under `key` when the snapshot was taken.
+    pub fn contains_key<K: Serialize + ?Sized>(&self, key: &K) -> Result<bool> {
+        let (_, stored_key) = self.store.locate(&serialize_key(key)?);
+        Ok(self.contains(&stored_key))
+    }
+
+    /// Returns an iterator over every key-value mapping in the snapshot, like [Store::iter].
+    pub fn iter<K, V>(&self) -> Result<Iter<'_, B, K, V>>
+    where
+        K: DeserializeOwned,
+        V: DeserializeOwned,
+    {
+        Ok(Iter::new(Source::Snapshot(self), self.stored_keys()))
+    }
+
+    /// Returns an iterator over every key in the snapshot, like [Store::keys].
+    pub fn keys<K: DeserializeOwned>(&self) -> Result<Keys<'_, B, K>> {
+        Ok(Keys::new(Source::Snapshot(self), self.stored_keys()))
+    }
+
+    /// Returns an iterator over every value in the snapshot, like [Store::values].
+    pub fn values<V: DeserializeOwned>(&self) -> Result<Values<'_, B, V>> {
+        Ok(Values::new(self.iter::<IgnoredAny, V>()?))
+    }
+
+    pub(crate) fn store(&self) -> &'a Store<B> {
+        self.store
+    }
+
+    /// Returns whether a mapping was stored under `stored_key` when the snapshot was taken.
+    pub(crate) fn contains(&self, stored_key: &[u8]) -> bool {
+        self.keys.contains(stored_key)
+    }
+
+    /// Reads the record stored under `stored_key`, which `hash` locates, when the snapshot was
+    /// taken.
+    pub(crate) fn record(&self, hash: &str, stored_key: &[u8]) -> Result<Option<Record>> {
+        if !self.contains(stored_key) {
+            return Ok(None);
+        }
+        let record = self
+            .store
+            .backend
+            .get_pinned(hash, stored_key, self.sequence)
+            .map_err(|e| Error::from(e).for_key(hash))?;
+        match record {
+            Some(record) => Ok(Some(record)),
+            None => Err(Error::corrupt("Record kept for a snapshot is missing!").for_key(hash)),
+        }
+    }
+
+    fn stored_keys(&self) -> Vec<Vec<u8>> {
+        self.keys.iter().cloned().collect()
+    }
+}
+
+impl<B: Backend> Drop for Snapshot<'_, B> {
+    /// Releases the records and chunks the snapshot kept.
+    fn drop(&mut self) {
+        let _ = self.store.backend.unpin();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{KVStore, KeyValue, Operations, Options, Store};
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::Path;
+
+    fn options() -> Options {
+        Options {
+            chunk_size: 16,
+            ..Options::default()
+        }
+    }
+
+    fn retired_chunks(path: &str) -> usize {
+        match fs::read_dir(Path::new(path).join("chunks").join("retired")) {
+            Ok(entries) => entries.count(),
+            Err(_) => 0,
+        }
+    }
+
+    fn retired_records(path: &str) -> bool {
+        Path::new(path).join("retired").exists()
+    }
+
+    #[test]
+    fn later_writes_stay_invisible() {
+        let path = "./test-KV/snapshot1";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store: KVStore = Store::open(path, options()).unwrap();
+        for i in 0..5_u32 {
+            kv_store.put(&i, &vec![i; 40]).unwrap();
+        }
+        let expected: BTreeMap<u32, Vec<u32>> = (0..5).map(|i| (i, vec![i; 40])).collect();
+
+        let reader: KVStore = Store::open(path, options()).unwrap();
+        let snapshot = reader.snapshot().unwrap();
+        kv_store.take::<_, Vec<u32>>(&0_u32).unwrap();
+        kv_store.put(&1_u32, &vec![9_u32]).unwrap();
+        kv_store.put(&5_u32, &vec![5_u32]).unwrap();
+        assert!(retired_chunks(path) > 0);
+        assert!(retired_records(path));
+
+        assert_eq!(snapshot.len(), 5);
+        assert_eq!(snapshot.get(&0_u32).unwrap(), Some(vec![0_u32; 40]));
+        assert_eq!(snapshot.get(&1_u32).unwrap(), Some(vec![1_u32; 40]));
+        assert!(!snapshot.contains_key(&5_u32).unwrap());
+        let entries: BTreeMap<u32, Vec<u32>> =
+            snapshot.iter().unwrap().map(Result::unwrap).collect();
+        assert_eq!(entries, expected);
+        let mut keys: Vec<u32> = snapshot.keys().unwrap().map(Result::unwrap).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec![0, 1, 2, 3, 4]);
+        assert_eq!(snapshot.values::<Vec<u32>>().unwrap().count(), 5);
+
+        // The store itself moves on, and the retired chunks go with the last snapshot.
+        assert_eq!(reader.get(&1_u32).unwrap(), Some(vec![9_u32]));
+        drop(snapshot);
+        assert_eq!(retired_chunks(path), 0);
+        assert!(!retired_records(path));
+        assert_eq!(kv_store.get(&2_u32).unwrap(), Some(vec![2_u32; 40]));
+    }
+
+    #[test]
+    fn each_snapshot_sees_its_own_value() {
+        let path = "./test-KV/snapshot3";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store: KVStore = Store::open(path, options()).unwrap();
+        kv_store.put("key", &1_u32).unwrap();
+        let reader: KVStore = Store::open(path, options()).unwrap();
+        let first = reader.snapshot().unwrap();
+        kv_store.put("key", &2_u32).unwrap();
+        let second = reader.snapshot().unwrap();
+        kv_store.take::<_, u32>("key").unwrap();
+        kv_store.put("key", &3_u32).unwrap();
+
+        assert_eq!(first.get("key").unwrap(), Some(1_u32));
+        assert_eq!(second.get("key").unwrap(), Some(2_u32));
+        assert_eq!(kv_store.get("key").unwrap(), Some(3_u32));
+        drop(first);
+        assert_eq!(second.get("key").unwrap(), Some(2_u32));
+        drop(second);
+        assert!(!retired_records(path));
+    }
+
+    #[test]
+    fn chunks_are_deleted_without_snapshots() {
+        let path = "./test-KV/snapshot2";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store: KVStore = Store::open(path, options()).unwrap();
+        kv_store.put("large", &vec![1_u8; 100]).unwrap();
+        let snapshot = kv_store.snapshot().unwrap();
+        assert_eq!(snapshot.len(), 1);
+        drop(snapshot);
+
+        kv_store.take::<_, Vec<u8>>("large").unwrap();
+        assert_eq!(retired_chunks(path), 0);
+        assert!(kv_store.is_empty());
+        assert_eq!(KVStore::new(path).unwrap().size(), 0);
+    }
+}
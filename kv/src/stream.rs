@@ -0,0 +1,729 @@
+//! Streaming reads and writes of large values.
+//!
+//! A value longer than [crate::Options::chunk_size] bytes is not stored inside its [Record].
+//! It is split into chunks of that size, which the backend keeps apart from records, and the
+//! record holds a chunk list instead (all integers little-endian):
+//!
+//! ```text
+//! +--------------+------------+-------------+------+-----+------+
+//! | value length | chunk size | chunk count | id 1 | ... | id n |
+//! |     u64      |    u64     |     u32     |      |     |      |
+//! +--------------+------------+-------------+------+-----+------+
+//! ```
+//!
+//! where every id is prefixed with its length as a u8. Each chunk is compressed and encrypted on
+//! its own, so a [ValueReader] only ever holds one chunk in memory and a ranged read only loads
+//! the chunks it overlaps. A stored chunk looks like this:
+//!
+//! ```text
+//! +-------+-------+---------+
+//! | flags | crc32 | payload |
+//! |  u8   |  u32  |         |
+//! +-------+-------+---------+
+//! ```
+//!
+//! Chunks are written before the record that lists them and deleted after it, so a crash can
+//! leave unreferenced chunks behind but never a record whose chunks are missing. Chunks that are
+//! shared between values are described in [crate::dedup].
+
+use std::convert::TryInto;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::dedup::FLAG_SHARED;
+use crate::{serialize_key, Backend, Codec, Error, Record, Result, Store};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+/// The default for [crate::Options::chunk_size].
+pub(crate) const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Record flag of a record whose value is a chunk list.
+pub(crate) const FLAG_CHUNKED: u8 = 0b1000;
+
+const CHUNK_HEADER_LEN: usize = 5;
+
+/// Where the bytes of a stored value are.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum StoredValue {
+    /// The value is held by its record.
+    Inline(Vec<u8>),
+    /// The value is split into chunks.
+    Chunked(ChunkList),
+}
+
+impl StoredValue {
+    fn len(&self) -> u64 {
+        match self {
+            StoredValue::Inline(bytes) => bytes.len() as u64,
+            StoredValue::Chunked(list) => list.len,
+        }
+    }
+}
+
+/// The chunks a large value is split into.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ChunkList {
+    /// The length of the whole value.
+    len: u64,
+    /// The length of every chunk but the last.
+    chunk_size: u64,
+    ids: Vec<String>,
+    /// Whether the chunks are shared with other values and reference counted. This is marked in
+    /// the record flags rather than in the list itself.
+    shared: bool,
+}
+
+impl ChunkList {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(20 + self.ids.len() * 65);
+        out.extend_from_slice(&self.len.to_le_bytes());
+        out.extend_from_slice(&self.chunk_size.to_le_bytes());
+        out.extend_from_slice(&(self.ids.len() as u32).to_le_bytes());
+        for id in &self.ids {
+            out.push(id.len() as u8);
+            out.extend_from_slice(id.as_bytes());
+        }
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<ChunkList> {
+        let len = u64::from_le_bytes(bytes.get(0..8)?.try_into().ok()?);
+        let chunk_size = u64::from_le_bytes(bytes.get(8..16)?.try_into().ok()?);
+        let count = u32::from_le_bytes(bytes.get(16..20)?.try_into().ok()?);
+        // Every id takes at least its length byte, so a damaged count cannot reserve more than the
+        // list could hold.
+        if count as usize > bytes.len() - 20 {
+            return None;
+        }
+        let mut ids = Vec::with_capacity(count as usize);
+        let mut pos = 20;
+        for _ in 0..count {
+            let id_len = *bytes.get(pos)? as usize;
+            let id = bytes.get(pos + 1..pos + 1 + id_len)?;
+            ids.push(String::from_utf8(id.to_vec()).ok()?);
+            pos += 1 + id_len;
+        }
+        if chunk_size == 0 || len > chunk_size.checked_mul(ids.len() as u64)? {
+            return None;
+        }
+        Some(ChunkList {
+            len,
+            chunk_size,
+            ids,
+            shared: false,
+        })
+    }
+}
+
+impl<B: Backend> Store<B> {
+    /// Starts inserting a new key-value mapping whose value is written through the returned
+    /// [ValueWriter] as raw bytes, without holding all of it in memory.
+    ///
+    /// The mapping only becomes visible once [ValueWriter::finish] is called. If there is a
+    /// key-value mapping stored already with the same key, this returns an
+    /// [Error::AlreadyExists] error.
+    pub fn writer<K: serde::Serialize>(&mut self, key: K) -> Result<ValueWriter<'_, B>> {
+        let (hash, stored_key) = self.locate(&serialize_key(&key)?);
+        if self.exists(&hash, &stored_key)? {
+            return Err(Error::already_exists().for_key(&hash));
+        }
+        Ok(ValueWriter::new(self, hash, stored_key))
+    }
+
+    /// Inserts a new key-value mapping whose value is everything `reader` yields, and returns the
+    /// length of the value.
+    pub fn insert_from<K: serde::Serialize, R: Read>(
+        &mut self,
+        key: K,
+        mut reader: R,
+    ) -> Result<u64> {
+        let mut writer = self.writer(key)?;
+        std::io::copy(&mut reader, &mut writer)?;
+        writer.finish()
+    }
+
+    /// Returns a [ValueReader] over the raw bytes of the value stored under `key`.
+    ///
+    /// For a value inserted through [crate::Operations::insert] these are its serialized form.
+    pub fn reader<K: serde::Serialize>(&self, key: K) -> Result<ValueReader<'_, B>> {
+        let (hash, stored_key) = self.locate(&serialize_key(&key)?);
+        let value = match self.get_stored(&hash, &stored_key)? {
+            None => return Err(Error::not_found().for_key(&hash)),
+            Some((_, value)) => value,
+        };
+        Ok(ValueReader {
+            store: self,
+            value,
+            pos: 0,
+            chunk: None,
+        })
+    }
+
+    /// Returns the bytes in `range` of the value stored under `key`, loading only the chunks the
+    /// range overlaps. A range reaching past the end of the value is cut short.
+    pub fn read_range<K: serde::Serialize>(&self, key: K, range: Range<u64>) -> Result<Vec<u8>> {
+        let mut reader = self.reader(key)?;
+        let start = range.start.min(reader.len());
+        let end = range.end.clamp(start, reader.len());
+        let mut bytes = vec![0; (end - start) as usize];
+        reader.seek(SeekFrom::Start(start))?;
+        reader.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Removes the key-value mapping stored under `key` without reading its value back.
+    ///
+    /// Returns whether there was a mapping to remove.
+    pub fn delete<K: serde::Serialize>(&mut self, key: K) -> Result<bool> {
+        self.delete_bytes(&serialize_key(&key)?)
+    }
+
+    /// Stores `value` under the key stored as `stored_key` in a record of the given version,
+    /// splitting it into chunks if it is too long to be held by the record. Does not check for an
+    /// existing mapping.
+    pub(crate) fn put_value(
+        &mut self,
+        hash: &str,
+        stored_key: Vec<u8>,
+        value: &[u8],
+        version: u64,
+    ) -> Result<()> {
+        let mut writer = ValueWriter::new(self, hash.to_string(), stored_key);
+        writer.version = version;
+        let written = writer.write_all(value).map_err(Error::from);
+        written
+            .and_then(|()| writer.commit())
+            .map(|_| ())
+            .map_err(|e| e.for_key(hash))
+    }
+
+    /// Writes the chunks of `value` like [Store::put_value], but returns the record that lists
+    /// them instead of storing it. The record marks the value as written with `codec` and has the
+    /// given version.
+    ///
+    /// The caller owns the chunks from then on and has to delete them if the record is never
+    /// stored.
+    pub(crate) fn prepare_value(
+        &mut self,
+        hash: &str,
+        stored_key: Vec<u8>,
+        value: &[u8],
+        codec: Codec,
+        version: u64,
+    ) -> Result<Record> {
+        let mut writer = ValueWriter::new(self, hash.to_string(), stored_key);
+        writer.codec = codec;
+        writer.version = version;
+        let written = writer.write_all(value).map_err(Error::from);
+        let record = written
+            .and_then(|()| writer.record())
+            .map_err(|e| e.for_key(hash))?;
+        writer.finished = true;
+        Ok(record)
+    }
+
+    /// Opens the value of `record`, which is either the value itself or its chunk list.
+    pub(crate) fn stored_value(&self, hash: &str, record: &Record) -> Result<StoredValue> {
+        let bytes = self.open_value(hash.as_bytes(), record.value.clone(), record.flags)?;
+        if record.flags & FLAG_CHUNKED == 0 {
+            return Ok(StoredValue::Inline(bytes));
+        }
+        match ChunkList::decode(&bytes) {
+            Some(mut list) => {
+                list.shared = record.flags & FLAG_SHARED != 0;
+                Ok(StoredValue::Chunked(list))
+            }
+            None => Err(Error::corrupt("Stored chunk list is corrupt!")),
+        }
+    }
+
+    /// Returns all bytes of a stored value, reading every chunk of a chunked one.
+    pub(crate) fn value_bytes(&self, stored: &StoredValue) -> Result<Vec<u8>> {
+        match stored {
+            StoredValue::Inline(bytes) => Ok(bytes.clone()),
+            StoredValue::Chunked(list) => {
+                let mut bytes = Vec::with_capacity(list.len as usize);
+                for id in &list.ids {
+                    bytes.extend_from_slice(&self.read_chunk(id)?);
+                }
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Deletes the chunks of a value whose record was deleted, or releases them if they are
+    /// shared.
+    pub(crate) fn delete_chunks(&mut self, stored: &StoredValue) -> Result<()> {
+        if let StoredValue::Chunked(list) = stored {
+            for id in &list.ids {
+                if list.shared {
+                    self.release_chunk(id)?;
+                } else {
+                    self.backend.delete_chunk(id)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write_chunk(&mut self, id: &str, chunk: Vec<u8>) -> Result<()> {
+        let (payload, flags) = self.seal_value(id.as_bytes(), chunk)?;
+        let mut stored = Vec::with_capacity(CHUNK_HEADER_LEN + payload.len());
+        stored.push(flags);
+        stored.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        stored.extend_from_slice(&payload);
+        Ok(self.backend.put_chunk(id, &stored)?)
+    }
+
+    fn read_chunk(&self, id: &str) -> Result<Vec<u8>> {
+        let mut stored = match self.backend.get_chunk(id)? {
+            Some(stored) if stored.len() >= CHUNK_HEADER_LEN => stored,
+            _ => {
+                return Err(Error::corrupt(format!(
+                    "Chunk {} of a stored value is missing!",
+                    id
+                )))
+            }
+        };
+        let payload = stored.split_off(CHUNK_HEADER_LEN);
+        if crc32fast::hash(&payload).to_le_bytes() != stored[1..CHUNK_HEADER_LEN] {
+            return Err(Error::corrupt(format!(
+                "Chunk {} of a stored value is corrupt!",
+                id
+            )));
+        }
+        self.open_value(id.as_bytes(), payload, stored[0])
+    }
+}
+
+/// Returns the id of chunk number `index` of a value written under `stored_key` by the write
+/// identified by `nonce`.
+fn chunk_id(stored_key: &[u8], nonce: &[u8], index: usize) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(stored_key);
+    hasher.input(nonce);
+    hasher.input(&(index as u64).to_le_bytes());
+    hasher.result_str()
+}
+
+/// Returns bytes that differ between any two writes, so that the chunks of a new value never
+/// replace the chunks of the value it overwrites before the record is replaced.
+fn write_nonce() -> Vec<u8> {
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let mut nonce = nanos.to_le_bytes().to_vec();
+    nonce.extend_from_slice(&WRITES.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    nonce.extend_from_slice(&std::process::id().to_le_bytes());
+    nonce
+}
+
+/// A handle that writes the value of a new key-value mapping, returned by [Store::writer].
+///
+/// Bytes are buffered until they fill a chunk, which is then written out. Dropping the writer
+/// without calling [ValueWriter::finish] discards everything written so far.
+#[derive(Debug)]
+pub struct ValueWriter<'a, B: Backend> {
+    store: &'a mut Store<B>,
+    hash: String,
+    stored_key: Vec<u8>,
+    /// The codec the record marks the value as written with.
+    codec: Codec,
+    /// The version of the record.
+    version: u64,
+    /// What tells the chunks of this write apart from those of earlier writes of the key.
+    nonce: Vec<u8>,
+    /// The bytes that do not fill a chunk yet.
+    buffer: Vec<u8>,
+    /// The ids of the chunks written so far.
+    ids: Vec<String>,
+    len: u64,
+    finished: bool,
+}
+
+impl<'a, B: Backend> ValueWriter<'a, B> {
+    fn new(store: &'a mut Store<B>, hash: String, stored_key: Vec<u8>) -> ValueWriter<'a, B> {
+        let codec = store.codec;
+        ValueWriter {
+            store,
+            hash,
+            stored_key,
+            codec,
+            version: 1,
+            nonce: write_nonce(),
+            buffer: Vec::new(),
+            ids: Vec::new(),
+            len: 0,
+            finished: false,
+        }
+    }
+
+    /// Writes out whatever is still buffered and stores the mapping. Returns the length of the
+    /// value.
+    ///
+    /// If another mapping was stored under the same key since the writer was started, the
+    /// written chunks are deleted again and this returns an [Error::AlreadyExists] error.
+    pub fn finish(mut self) -> Result<u64> {
+        let mut record = self.record()?;
+        let (hash, stored_key) = (&self.hash, &self.stored_key);
+        self.store.locked(|store| {
+            if store.exists(hash, stored_key)? {
+                return Err(Error::already_exists().for_key(hash));
+            }
+            record.version = store.next_version(None)?;
+            store.backend.put(hash, &record)?;
+            store.track_key(stored_key, true);
+            *store.size_mut() += 1;
+            Ok(())
+        })?;
+        self.finished = true;
+        Ok(self.len)
+    }
+
+    fn commit(&mut self) -> Result<u64> {
+        let record = self.record()?;
+        self.store.backend.put(&self.hash, &record)?;
+        self.finished = true;
+        Ok(self.len)
+    }
+
+    /// Writes out whatever is still buffered and returns the record of the value.
+    fn record(&mut self) -> Result<Record> {
+        // A deduplicated value is always stored as shared chunks, however short it is.
+        let shared = self.store.deduplicate;
+        let (value, flags) = if self.ids.is_empty() && (!shared || self.buffer.is_empty()) {
+            (std::mem::take(&mut self.buffer), 0)
+        } else {
+            if !self.buffer.is_empty() {
+                let rest = std::mem::take(&mut self.buffer);
+                self.write_chunk(rest)?;
+            }
+            let list = ChunkList {
+                len: self.len,
+                chunk_size: self.store.chunk_size as u64,
+                ids: self.ids.clone(),
+                shared,
+            };
+            let flags = if shared {
+                FLAG_CHUNKED | FLAG_SHARED
+            } else {
+                FLAG_CHUNKED
+            };
+            (list.encode(), flags)
+        };
+
+        let (value, sealed_flags) = self.store.seal_value(self.hash.as_bytes(), value)?;
+        let mut record = Record::new(self.stored_key.clone(), value, self.codec.id());
+        record.flags = flags | sealed_flags;
+        record.version = self.version;
+        Ok(record)
+    }
+
+    fn write_chunk(&mut self, chunk: Vec<u8>) -> Result<()> {
+        let id = if self.store.deduplicate {
+            let id = self.store.content_id(&chunk);
+            self.store.share_chunk(&id, chunk)?;
+            id
+        } else {
+            let id = chunk_id(&self.stored_key, &self.nonce, self.ids.len());
+            self.store.write_chunk(&id, chunk)?;
+            id
+        };
+        self.ids.push(id);
+        Ok(())
+    }
+}
+
+impl<B: Backend> Write for ValueWriter<'_, B> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        self.len += buf.len() as u64;
+        // A value exactly one chunk long is still held by its record, so a chunk is only written
+        // once more bytes follow it.
+        let chunk_size = self.store.chunk_size;
+        while self.buffer.len() > chunk_size {
+            let rest = self.buffer.split_off(chunk_size);
+            let chunk = std::mem::replace(&mut self.buffer, rest);
+            self.write_chunk(chunk)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<B: Backend> Drop for ValueWriter<'_, B> {
+    /// Deletes (or releases) the chunks of a value that was never finished.
+    fn drop(&mut self) {
+        if !self.finished {
+            for id in std::mem::take(&mut self.ids) {
+                if self.store.deduplicate {
+                    let _ = self.store.release_chunk(&id);
+                } else {
+                    let _ = self.store.backend.delete_chunk(&id);
+                }
+            }
+        }
+    }
+}
+
+/// A handle that reads the value of a key-value mapping, returned by [Store::reader].
+///
+/// Only the chunk that is currently read from is held in memory, and seeking to another
+/// position only loads the chunk there.
+#[derive(Debug)]
+pub struct ValueReader<'a, B: Backend> {
+    store: &'a Store<B>,
+    value: StoredValue,
+    pos: u64,
+    /// The index and bytes of the chunk read last.
+    chunk: Option<(usize, Vec<u8>)>,
+}
+
+impl<B: Backend> ValueReader<'_, B> {
+    /// Returns the length of the value in bytes.
+    pub fn len(&self) -> u64 {
+        self.value.len()
+    }
+
+    /// Returns whether the value is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<B: Backend> Read for ValueReader<'_, B> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.len() {
+            return Ok(0);
+        }
+        let (bytes, offset) = match &self.value {
+            StoredValue::Inline(bytes) => (bytes, self.pos as usize),
+            StoredValue::Chunked(list) => {
+                let index = (self.pos / list.chunk_size) as usize;
+                if self.chunk.as_ref().map(|(i, _)| *i) != Some(index) {
+                    let id = list
+                        .ids
+                        .get(index)
+                        .ok_or_else(|| Error::corrupt("Stored chunk list is corrupt!"))?;
+                    self.chunk = Some((index, self.store.read_chunk(id)?));
+                }
+                let offset = (self.pos - index as u64 * list.chunk_size) as usize;
+                (&self.chunk.as_ref().unwrap().1, offset)
+            }
+        };
+        let available = bytes.get(offset..).unwrap_or_default();
+        if available.is_empty() {
+            return Err(Error::corrupt("Stored value is shorter than its chunk list says!").into());
+        }
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<B: Backend> Seek for ValueReader<'_, B> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len().checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        match target {
+            Some(target) => {
+                self.pos = target;
+                Ok(target)
+            }
+            None => Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "Cannot seek before the start of the value!",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChunkList, DEFAULT_CHUNK_SIZE};
+    use crate::{
+        BitcaskStore, Compression, EncryptionKey, Error, KVStore, MemoryStore, Operations, Options,
+        Store,
+    };
+    use std::fs;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use walkdir::WalkDir;
+
+    fn options(chunk_size: usize) -> Options {
+        Options {
+            chunk_size,
+            ..Options::default()
+        }
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn chunk_files(path: &str) -> usize {
+        WalkDir::new(path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().extension().is_some_and(|x| x == "chunk"))
+            .count()
+    }
+
+    #[test]
+    fn large_values_are_streamed_in_chunks() {
+        let path = "./test-KV/stream1";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::open(path, options(1000)).unwrap();
+        let value = pattern(10_500);
+        assert_eq!(kv_store.insert_from("big", &value[..]).unwrap(), 10_500);
+        assert_eq!(chunk_files(path), 11);
+        assert!(kv_store.insert_from("big", &b"again"[..]).is_err());
+
+        let mut kv_store = KVStore::open(path, options(DEFAULT_CHUNK_SIZE)).unwrap();
+        assert_eq!(kv_store.size(), 1);
+        let mut reader = kv_store.reader("big").unwrap();
+        assert_eq!(reader.len(), 10_500);
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, value);
+
+        assert!(kv_store.delete("big").unwrap());
+        assert!(!kv_store.delete("big").unwrap());
+        assert_eq!(kv_store.size(), 0);
+        assert_eq!(chunk_files(path), 0);
+    }
+
+    #[test]
+    fn ranged_reads_fetch_a_slice() {
+        let key = EncryptionKey::from_bytes([5; 32]);
+        let options = Options {
+            compression: Compression::Lz4,
+            compression_threshold: 0,
+            encryption_key: Some(key),
+            ..options(64)
+        };
+        let mut kv_store: MemoryStore = Store::open("", options).unwrap();
+        let value = pattern(1000);
+        let mut writer = kv_store.writer(7_u32).unwrap();
+        for piece in value.chunks(100) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.finish().unwrap();
+
+        assert_eq!(
+            kv_store.read_range(7_u32, 60..200).unwrap(),
+            &value[60..200]
+        );
+        assert_eq!(
+            kv_store.read_range(7_u32, 990..2000).unwrap(),
+            &value[990..]
+        );
+        assert!(kv_store.read_range(7_u32, 1200..1300).unwrap().is_empty());
+
+        let mut reader = kv_store.reader(7_u32).unwrap();
+        reader.seek(SeekFrom::End(-10)).unwrap();
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, &value[990..]);
+        assert!(reader.seek(SeekFrom::Current(-2000)).is_err());
+    }
+
+    #[test]
+    fn typed_values_are_chunked_transparently() {
+        let path = "./test-KV/stream2";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = BitcaskStore::open(path, options(128)).unwrap();
+        let value: Vec<u32> = (0..500).collect();
+        kv_store.insert("numbers", value.clone()).unwrap();
+        kv_store.insert("small", 1_u8).unwrap();
+        kv_store.backend_mut().compact().unwrap();
+
+        let mut kv_store = BitcaskStore::open(path, options(128)).unwrap();
+        assert_eq!(kv_store.size(), 2);
+        assert_eq!(kv_store.lookup::<&str, Vec<u32>>("numbers").unwrap(), value);
+        let json = serde_json::to_vec(&value).unwrap();
+        assert_eq!(kv_store.read_range("numbers", 0..4).unwrap(), &json[0..4]);
+        assert_eq!(kv_store.remove::<&str, Vec<u32>>("numbers").unwrap(), value);
+        assert!(kv_store.reader("numbers").is_err());
+    }
+
+    #[test]
+    fn overwritten_values_drop_their_chunks() {
+        let path = "./test-KV/stream4";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::open(path, options(10)).unwrap();
+        kv_store.put_bytes(b"blob", &pattern(95)).unwrap();
+        assert_eq!(chunk_files(path), 10);
+        kv_store.put_bytes(b"blob", &pattern(35)).unwrap();
+        assert_eq!(chunk_files(path), 4);
+        assert_eq!(kv_store.get_bytes(b"blob").unwrap().unwrap(), pattern(35));
+        kv_store.put_bytes(b"blob", b"tiny").unwrap();
+        assert_eq!(chunk_files(path), 0);
+        assert_eq!(kv_store.size(), 1);
+    }
+
+    #[test]
+    fn unfinished_writes_leave_nothing_behind() {
+        let path = "./test-KV/stream3";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::open(path, options(16)).unwrap();
+        let mut writer = kv_store.writer("partial").unwrap();
+        writer.write_all(&pattern(100)).unwrap();
+        assert!(chunk_files(path) > 0);
+        drop(writer);
+
+        assert_eq!(chunk_files(path), 0);
+        assert_eq!(kv_store.size(), 0);
+        assert!(kv_store.reader("partial").is_err());
+    }
+
+    #[test]
+    fn writes_lose_to_an_earlier_insert() {
+        let path = "./test-KV/stream5";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::open(path, options(16)).unwrap();
+        let mut other = KVStore::open(path, options(16)).unwrap();
+        let mut writer = kv_store.writer("k").unwrap();
+        writer.write_all(&pattern(100)).unwrap();
+        let written = chunk_files(path);
+        other.insert_bytes(b"\"k\"", &pattern(40)).unwrap();
+        let others = chunk_files(path) - written;
+
+        let e = writer.finish().unwrap_err();
+        assert!(matches!(e, Error::AlreadyExists { .. }), "{}", e);
+        assert_eq!(chunk_files(path), others);
+        assert_eq!(kv_store.size(), 1);
+        assert_eq!(other.get_bytes(b"\"k\"").unwrap().unwrap(), pattern(40));
+    }
+
+    #[test]
+    fn damaged_chunk_lists_are_rejected() {
+        let list = ChunkList {
+            len: 100,
+            chunk_size: 64,
+            ids: vec![String::from("a"), String::from("b")],
+            shared: false,
+        };
+        let bytes = list.encode();
+        assert_eq!(ChunkList::decode(&bytes), Some(list));
+
+        let mut huge_count = bytes.clone();
+        huge_count[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut huge_chunks = bytes.clone();
+        huge_chunks[0..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        huge_chunks[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(ChunkList::decode(&huge_count), None);
+        assert_eq!(ChunkList::decode(&huge_chunks), None);
+    }
+}
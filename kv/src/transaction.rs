@@ -0,0 +1,240 @@
+//! Read-modify-write transactions with optimistic concurrency control.
+//!
+//! A [Transaction] reads keys straight from the store and remembers the record every key held
+//! when it was first read, or that it held none. Writes are only collected, in a [WriteBatch].
+//! [Transaction::commit] then takes the store lock, checks that every key read still holds the
+//! record it was read with, and applies the batch before releasing the lock. If any key changed
+//! in the meantime nothing is written and the commit fails with [Error::Conflict], after which
+//! the caller can start a new transaction and try again.
+//!
+//! A [crate::DirBackend] locks a file in the store directory, so transactions are isolated from
+//! every other handle on the same directory, in this process or another one. Backends without a
+//! lock of their own, such as [crate::MemoryBackend], are only ever changed through one handle,
+//! which a transaction borrows mutably.
+
+use std::collections::HashMap;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{decode_value, serialize_key, Backend, Error, Record, Result, Store, WriteBatch};
+
+impl<B: Backend> Store<B> {
+    /// Starts a transaction on the store.
+    ///
+    /// ```
+    /// use kv::{KeyValue, MemoryStore, Options, Store};
+    ///
+    /// let mut store: MemoryStore = Store::open("", Options::default()).unwrap();
+    /// store.put("alice", &10).unwrap();
+    /// store.put("bob", &0).unwrap();
+    ///
+    /// let mut transaction = store.transaction();
+    /// let alice: i32 = transaction.get("alice").unwrap().unwrap();
+    /// let bob: i32 = transaction.get("bob").unwrap().unwrap();
+    /// transaction.put("alice", &(alice - 3)).unwrap();
+    /// transaction.put("bob", &(bob + 3)).unwrap();
+    /// transaction.commit().unwrap();
+    ///
+    /// assert_eq!(store.get("bob").unwrap(), Some(3));
+    /// ```
+    pub fn transaction(&mut self) -> Transaction<'_, B> {
+        let batch = self.batch();
+        Transaction {
+            store: self,
+            reads: HashMap::new(),
+            writes: HashMap::new(),
+            batch,
+        }
+    }
+}
+
+/// A set of reads and writes that is committed only if none of the keys read has changed since,
+/// returned by [Store::transaction].
+///
+/// Reads see the writes made earlier in the same transaction, and a key read twice is read from
+/// the store only once. Dropping a transaction without committing it discards its writes.
+///
+/// Only keys that are read are validated: a key that is written without being read is written
+/// whatever it holds at commit time.
+#[derive(Debug)]
+pub struct Transaction<'a, B: Backend> {
+    store: &'a mut Store<B>,
+    /// The hash and the record, if any, of every key read, by stored key.
+    reads: HashMap<Vec<u8>, (String, Option<Record>)>,
+    /// The value bytes of every key written, or `None` for a deleted key, by serialized key.
+    writes: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    batch: WriteBatch,
+}
+
+impl<B: Backend> Transaction<'_, B> {
+    /// Looks up the value stored under `key`, or written to it by this transaction, and
+    /// deserializes it as `V`.
+    pub fn get<K, V>(&mut self, key: &K) -> Result<Option<V>>
+    where
+        K: Serialize + ?Sized,
+        V: DeserializeOwned,
+    {
+        let key = serialize_key(key)?;
+        let (hash, stored_key) = self.store.locate(&key);
+        if let Some(written) = self.writes.get(&key) {
+            return match written {
+                None => Ok(None),
+                Some(bytes) => decode_value(self.store.codec.id(), bytes, &hash).map(Some),
+            };
+        }
+
+        if !self.reads.contains_key(&stored_key) {
+            let record = self.store.get_record(&hash, &stored_key)?;
+            self.reads
+                .insert(stored_key.clone(), (hash.clone(), record));
+        }
+        match &self.reads[&stored_key] {
+            (_, None) => Ok(None),
+            (_, Some(record)) => self.store.record_value(&hash, record).map(Some),
+        }
+    }
+
+    /// Stores `value` under `key` when the transaction commits, replacing any value stored there
+    /// already.
+    pub fn put<K, V>(&mut self, key: &K, value: &V) -> Result<()>
+    where
+        K: Serialize + ?Sized,
+        V: Serialize + ?Sized,
+    {
+        let key = serialize_key(key)?;
+        let value = self.store.codec.encode(value)?;
+        self.batch.put_bytes(&key, &value);
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    /// Removes the mapping stored under `key`, if there is one, when the transaction commits.
+    pub fn delete<K: Serialize + ?Sized>(&mut self, key: &K) -> Result<()> {
+        let key = serialize_key(key)?;
+        self.batch.delete_bytes(&key);
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// Makes every write of the transaction, all or nothing, provided that no key it read has
+    /// changed since. Fails with [Error::Conflict] for the first changed key found otherwise.
+    pub fn commit(self) -> Result<()> {
+        let Transaction {
+            store,
+            reads,
+            batch,
+            ..
+        } = self;
+        store.locked(|store| {
+            validate(store, &reads)?;
+            store.apply(batch)
+        })
+    }
+}
+
+/// Checks that every key in `reads` still holds the record it was read with.
+fn validate<B: Backend>(
+    store: &Store<B>,
+    reads: &HashMap<Vec<u8>, (String, Option<Record>)>,
+) -> Result<()> {
+    for (stored_key, (hash, record)) in reads {
+        if store.get_record(hash, stored_key)? != *record {
+            return Err(Error::conflict().for_key(hash));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Error, KVStore, KeyValue, MemoryStore, Operations, Options, Store};
+    use std::fs;
+    use std::thread;
+
+    #[test]
+    fn reads_see_earlier_writes() {
+        let mut kv_store: MemoryStore = Store::open("", Options::default()).unwrap();
+        kv_store.put("a", &1).unwrap();
+        kv_store.put("b", &2).unwrap();
+
+        let mut transaction = kv_store.transaction();
+        assert_eq!(transaction.get("a").unwrap(), Some(1));
+        transaction.put("a", &10).unwrap();
+        transaction.delete("b").unwrap();
+        transaction.put("c", &3).unwrap();
+        assert_eq!(transaction.get("a").unwrap(), Some(10));
+        assert_eq!(transaction.get::<_, i32>("b").unwrap(), None);
+        assert_eq!(transaction.get("c").unwrap(), Some(3));
+        transaction.commit().unwrap();
+
+        assert_eq!(kv_store.len(), 2);
+        assert_eq!(kv_store.get("a").unwrap(), Some(10));
+        assert_eq!(kv_store.get::<_, i32>("b").unwrap(), None);
+
+        let mut transaction = kv_store.transaction();
+        transaction.put("d", &4).unwrap();
+        drop(transaction);
+        assert!(!kv_store.contains_key("d").unwrap());
+    }
+
+    #[test]
+    fn changed_reads_fail_the_commit() {
+        let path = "./test-KV/transaction1";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        kv_store.put("balance", &100).unwrap();
+        let mut other = KVStore::new(path).unwrap();
+
+        let mut transaction = kv_store.transaction();
+        let balance: i32 = transaction.get("balance").unwrap().unwrap();
+        assert_eq!(transaction.get::<_, i32>("audit").unwrap(), None);
+        transaction.put("balance", &(balance - 30)).unwrap();
+        transaction.put("audit", "withdrew 30").unwrap();
+        other.put("balance", &50).unwrap();
+        let e = transaction.commit().unwrap_err();
+        assert!(matches!(e, Error::Conflict { .. }), "{}", e);
+        assert_eq!(kv_store.get("balance").unwrap(), Some(50));
+        assert!(!kv_store.contains_key("audit").unwrap());
+
+        // A key that was missing when it was read conflicts once it is inserted.
+        let mut transaction = kv_store.transaction();
+        assert_eq!(transaction.get::<_, i32>("audit").unwrap(), None);
+        transaction.put("audit", "checked").unwrap();
+        other.put("audit", &String::from("taken")).unwrap();
+        assert!(matches!(transaction.commit(), Err(Error::Conflict { .. })));
+        assert_eq!(kv_store.get("audit").unwrap(), Some(String::from("taken")));
+    }
+
+    #[test]
+    fn concurrent_increments_are_not_lost() {
+        let path = "./test-KV/transaction2";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        kv_store.put("counter", &0_u32).unwrap();
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                thread::spawn(move || {
+                    let mut kv_store = KVStore::new(path).unwrap();
+                    for _ in 0..25 {
+                        loop {
+                            let mut transaction = kv_store.transaction();
+                            let counter: u32 = transaction.get("counter").unwrap().unwrap();
+                            transaction.put("counter", &(counter + 1)).unwrap();
+                            match transaction.commit() {
+                                Ok(()) => break,
+                                Err(Error::Conflict { .. }) => continue,
+                                Err(e) => panic!("{}", e),
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(kv_store.get("counter").unwrap(), Some(100_u32));
+    }
+}
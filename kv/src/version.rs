@@ -0,0 +1,291 @@
+//! Versioned lookups and conditional writes.
+//!
+//! Every record carries a version: 1 when its key is inserted, one more on every write after
+//! that. [Store::lookup_versioned] returns the version along with the value, and the conditional
+//! writes below only go through if the key still holds what the caller expects, failing with
+//! [Error::Conflict] otherwise. The check and the write happen under the backend lock, so no
+//! other handle on the same storage can change the key in between.
+//!
+//! A removed key has no version, which the conditional writes take as version 0. Versions are
+//! never reused, though: the backend keeps the highest version any removed key had (see
+//! [Backend::removed_version]), and every key that is inserted starts above it, so a key that
+//! comes back carries on from where it was. The version is kept before the record is removed,
+//! so a crash in between cannot lose it either.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{serialize_key, Backend, Error, Record, Result, Store};
+
+impl<B: Backend> Store<B> {
+    /// Returns the value stored under `key` together with its version, or `None` if there is
+    /// none.
+    pub fn lookup_versioned<K, V>(&self, key: &K) -> Result<Option<(V, u64)>>
+    where
+        K: Serialize + ?Sized,
+        V: DeserializeOwned,
+    {
+        let (hash, stored_key) = self.locate(&serialize_key(key)?);
+        match self.get_record(&hash, &stored_key)? {
+            None => Ok(None),
+            Some(record) => {
+                let value = self.record_value(&hash, &record)?;
+                Ok(Some((value, record.version)))
+            }
+        }
+    }
+
+    /// Stores `value` under `key` if the version stored there is `version`, and returns the new
+    /// version. A `version` of 0 stores the value only if there is none yet.
+    ///
+    /// ```
+    /// use kv::{Error, MemoryStore, Options, Store};
+    ///
+    /// let mut store: MemoryStore = Store::open("", Options::default()).unwrap();
+    /// assert_eq!(store.put_if_version("stock", &10, 0).unwrap(), 1);
+    ///
+    /// let (stock, version) = store.lookup_versioned::<_, i32>("stock").unwrap().unwrap();
+    /// assert_eq!(store.put_if_version("stock", &(stock - 1), version).unwrap(), 2);
+    /// // A second update based on the same read is refused.
+    /// let e = store.put_if_version("stock", &(stock - 1), version).unwrap_err();
+    /// assert!(matches!(e, Error::Conflict { .. }));
+    /// ```
+    pub fn put_if_version<K, V>(&mut self, key: &K, value: &V, version: u64) -> Result<u64>
+    where
+        K: Serialize + ?Sized,
+        V: Serialize + ?Sized,
+    {
+        let (hash, stored_key) = self.locate(&serialize_key(key)?);
+        let value = self.codec.encode(value)?;
+        self.locked(|store| {
+            let old = store.get_record(&hash, &stored_key)?;
+            if old.as_ref().map_or(0, |old| old.version) != version {
+                return Err(Error::conflict().for_key(&hash));
+            }
+            store.write_value(&hash, stored_key, old, &value)
+        })
+    }
+
+    /// Stores `new` under `key` if the value stored there is `expected`, and returns the new
+    /// version.
+    ///
+    /// The stored value is compared after deserializing it as a `V`, so values written with
+    /// another codec compare as equal too. If there is no value, or a different one, this
+    /// returns an [Error::Conflict] error.
+    pub fn compare_and_swap<K, V>(&mut self, key: &K, expected: &V, new: &V) -> Result<u64>
+    where
+        K: Serialize + ?Sized,
+        V: Serialize + DeserializeOwned + PartialEq,
+    {
+        let (hash, stored_key) = self.locate(&serialize_key(key)?);
+        let new = self.codec.encode(new)?;
+        self.locked(|store| {
+            let old = match store.get_record(&hash, &stored_key)? {
+                None => return Err(Error::conflict().for_key(&hash)),
+                Some(old) => old,
+            };
+            if store.record_value::<V>(&hash, &old)? != *expected {
+                return Err(Error::conflict().for_key(&hash));
+            }
+            store.write_value(&hash, stored_key, Some(old), &new)
+        })
+    }
+
+    /// Removes the mapping stored under `key` if its version is `version`. A `version` of 0
+    /// succeeds, without removing anything, only if there is no mapping.
+    pub fn remove_if_version<K>(&mut self, key: &K, version: u64) -> Result<()>
+    where
+        K: Serialize + ?Sized,
+    {
+        let (hash, stored_key) = self.locate(&serialize_key(key)?);
+        self.locked(|store| match store.get_record(&hash, &stored_key)? {
+            None if version == 0 => Ok(()),
+            Some(old) if old.version == version => store.delete_record(&hash, old),
+            _ => Err(Error::conflict().for_key(&hash)),
+        })
+    }
+
+    /// Returns the version of a record stored in place of `old`, or of a newly inserted one if
+    /// there is no `old`.
+    pub(crate) fn next_version(&self, old: Option<&Record>) -> Result<u64> {
+        match old {
+            Some(old) => Ok(old.version + 1),
+            None => Ok(self.backend.removed_version()? + 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{BitcaskStore, Error, KVStore, KeyValue, MemoryStore, Operations, Options, Store};
+    use std::fs;
+    use std::path::Path;
+    use std::thread;
+
+    fn is_conflict<T>(result: crate::Result<T>) -> bool {
+        matches!(result, Err(Error::Conflict { .. }))
+    }
+
+    #[test]
+    fn every_write_increments_the_version() {
+        let path = "./test-KV/version1";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        kv_store.put_new("k", &1).unwrap();
+        assert_eq!(kv_store.lookup_versioned("k").unwrap(), Some((1, 1)));
+        kv_store.put("k", &2).unwrap();
+        let mut batch = kv_store.batch();
+        batch.put("k", &3).unwrap();
+        batch.put("k", &4).unwrap();
+        kv_store.apply(batch).unwrap();
+        assert_eq!(kv_store.lookup_versioned("k").unwrap(), Some((4, 4)));
+
+        let kv_store = KVStore::new(path).unwrap();
+        assert_eq!(kv_store.lookup_versioned("k").unwrap(), Some((4, 4)));
+        assert_eq!(
+            kv_store.lookup_versioned::<_, i32>("missing").unwrap(),
+            None
+        );
+
+        let path = "./test-KV/version2";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = BitcaskStore::open(path, Options::default()).unwrap();
+        kv_store.put("k", &1).unwrap();
+        kv_store.put("k", &2).unwrap();
+        drop(kv_store);
+        let kv_store = BitcaskStore::open(path, Options::default()).unwrap();
+        assert_eq!(kv_store.lookup_versioned("k").unwrap(), Some((2, 2)));
+    }
+
+    #[test]
+    fn conditional_writes_check_the_current_state() {
+        let mut kv_store: MemoryStore = Store::open("", Options::default()).unwrap();
+        assert_eq!(kv_store.put_if_version("k", "a", 0).unwrap(), 1);
+        assert!(is_conflict(kv_store.put_if_version("k", "b", 0)));
+        assert!(is_conflict(kv_store.put_if_version("k", "b", 2)));
+        assert_eq!(kv_store.put_if_version("k", "b", 1).unwrap(), 2);
+
+        let a = String::from("a");
+        let b = String::from("b");
+        let c = String::from("c");
+        assert!(is_conflict(kv_store.compare_and_swap("k", &a, &c)));
+        assert!(is_conflict(kv_store.compare_and_swap("missing", &a, &c)));
+        assert_eq!(kv_store.compare_and_swap("k", &b, &c).unwrap(), 3);
+        assert_eq!(kv_store.lookup_versioned("k").unwrap(), Some((c, 3)));
+
+        assert!(is_conflict(kv_store.remove_if_version("k", 2)));
+        assert!(is_conflict(kv_store.remove_if_version("missing", 1)));
+        kv_store.remove_if_version("missing", 0).unwrap();
+        kv_store.remove_if_version("k", 3).unwrap();
+        assert!(kv_store.is_empty());
+        assert_eq!(kv_store.put_if_version("k", "d", 0).unwrap(), 4);
+    }
+
+    #[test]
+    fn versions_of_removed_keys_are_not_reused() {
+        let path = "./test-KV/version4";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        assert_eq!(kv_store.put_if_version("k", "a", 0).unwrap(), 1);
+        let (_, stale) = kv_store
+            .lookup_versioned::<_, String>("k")
+            .unwrap()
+            .unwrap();
+        kv_store.remove_if_version("k", 1).unwrap();
+
+        // The key comes back through another handle, after the store was reopened.
+        let mut other = KVStore::new(path).unwrap();
+        other.put_new("k", "b").unwrap();
+        assert_eq!(
+            other.lookup_versioned("k").unwrap(),
+            Some((String::from("b"), 2))
+        );
+        assert!(is_conflict(kv_store.put_if_version("k", "c", stale)));
+        assert!(is_conflict(kv_store.remove_if_version("k", stale)));
+
+        // Batches and transactions carry on from the removed version too.
+        let mut batch = other.batch();
+        batch.delete("k").unwrap();
+        batch.put("k", "d").unwrap();
+        batch.delete("k").unwrap();
+        other.apply(batch).unwrap();
+        let mut transaction = other.transaction();
+        transaction.put("k", "e").unwrap();
+        transaction.commit().unwrap();
+        assert_eq!(
+            other.lookup_versioned("k").unwrap(),
+            Some((String::from("e"), 4))
+        );
+        // New keys start above every removed version as well.
+        kv_store.insert_from("fresh", &b"\"f\""[..]).unwrap();
+        assert_eq!(
+            kv_store.lookup_versioned("fresh").unwrap(),
+            Some((String::from("f"), 4))
+        );
+    }
+
+    #[test]
+    fn removed_keys_leave_nothing_behind() {
+        let path = "./test-KV/version5";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        for i in 0..20_u32 {
+            kv_store.put(&i, &i).unwrap();
+            kv_store.take::<_, u32>(&i).unwrap();
+        }
+        assert!(!Path::new(path).join("chunks").exists());
+        // Every key started above the versions removed before it.
+        kv_store.put(&0_u32, &0_u32).unwrap();
+        assert_eq!(
+            kv_store.lookup_versioned(&0_u32).unwrap(),
+            Some((0_u32, 21))
+        );
+
+        // Backends without a lock file keep the highest removed version across reopening too.
+        let path = "./test-KV/version6";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = BitcaskStore::open(path, Options::default()).unwrap();
+        kv_store.put("k", &1).unwrap();
+        kv_store.put("k", &2).unwrap();
+        kv_store.take::<_, i32>("k").unwrap();
+        drop(kv_store);
+        let mut kv_store = BitcaskStore::open(path, Options::default()).unwrap();
+        assert_eq!(kv_store.put_if_version("k", &3, 0).unwrap(), 3);
+    }
+
+    #[test]
+    fn concurrent_compare_and_swaps_are_not_lost() {
+        let path = "./test-KV/version3";
+        let _ = fs::remove_dir_all(path);
+        let mut kv_store = KVStore::new(path).unwrap();
+        kv_store.put("counter", &0_u32).unwrap();
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                thread::spawn(move || {
+                    let mut kv_store = KVStore::new(path).unwrap();
+                    for _ in 0..25 {
+                        loop {
+                            let (counter, _) = kv_store
+                                .lookup_versioned::<_, u32>("counter")
+                                .unwrap()
+                                .unwrap();
+                            match kv_store.compare_and_swap("counter", &counter, &(counter + 1)) {
+                                Ok(_) => break,
+                                Err(Error::Conflict { .. }) => continue,
+                                Err(e) => panic!("{}", e),
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(
+            kv_store.lookup_versioned("counter").unwrap(),
+            Some((100_u32, 101))
+        );
+    }
+}
@@ -4,6 +4,13 @@ use kv::KVStore;
 use kv::Operations;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fs;
+
+/// Opens a store at `path` after clearing anything a previous test run left there.
+fn fresh_store(path: &str) -> std::io::Result<KVStore> {
+    let _ = fs::remove_dir_all(path);
+    KVStore::new(path)
+}
 
 
 #[test]
@@ -20,7 +27,7 @@ fn insert_with_empty_path() {
 fn check_insert_size_update() {
 
     let owned_string = "./test-KV/data1".to_string();
-    let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+    let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
 
         process::exit(1);
     });
@@ -36,7 +43,7 @@ fn check_insert_size_update() {
 fn inserting_already_existing_key() {
 
     let owned_string = "./test-KV/data2".to_string();
-    let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+    let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
 
         process::exit(1);
     });
@@ -52,7 +59,7 @@ fn inserting_already_existing_key() {
 fn lookup_existing_key() {
 
     let owned_string = "./test-KV/data3".to_string();
-    let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+    let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
 
         process::exit(1);
     });
@@ -64,7 +71,7 @@ fn lookup_existing_key() {
 fn lookup_non_existing_key() {
 
     let owned_string = "./test-KV/data4".to_string();
-    let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+    let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
 
         process::exit(1);
     });
@@ -79,7 +86,7 @@ fn lookup_non_existing_key() {
 fn lookup_empty_key() {
 
     let owned_string = "./test-KV/data5".to_string();
-    let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+    let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
 
         process::exit(1);
     });
@@ -94,7 +101,7 @@ fn lookup_empty_key() {
 fn remove_existing_key() {
 
     let owned_string = "./test-KV/data6".to_string();
-    let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+    let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
 
         process::exit(1);
     });
@@ -107,7 +114,7 @@ fn remove_existing_key() {
 fn remove_non_existing_key() {
 
     let owned_string = "./test-KV/data7".to_string();
-    let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+    let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
 
         process::exit(1);
     });
@@ -123,7 +130,7 @@ fn remove_non_existing_key() {
 fn check_size_when_remove_existing_key() {
 
     let owned_string = "./test-KV/data8".to_string();
-    let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+    let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
 
         process::exit(1);
     });
@@ -139,7 +146,7 @@ fn check_size_when_remove_existing_key() {
 fn remove_existing_key2() {
 
     let owned_string = "./test-KV/data9".to_string();
-    let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+    let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
 
         process::exit(1);
     });
@@ -151,7 +158,7 @@ fn remove_existing_key2() {
 fn insert_i32() {
 
     let owned_string = "./test-KV/data".to_string();
-    let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+    let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
 
         process::exit(1);
     });
@@ -183,7 +190,7 @@ fn insert_i32() {
 
 
         let owned_string = "./test-KV/test1".to_string();
-        let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+        let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
 
             process::exit(1);
         });
@@ -237,7 +244,7 @@ fn insert_object() {
 fn insert_bool_true() {
 
     let owned_string = "./test-KV/test2".to_string();
-    let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+    let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
 
         process::exit(1);
     });
@@ -253,7 +260,7 @@ fn insert_bool_true() {
 fn insert_bool_false() {
 
     let owned_string = "./test-KV/test3".to_string();
-    let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+    let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
 
         process::exit(1);
     });
@@ -269,7 +276,7 @@ fn insert_bool_false() {
 fn insert_array() {
 
     let owned_string = "./test-KV/test4".to_string();
-    let mut kv_store = KVStore::new(&owned_string).unwrap_or_else(|_err| {
+    let mut kv_store = fresh_store(&owned_string).unwrap_or_else(|_err| {
         process::exit(1)
     });
 
@@ -285,7 +292,7 @@ fn insert_array() {
 fn insert_hashmap() {
 
     let owned_string = "./test-KV/test5".to_string();
-    let mut kv_store = KVStore::new(&owned_string).unwrap_or_else(|_err| {
+    let mut kv_store = fresh_store(&owned_string).unwrap_or_else(|_err| {
         process::exit(1)
     });
 
@@ -307,7 +314,7 @@ fn insert_hashmap() {
 fn invalid_path_lookup() {
 
     let owned_string = "./test-KV/invalidfolder2".to_string();
-    let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+    let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
 
         process::exit(1);
     });
@@ -324,7 +331,7 @@ fn invalid_path_lookup() {
 fn invalid_path_insert() {
 
     let owned_string = "./test-KV/invalidfolder".to_string();
-    let mut kv_store =  KVStore::new(&owned_string).unwrap_or_else(|_err| {
+    let mut kv_store =  fresh_store(&owned_string).unwrap_or_else(|_err| {
 
         process::exit(1);
     });